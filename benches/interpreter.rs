@@ -0,0 +1,103 @@
+use std::hint::black_box;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use eightbit::compiler::{Layout, assemble, tokenize};
+use eightbit::instruction::Instruction;
+use eightbit::interpreter::{Machine, State};
+
+/*
+INTERPRETER BENCHMARK
+
+cargo bench
+
+Runs long-running programs on the machine and prints the fastest of a few runs
+with the instructions executed per second. The programs are assembled once
+and only running them is timed.
+
+The decoding rows are the baseline: they decode the instruction at the
+program counter again before every step, like the machine did before it
+decoded the program once when loading it.
+*/
+
+const RUNS: usize = 5;
+
+/// Loops 1000 times over a loop of 1000 iterations, three million cycles.
+const LOOPS: &str = "
+    IMM 0x3E8 R1
+DEF OUTER
+    IMM 0x3E8 R2
+DEF INNER
+    XOR R2 R3
+    DEC R2
+    JNZ INNER R2
+    DEC R1
+    JNZ OUTER R1
+";
+
+/// Calls a subroutine that pushes and pops, 500000 times.
+const CALLS: &str = "
+    IMM 0x3E8 R1
+DEF OUTER
+    IMM 0x1F4 R2
+DEF INNER
+    CALL WORK
+    DEC R2
+    JNZ INNER R2
+    DEC R1
+    JNZ OUTER R1
+    JMP END
+DEF WORK
+    PUSH R2
+    POP R4
+    MOV R4 R5
+    RET
+DEF END
+";
+
+/// Fastest of RUNS runs of `bytecode` to its end with `run`, and the cycles
+/// it took.
+fn time(bytecode: &[Vec<u16>], run: impl Fn(&mut Machine) -> State) -> (Duration, u64) {
+    let mut fastest = Duration::MAX;
+    let mut cycles = 0;
+    for _ in 0..RUNS {
+        let mut machine = Machine::new(bytecode.to_vec());
+        let start = Instant::now();
+        let state = run(black_box(&mut machine));
+        fastest = fastest.min(start.elapsed());
+        assert_eq!(state, State::Finished);
+        cycles = machine.cycle;
+    }
+    (fastest, cycles)
+}
+
+/// Steps `machine` to its end, decoding every instruction before it runs.
+fn run_decoding(machine: &mut Machine, bytecode: &[Vec<u16>]) -> State {
+    loop {
+        let pc = machine.pc();
+        if let Some(line) = bytecode.get(pc as usize) {
+            black_box(Instruction::decode(black_box(line), pc, bytecode.len()));
+        }
+        match machine.step() {
+            State::Running => continue,
+            state => return state,
+        }
+    }
+}
+
+fn main() {
+    for (name, program) in [("loops", LOOPS), ("calls", CALLS)] {
+        let lines = tokenize(program, Rc::from(name));
+        let bytecode = assemble(lines, Layout::default())
+            .unwrap_or_else(|e| panic!("{e}"))
+            .bytecode;
+        let decoded = time(&bytecode, Machine::run);
+        let decoding = time(&bytecode, |machine| run_decoding(machine, &bytecode));
+        for (mode, (fastest, cycles)) in [("decoded", decoded), ("decoding", decoding)] {
+            let rate = cycles as f64 / fastest.as_secs_f64() / 1e6;
+            println!(
+                "{name:<6} {mode:<9} {cycles:>9} cycles in {fastest:>10.2?}, {rate:.1}M per second"
+            );
+        }
+    }
+}
@@ -0,0 +1,933 @@
+use crate::compiler::{Diagnostic, Line, tokenize};
+use crate::formatter::format;
+use crate::language::{Expr, Function, Place, Program, Statement, StatementKind, parse};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::path::Path;
+use std::rc::Rc;
+
+/*
+CODE GENERATION
+
+Programs of the high-level language (see language.rs) are translated to x1
+assembly, which is then assembled like any other program.
+
+DEF _total          one data word per global variable
+.word 0x000
+CALL _main          the program calls main
+JMP _end            and jumps past its last instruction when main returns
+DEF _add            one label per function, the _ keeps names apart from
+...                 mnemonics and registers
+
+REGISTERS
+
+A function keeps its variables in R1 and up, the ones used most first (a use
+inside a loop counts 8 times as much, per loop), and uses the registers up to
+R15 for intermediate values, at least 3 of them. R0 is left alone as MOV, IMM
+and arithmetic ignore address 0. Variables that don't fit are spilled to a
+data word of their own, _FUNCTION@VARIABLE: instructions take those like
+registers, but the machine has no stack-relative addressing. An expression
+needing more intermediate values than there are registers pushes the oldest
+one onto the stack and pops it when it is done.
+
+CALLS
+
+Arguments are evaluated and pushed, then popped into the parameters of the
+callee. Before the call the caller pushes each register and spill word it
+reads again later that the callee, or a function it calls, may write, and
+pops them after it. Results are returned in RET. CALL stores the return
+address where the last PUSH put its value, so the caller moves SP up by one
+around the call when it has pushed anything.
+
+The stack has 32 words, which limits how deep functions can recurse.
+*/
+
+const REGISTERS: usize = 15;
+/// Registers kept for intermediate values when a function has many variables.
+const TEMPORARIES: usize = 3;
+
+/// x1 assembly for the program `text`, read from `path`.
+pub fn assembly(text: &str, path: &Path) -> Result<String, Diagnostic> {
+    let rows = generate(text, path)?;
+    let lines: Vec<&str> = rows.iter().map(|(row, _)| row.as_str()).collect();
+    Ok(format(&lines.join("\n")))
+}
+
+/// Assembler lines for the program `text`, read from `path`, numbered with
+/// the lines of `text` they were generated for.
+pub fn translate(text: &str, path: &Path) -> Result<Vec<Line>, Diagnostic> {
+    let file: Rc<str> = Rc::from(path.display().to_string());
+    Ok(generate(text, path)?
+        .into_iter()
+        .flat_map(|(row, line)| {
+            tokenize(&row, file.clone())
+                .into_iter()
+                .map(move |mut row| {
+                    row.line = line;
+                    row
+                })
+        })
+        .collect())
+}
+
+/// Rows of assembly with the source line of each.
+fn generate(text: &str, path: &Path) -> Result<Vec<(String, usize)>, Diagnostic> {
+    let file: Rc<str> = Rc::from(path.display().to_string());
+    let program = parse(text, file.clone())?;
+    let Some(main) = program.functions.iter().find(|f| f.name == "main") else {
+        return Err(Diagnostic::at(
+            file,
+            1,
+            "There is no main function".to_string(),
+        ));
+    };
+    if main.params > 0 {
+        return Err(Diagnostic::at(
+            file,
+            main.line,
+            "main takes no parameters".to_string(),
+        ));
+    }
+
+    let frames: HashMap<&str, Frame> = program
+        .functions
+        .iter()
+        .map(|function| (function.name.as_str(), allocate(function)))
+        .collect();
+    let calls: HashMap<&str, HashSet<&str>> = program
+        .functions
+        .iter()
+        .map(|function| {
+            let mut callees = HashSet::new();
+            called(&function.body, &mut callees);
+            callees.retain(|callee| frames.contains_key(callee));
+            (function.name.as_str(), callees)
+        })
+        .collect();
+    // what each function and the functions it calls write
+    let mut clobbers: HashMap<&str, HashSet<String>> = frames
+        .iter()
+        .map(|(name, frame)| {
+            let written = frame.locations.iter().chain(&frame.temporaries);
+            (*name, written.cloned().collect())
+        })
+        .collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (name, callees) in calls.iter() {
+            for callee in callees {
+                let added: Vec<String> = clobbers[callee]
+                    .difference(&clobbers[name])
+                    .cloned()
+                    .collect();
+                changed |= !added.is_empty();
+                clobbers.get_mut(name).expect("no clobbers").extend(added);
+            }
+        }
+    }
+    let mut reached = HashSet::from(["main"]);
+    let mut pending = vec!["main"];
+    while let Some(name) = pending.pop() {
+        for callee in calls[name].iter() {
+            if reached.insert(callee) {
+                pending.push(callee);
+            }
+        }
+    }
+
+    let sources: Vec<&str> = text.split('\n').collect();
+    let mut code = Vec::new();
+    let mut scratch = 0;
+    for function in program.functions.iter() {
+        let generator = Generator {
+            program: &program,
+            frames: &frames,
+            clobbers: &clobbers,
+            function,
+            frame: &frames[function.name.as_str()],
+            file: file.clone(),
+            sources: &sources,
+            rows: Vec::new(),
+            line: function.line,
+            commented: 0,
+            labels: 0,
+            jumped: HashSet::new(),
+            temporaries: 0,
+            pushed: 0,
+            loops: Vec::new(),
+            scratch: 0,
+        };
+        let (rows, used) = generator.function()?;
+        // functions main never calls are still checked
+        if reached.contains(function.name.as_str()) {
+            code.extend(rows);
+            scratch = scratch.max(used);
+        }
+    }
+
+    let mut rows = vec![(
+        format!("// Generated from {} by eightbit compile", path.display()),
+        1,
+    )];
+    let mut data = |name: String, value: u16, line: usize| {
+        rows.push((format!("DEF {name}"), line));
+        rows.push((format!(".word {}", hex(value)), line));
+    };
+    for global in program.globals.iter() {
+        data(format!("_{}", global.name), global.value, global.line);
+    }
+    for function in program.functions.iter() {
+        if reached.contains(function.name.as_str()) {
+            for cell in frames[function.name.as_str()].cells.iter() {
+                data(cell.clone(), 0, function.line);
+            }
+        }
+    }
+    for index in 0..scratch {
+        data(format!("_@{index}"), 0, main.line);
+    }
+    rows.push(("CALL _main".to_string(), main.line));
+    rows.push(("JMP _end".to_string(), main.line));
+    rows.extend(code);
+    rows.push(("DEF _end".to_string(), sources.len()));
+    Ok(rows)
+}
+
+/// Names of the functions `statements` call.
+fn called<'a>(statements: &'a [Statement], callees: &mut HashSet<&'a str>) {
+    fn expression<'a>(value: &'a Expr, callees: &mut HashSet<&'a str>) {
+        match value {
+            Expr::Number(_) | Expr::Local(..) | Expr::Global(_) => {}
+            Expr::Unary(_, operand) => expression(operand, callees),
+            Expr::Binary(_, left, right) => {
+                expression(left, callees);
+                expression(right, callees);
+            }
+            Expr::Call { name, args, .. } => {
+                callees.insert(name);
+                for arg in args {
+                    expression(arg, callees);
+                }
+            }
+        }
+    }
+    for statement in statements {
+        match &statement.kind {
+            StatementKind::Assign(_, value)
+            | StatementKind::Return(Some(value))
+            | StatementKind::Expr(value) => expression(value, callees),
+            StatementKind::If(condition, then, otherwise) => {
+                expression(condition, callees);
+                called(then, callees);
+                called(otherwise, callees);
+            }
+            StatementKind::While {
+                condition, body, ..
+            } => {
+                expression(condition, callees);
+                called(body, callees);
+            }
+            StatementKind::Return(None) | StatementKind::Break | StatementKind::Continue => {}
+        }
+    }
+}
+
+/// Whether running `statements` never reaches their end.
+fn ends(statements: &[Statement]) -> bool {
+    statements.iter().any(|statement| match &statement.kind {
+        StatementKind::Return(_) | StatementKind::Break | StatementKind::Continue => true,
+        StatementKind::If(_, then, otherwise) => ends(then) && ends(otherwise),
+        StatementKind::While {
+            condition: Expr::Number(value),
+            body,
+            ..
+        } => *value != 0 && !breaks(body),
+        _ => false,
+    })
+}
+
+/// Whether `statements` break out of the loop they are in.
+fn breaks(statements: &[Statement]) -> bool {
+    statements.iter().any(|statement| match &statement.kind {
+        StatementKind::Break => true,
+        StatementKind::If(_, then, otherwise) => breaks(then) || breaks(otherwise),
+        _ => false,
+    })
+}
+
+fn hex(value: u16) -> String {
+    format!("0x{value:03X}")
+}
+
+fn mnemonic(operator: &str) -> Option<&'static str> {
+    Some(match operator {
+        "+" => "ADD",
+        "-" => "SUB",
+        "*" => "MUL",
+        "/" => "DIV",
+        "%" => "MOD",
+        "&" => "AND",
+        "|" => "OR",
+        "^" => "XOR",
+        "<<" => "SHL",
+        ">>" => "SHR",
+        _ => return None,
+    })
+}
+
+/// Whether `value` can be computed right in the location of `place` (RET
+/// for None): arithmetic without calls that reads `place` only as its
+/// leftmost operand, which is where the computation starts.
+fn direct(value: &Expr, place: Option<&Place>) -> bool {
+    match value {
+        Expr::Number(_) | Expr::Local(..) | Expr::Global(_) => true,
+        Expr::Unary("~", operand) => direct(operand, place),
+        Expr::Binary(operator, left, right) if mnemonic(operator).is_some() => {
+            direct(left, place) && !right.calls() && place.is_none_or(|place| !right.reads(place))
+        }
+        _ => false,
+    }
+}
+
+/// Where a function keeps its values, see REGISTERS above.
+#[derive(Default)]
+struct Frame {
+    /// Register or spill word of each variable.
+    locations: Vec<String>,
+    temporaries: Vec<String>,
+    cells: Vec<String>,
+}
+
+fn allocate(function: &Function) -> Frame {
+    let variables = &function.variables;
+    let mut order: Vec<usize> = (0..variables.len()).collect();
+    order.sort_by_key(|index| Reverse(variables[*index].weight));
+    let mut in_register = vec![false; variables.len()];
+    for index in order.iter().take(REGISTERS - TEMPORARIES) {
+        in_register[*index] = true;
+    }
+
+    let mut frame = Frame::default();
+    let mut next = 1;
+    for (index, variable) in variables.iter().enumerate() {
+        if in_register[index] {
+            frame.locations.push(format!("R{next}"));
+            next += 1;
+        } else {
+            let mut cell = format!("_{}@{}", function.name, variable.name);
+            if frame.cells.contains(&cell) {
+                cell = format!("{cell}@{index}");
+            }
+            frame.cells.push(cell.clone());
+            frame.locations.push(cell);
+        }
+    }
+    frame.temporaries = (next..=REGISTERS).map(|r| format!("R{r}")).collect();
+    frame
+}
+
+/// An address holding a value, a temporary to release once it is used.
+struct Operand {
+    name: String,
+    temporary: Option<usize>,
+}
+
+struct Loop {
+    start: String,
+    end: String,
+    span: Range<usize>,
+}
+
+/// Generates the code of one function.
+struct Generator<'a> {
+    program: &'a Program,
+    frames: &'a HashMap<&'a str, Frame>,
+    clobbers: &'a HashMap<&'a str, HashSet<String>>,
+    function: &'a Function,
+    frame: &'a Frame,
+    file: Rc<str>,
+    sources: &'a [&'a str],
+    rows: Vec<(String, usize)>,
+    /// Source line of the statement being generated.
+    line: usize,
+    commented: usize,
+    labels: usize,
+    jumped: HashSet<String>,
+    /// Temporaries in use, the later ones past the registers for them push
+    /// the earlier ones.
+    temporaries: usize,
+    /// Values pushed and not popped yet.
+    pushed: usize,
+    loops: Vec<Loop>,
+    /// Scratch words used, see settle.
+    scratch: usize,
+}
+
+impl Generator<'_> {
+    /// The rows of the function and the number of scratch words they use.
+    fn function(mut self) -> Result<(Vec<(String, usize)>, usize), Diagnostic> {
+        let function = self.function;
+        self.comment();
+        self.emit(format!("DEF _{}", function.name));
+        for statement in function.body.iter() {
+            self.statement(statement)?;
+        }
+        if !ends(&function.body) {
+            self.line = function.line;
+            self.emit(format!("IMM {} RET", hex(0)));
+            self.emit("RET".to_string());
+        }
+        let jumped = self.jumped;
+        let rows = self
+            .rows
+            .into_iter()
+            .filter(|(row, _)| {
+                row.strip_prefix("DEF ")
+                    .is_none_or(|label| !label.starts_with('.') || jumped.contains(label))
+            })
+            .collect();
+        Ok((rows, self.scratch))
+    }
+
+    fn emit(&mut self, row: String) {
+        self.rows.push((row, self.line));
+    }
+
+    /// Emits a jump or call to `label`.
+    fn branch(&mut self, row: String, label: &str) {
+        self.jumped.insert(label.to_string());
+        self.emit(row);
+    }
+
+    fn label(&mut self, kind: &str) -> String {
+        self.labels += 1;
+        format!(".{kind}{}", self.labels)
+    }
+
+    /// Defines `label`, it is left out if nothing jumps to it.
+    fn place(&mut self, label: &str) {
+        self.emit(format!("DEF {label}"));
+    }
+
+    /// Puts the source line above its code.
+    fn comment(&mut self) {
+        if self.commented == self.line {
+            return;
+        }
+        self.commented = self.line;
+        let source = self.sources[self.line - 1].trim();
+        if !source.is_empty() {
+            self.emit(format!("// {source}"));
+        }
+    }
+
+    fn error(&self, message: String) -> Diagnostic {
+        Diagnostic::at(self.file.clone(), self.line, message)
+    }
+
+    fn location(&self, place: &Place) -> Result<String, Diagnostic> {
+        match place {
+            Place::Local(variable) => Ok(self.frame.locations[*variable].clone()),
+            Place::Global(name) => match self.program.globals.iter().any(|g| g.name == *name) {
+                true => Ok(format!("_{name}")),
+                false => Err(self.error(format!("Undefined variable {name}"))),
+            },
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) -> Result<(), Diagnostic> {
+        self.line = statement.line;
+        self.comment();
+        match &statement.kind {
+            StatementKind::Assign(place, value) => {
+                let target = self.location(place)?;
+                self.assign(value, &target, Some(place))?;
+            }
+            StatementKind::If(condition, then, otherwise) => {
+                let skip = self.label("else");
+                self.jump(condition, false, &skip)?;
+                for statement in then {
+                    self.statement(statement)?;
+                }
+                let end = self.label("end");
+                if !otherwise.is_empty() && !ends(then) {
+                    self.branch(format!("JMP {end}"), &end);
+                }
+                self.place(&skip);
+                for statement in otherwise {
+                    self.statement(statement)?;
+                }
+                self.place(&end);
+            }
+            StatementKind::While {
+                condition,
+                body,
+                span,
+            } => {
+                let start = self.label("while");
+                let end = self.label("done");
+                self.place(&start);
+                self.jump(condition, false, &end)?;
+                self.loops.push(Loop {
+                    start: start.clone(),
+                    end: end.clone(),
+                    span: span.clone(),
+                });
+                for statement in body {
+                    self.statement(statement)?;
+                }
+                self.loops.pop();
+                if !ends(body) {
+                    self.line = statement.line;
+                    self.branch(format!("JMP {start}"), &start);
+                }
+                self.place(&end);
+            }
+            StatementKind::Return(value) => {
+                match value {
+                    Some(value) => self.assign(value, "RET", None)?,
+                    None => self.emit(format!("IMM {} RET", hex(0))),
+                }
+                self.emit("RET".to_string());
+            }
+            StatementKind::Break | StatementKind::Continue => {
+                let innermost = self.loops.last().expect("break outside a loop");
+                let label = match statement.kind {
+                    StatementKind::Break => innermost.end.clone(),
+                    _ => innermost.start.clone(),
+                };
+                self.branch(format!("JMP {label}"), &label);
+            }
+            StatementKind::Expr(value) => match value {
+                Expr::Call { .. } => self.call(value)?,
+                _ => {
+                    let operand = self.operand(value)?;
+                    self.free(operand);
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Stores `value` in `target`, the location of `place` or RET.
+    fn assign(
+        &mut self,
+        value: &Expr,
+        target: &str,
+        place: Option<&Place>,
+    ) -> Result<(), Diagnostic> {
+        if let Expr::Call { .. } = value {
+            self.call(value)?;
+            if target != "RET" {
+                self.emit(format!("MOV RET {target}"));
+            }
+        } else if direct(value, place) {
+            self.evaluate(value, target)?;
+        } else {
+            let operand = self.operand(value)?;
+            let source = self.settle(operand, 0);
+            if source != target {
+                self.emit(format!("MOV {source} {target}"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Computes `value` into `dest`.
+    fn evaluate(&mut self, value: &Expr, dest: &str) -> Result<(), Diagnostic> {
+        match value {
+            Expr::Number(number) => self.emit(format!("IMM {} {dest}", hex(*number))),
+            Expr::Local(..) | Expr::Global(_) => {
+                let source = self.operand(value)?.name;
+                if source != dest {
+                    self.emit(format!("MOV {source} {dest}"));
+                }
+            }
+            Expr::Unary("~", operand) => {
+                self.evaluate(operand, dest)?;
+                self.emit(format!("NOT {dest}"));
+            }
+            Expr::Binary(operator, left, right) if mnemonic(operator).is_some() => {
+                self.evaluate(left, dest)?;
+                match (*operator, right.as_ref()) {
+                    ("+", Expr::Number(1)) => self.emit(format!("INC {dest}")),
+                    ("-", Expr::Number(1)) => self.emit(format!("DEC {dest}")),
+                    _ => {
+                        let source = self.operand(right)?;
+                        let mnemonic = mnemonic(operator).unwrap_or_default();
+                        self.emit(format!("{mnemonic} {} {dest}", source.name));
+                        self.free(source);
+                    }
+                }
+            }
+            Expr::Call { .. } => {
+                self.call(value)?;
+                self.emit(format!("MOV RET {dest}"));
+            }
+            // comparisons and logic give 1 or 0
+            _ => {
+                let skip = self.label("false");
+                self.emit(format!("IMM {} {dest}", hex(0)));
+                self.jump(value, false, &skip)?;
+                self.emit(format!("IMM {} {dest}", hex(1)));
+                self.place(&skip);
+            }
+        }
+        Ok(())
+    }
+
+    /// Where `value` can be read, computed into a temporary unless it is a
+    /// variable.
+    fn operand(&mut self, value: &Expr) -> Result<Operand, Diagnostic> {
+        let name = match value {
+            Expr::Local(variable, _) => self.frame.locations[*variable].clone(),
+            Expr::Global(name) => self.location(&Place::Global(name.clone()))?,
+            _ => {
+                let temporary = self.temporary(value)?;
+                return Ok(Operand {
+                    name: self.register(temporary),
+                    temporary: Some(temporary),
+                });
+            }
+        };
+        Ok(Operand {
+            name,
+            temporary: None,
+        })
+    }
+
+    fn temporary(&mut self, value: &Expr) -> Result<usize, Diagnostic> {
+        if let Expr::Call { .. } = value {
+            self.call(value)?;
+            let temporary = self.acquire();
+            self.emit(format!("MOV RET {}", self.register(temporary)));
+            return Ok(temporary);
+        }
+        let temporary = self.acquire();
+        self.evaluate(value, &self.register(temporary))?;
+        Ok(temporary)
+    }
+
+    fn register(&self, temporary: usize) -> String {
+        let registers = &self.frame.temporaries;
+        registers[temporary % registers.len()].clone()
+    }
+
+    fn acquire(&mut self) -> usize {
+        let temporary = self.temporaries;
+        if temporary >= self.frame.temporaries.len() {
+            // the register still holds an earlier temporary
+            self.emit(format!("PUSH {}", self.register(temporary)));
+            self.pushed += 1;
+        }
+        self.temporaries += 1;
+        temporary
+    }
+
+    fn release(&mut self, temporary: usize) {
+        assert_eq!(temporary + 1, self.temporaries, "temporaries out of order");
+        self.temporaries -= 1;
+        if temporary >= self.frame.temporaries.len() {
+            self.emit(format!("POP {}", self.register(temporary)));
+            self.pushed -= 1;
+        }
+    }
+
+    fn free(&mut self, operand: Operand) {
+        if let Some(temporary) = operand.temporary {
+            self.release(temporary);
+        }
+    }
+
+    /// Releases `operand` before a jump or push and returns where its value
+    /// is. A temporary whose release pops an earlier one into its register
+    /// is copied to the scratch word `_@scratch` first.
+    fn settle(&mut self, operand: Operand, scratch: usize) -> String {
+        match operand.temporary {
+            Some(temporary) if temporary >= self.frame.temporaries.len() => {
+                let word = format!("_@{scratch}");
+                self.scratch = self.scratch.max(scratch + 1);
+                self.emit(format!("MOV {} {word}", operand.name));
+                self.release(temporary);
+                word
+            }
+            _ => {
+                self.free(Operand {
+                    name: String::new(),
+                    ..operand
+                });
+                operand.name
+            }
+        }
+    }
+
+    /// Jumps to `label` if `value` is true (not 0) when `sense` is set, or if
+    /// it is false when it is not.
+    fn jump(&mut self, value: &Expr, sense: bool, label: &str) -> Result<(), Diagnostic> {
+        match value {
+            Expr::Number(number) => {
+                if (*number != 0) == sense {
+                    self.branch(format!("JMP {label}"), label);
+                }
+            }
+            Expr::Unary("!", operand) => self.jump(operand, !sense, label)?,
+            Expr::Binary(operator @ ("&&" | "||"), left, right) => {
+                // the left side alone decides && when false and || when true
+                let decides = *operator == "||";
+                if sense == decides {
+                    self.jump(left, sense, label)?;
+                    self.jump(right, sense, label)?;
+                } else {
+                    let skip = self.label("skip");
+                    self.jump(left, decides, &skip)?;
+                    self.jump(right, sense, label)?;
+                    self.place(&skip);
+                }
+            }
+            Expr::Binary(operator @ ("==" | "!=" | "<" | ">" | "<=" | ">="), left, right) => {
+                let left = self.operand(left)?;
+                let right = self.operand(right)?;
+                let right = self.settle(right, 1);
+                let left = self.settle(left, 0);
+                // a > b is b < a, a <= b is not b < a and a >= b not a < b
+                let (less, a, b, sense) = match *operator {
+                    "<" => (true, left, right, sense),
+                    ">" => (true, right, left, sense),
+                    "<=" => (true, right, left, !sense),
+                    ">=" => (true, left, right, !sense),
+                    "==" => (false, left, right, sense),
+                    _ => (false, left, right, !sense),
+                };
+                match (less, sense) {
+                    (true, true) => self.branch(format!("JL {label} {a} {b}"), label),
+                    (false, false) => {
+                        self.branch(format!("JL {label} {a} {b}"), label);
+                        self.branch(format!("JG {label} {a} {b}"), label);
+                    }
+                    _ => {
+                        let skip = self.label("skip");
+                        self.branch(format!("JL {skip} {a} {b}"), &skip);
+                        if !less {
+                            self.branch(format!("JG {skip} {a} {b}"), &skip);
+                        }
+                        self.branch(format!("JMP {label}"), label);
+                        self.place(&skip);
+                    }
+                }
+            }
+            _ => {
+                let operand = self.operand(value)?;
+                let name = self.settle(operand, 0);
+                let mnemonic = if sense { "JNZ" } else { "JZ" };
+                self.branch(format!("{mnemonic} {label} {name}"), label);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `variable` may be read after the call at position `at`: later
+    /// in the function or anywhere in a loop around the call.
+    fn live(&self, variable: usize, at: usize) -> bool {
+        self.function.variables[variable]
+            .reads
+            .iter()
+            .any(|read| *read > at || self.loops.iter().any(|l| l.span.contains(read)))
+    }
+
+    /// Calls a function, its result is left in RET.
+    fn call(&mut self, value: &Expr) -> Result<(), Diagnostic> {
+        let Expr::Call { name, args, at } = value else {
+            unreachable!("not a call");
+        };
+        let program = self.program;
+        let Some(callee) = program.functions.iter().find(|f| f.name == *name) else {
+            return Err(self.error(format!("Undefined function {name}")));
+        };
+        if args.len() != callee.params {
+            return Err(self.error(format!(
+                "{name} takes {} arguments, not {}",
+                callee.params,
+                args.len()
+            )));
+        }
+        let frame = &self.frames[name.as_str()];
+        let clobbered = &self.clobbers[name.as_str()];
+
+        let mut saved: Vec<String> = Vec::new();
+        for (variable, location) in self.frame.locations.iter().enumerate() {
+            if clobbered.contains(location) && self.live(variable, *at) {
+                saved.push(location.clone());
+            }
+        }
+        let registers = self.frame.temporaries.len();
+        for temporary in self.temporaries.saturating_sub(registers)..self.temporaries {
+            let register = self.register(temporary);
+            if clobbered.contains(&register) {
+                saved.push(register);
+            }
+        }
+        for location in saved.iter() {
+            self.emit(format!("PUSH {location}"));
+            self.pushed += 1;
+        }
+
+        for arg in args {
+            let operand = self.operand(arg)?;
+            let source = self.settle(operand, 0);
+            self.emit(format!("PUSH {source}"));
+            self.pushed += 1;
+        }
+        for param in frame.locations[..args.len()].iter().rev() {
+            self.emit(format!("POP {param}"));
+            self.pushed -= 1;
+        }
+        // CALL would overwrite the value pushed last
+        let pad = self.pushed > 0;
+        if pad {
+            self.emit("INC SP".to_string());
+        }
+        self.branch(format!("CALL _{name}"), name);
+        if pad {
+            self.emit("DEC SP".to_string());
+        }
+
+        for location in saved.iter().rev() {
+            self.emit(format!("POP {location}"));
+            self.pushed -= 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::translate;
+    use crate::compiler::{Layout, assemble};
+    use crate::interpreter::RETURN_REGISTER_ADDRESS;
+    use crate::testing;
+    use std::path::Path;
+
+    /// The value main returns.
+    fn run(program: &str) -> u16 {
+        let lines = translate(program, Path::new("test.x1h")).unwrap_or_else(|e| panic!("{e}"));
+        let assembly = assemble(lines, Layout::default()).unwrap_or_else(|e| panic!("{e}"));
+        testing::run(assembly).memory[RETURN_REGISTER_ADDRESS]
+    }
+
+    fn error(program: &str) -> String {
+        match translate(program, Path::new("test.x1h")) {
+            Ok(_) => panic!("compiled"),
+            Err(error) => error.to_string(),
+        }
+    }
+
+    #[test]
+    fn loops() {
+        let program = "
+            fn main() {
+                var sum = 0;
+                var i = 0;
+                while 1 {
+                    i = i + 1;
+                    if i > 10 { break; }
+                    if i % 2 == 0 { continue; }
+                    sum = sum + i;
+                }
+                return sum;
+            }";
+        assert_eq!(run(program), 25);
+    }
+
+    #[test]
+    fn recursion() {
+        let program = "
+            fn fib(n) {
+                if n < 2 { return n; }
+                return fib(n - 1) + fib(n - 2);
+            }
+            fn fact(n) {
+                if n == 0 { return 1; }
+                return n * fact(n - 1);
+            }
+            fn main() { return fib(6) * 1000 + fact(5); }";
+        assert_eq!(run(program), 8120);
+    }
+
+    #[test]
+    fn globals() {
+        let program = "
+            var count = 2;
+            fn bump(by) { count = count + by; }
+            fn main() {
+                bump(3);
+                bump(count);
+                return count;
+            }";
+        assert_eq!(run(program), 10);
+    }
+
+    #[test]
+    fn spilled_variables() {
+        let program = "
+            fn main() {
+                var a = 1; var b = 2; var c = 3; var d = 4; var e = 5;
+                var f = 6; var g = 7; var h = 8; var i = 9; var j = 10;
+                var k = 11; var l = 12; var m = 13; var n = 14;
+                var x = 0;
+                while x < 3 { x = x + 1; }
+                return a + b + c + d + e + f + g + h + i + j + k + l + m + n + x;
+            }";
+        assert_eq!(run(program), 108);
+    }
+
+    #[test]
+    fn deep_expressions() {
+        let program = "
+            fn main() {
+                var a = 2; var b = 0; var c = 0; var d = 0; var e = 0; var f = 0;
+                var g = 0; var h = 0; var i = 0; var j = 0; var k = 0; var l = 0;
+                var x = (a + (a * (a + (a * (a + (a * (a + 1))))))) - (a * (a - 1));
+                return x + b + c + d + e + f + g + h + i + j + k + l;
+            }";
+        assert_eq!(run(program), 36);
+    }
+
+    #[test]
+    fn calls_keep_live_values() {
+        let program = "
+            fn square(x) { var y = x * x; return y; }
+            fn main() {
+                var a = 3;
+                var b = 4;
+                var c = square(a) + square(b) * (a + square(2));
+                return c + a + b;
+            }";
+        assert_eq!(run(program), 9 + 16 * 7 + 7);
+    }
+
+    #[test]
+    fn logic() {
+        let program = "
+            fn zero() { return 0; }
+            fn main() {
+                var a = 5;
+                var r = 0;
+                if a > 3 && a != 4 || zero() { r = r + 1; }
+                if !(a >= 6) && (a <= 5) { r = r + 2; }
+                if zero() && 1 { r = r + 4; }
+                var t = (a == 5) + (a < 5) + !a;
+                return r * 10 + t;
+            }";
+        assert_eq!(run(program), 31);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(error("fn f() {}").contains("no main function"));
+        assert!(error("fn main() { return g(); }").contains("Undefined function g"));
+        assert!(error("fn f(a) {} fn main() { f(1, 2); }").contains("f takes 1 arguments, not 2"));
+        assert!(error("fn main() { x = 1; }").contains("Undefined variable x"));
+    }
+}
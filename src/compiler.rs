@@ -1,144 +1,1178 @@
-use crate::operation::parse_hex;
-use std::collections::HashMap;
+use crate::conditional::Conditions;
+use crate::data::{directive_data, directive_size, is_directive};
+use crate::expr::{evaluate, is_expression, names};
+use crate::include::{resolve_include, resolve_includes};
+use crate::interpreter::{
+    MEMORY_SIZE, PROGRAM_MEMORY_END, PROGRAM_MEMORY_START, READ_ONLY_REGISTERS, check_address,
+    register_address, register_name,
+};
+use crate::listing::{Entry, Symbol, SymbolKind};
+use crate::macros::expand_macros;
+use crate::operation::{OperandKind, Operation, WORD_BITS, fit_word, parse_literal};
+use crate::reachability::reachable;
+use crate::sourcemap::{Location, SourceMap};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
-pub fn compile(program: String) -> Vec<Vec<u16>> {
-    let lines = program.split("\n").collect::<Vec<&str>>();
+/// A source line split into words with comments removed. `line` is the
+/// 1-based line number in `file` and `column` the 1-based column of the first
+/// word, for lines produced by a macro expansion they point at the outermost
+/// call. `includes` holds the canonical paths of the files being included
+/// when the line was read, its own file last.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Line {
+    pub file: Rc<str>,
+    pub line: usize,
+    pub column: usize,
+    pub words: Vec<String>,
+    pub expansion: Vec<String>,
+    pub includes: Rc<[PathBuf]>,
+}
 
-    let mut bytecode: Vec<Vec<u16>> = Vec::new();
-    let mut defined_names: HashMap<String, Vec<u16>> = HashMap::new();
-    let mut defined_labels: HashMap<String, u16> = HashMap::new();
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub file: Rc<str>,
+    pub line: usize,
+    pub expansion: Vec<String>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn error(line: &Line, message: String) -> Diagnostic {
+        Diagnostic::new(Severity::Error, line, message)
+    }
+
+    pub fn warning(line: &Line, message: String) -> Diagnostic {
+        Diagnostic::new(Severity::Warning, line, message)
+    }
+
+    /// An error at a line known only by file and number.
+    pub fn at(file: Rc<str>, line: usize, message: String) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            file,
+            line,
+            expansion: Vec::new(),
+            message,
+        }
+    }
+
+    fn new(severity: Severity, line: &Line, message: String) -> Diagnostic {
+        Diagnostic {
+            severity,
+            file: line.file.clone(),
+            line: line.line,
+            expansion: line.expansion.clone(),
+            message,
+        }
+    }
+}
 
-    //loop for labels and definitions first
-    for (index, line) in lines.iter().enumerate() {
-        let line_contents: Vec<&str> = line.split_ascii_whitespace().collect();
-        let mut contents = Vec::new();
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)?;
+        for name in self.expansion.iter().rev() {
+            write!(f, ", in expansion of {name}")?;
+        }
+        if self.severity == Severity::Warning {
+            write!(f, ": warning")?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
 
-        for word in line_contents {
-            if word.starts_with("//") {
-                break;
+/// Output of a successful compilation. `data` is loaded into program memory
+/// starting at PROGRAM_MEMORY_START.
+pub struct Assembly {
+    pub bytecode: Vec<Vec<u16>>,
+    pub data: Vec<u16>,
+    pub warnings: Vec<Diagnostic>,
+    pub listing: Vec<Entry>,
+    pub symbols: Vec<Symbol>,
+    pub source_map: SourceMap,
+    pub exports: Vec<String>,
+    /// Operands naming an EXTERN symbol, as (instruction, word, name).
+    pub references: Vec<(usize, usize, String)>,
+    /// Whether each instruction can be reached, see reachability.rs.
+    pub reachable: Vec<bool>,
+}
+
+impl Assembly {
+    /// Listing entry of the data directive that produced the word at
+    /// `address`.
+    pub fn data_entry(&self, address: u16) -> Option<&Entry> {
+        self.listing
+            .iter()
+            .filter(|entry| entry.data && !entry.words.is_empty())
+            .find(|entry| {
+                let start = entry.address.unwrap_or_default();
+                (start..start + entry.words.len() as u16).contains(&address)
+            })
+    }
+}
+
+/// Where a program is placed. Executables start at instruction 0 and data at
+/// PROGRAM_MEMORY_START, objects are also assembled at shifted bases to find
+/// the words that hold addresses (see object.rs). Only objects may use EXTERN
+/// symbols. Position-independent programs (`pic`) use relative jumps and calls
+/// only and may not hold any other code address.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Layout {
+    pub code: u16,
+    pub data: u16,
+    pub object: bool,
+    pub pic: bool,
+}
+
+/// Lines of `program`, read from `path`, with every INCLUDE resolved and a DEF
+/// line for each of `defines` in front.
+pub fn read_file(
+    program: String,
+    path: &Path,
+    defines: &[String],
+) -> Result<Vec<Line>, Diagnostic> {
+    let lines = tokenize(&program, Rc::from(path.display().to_string()));
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut stack: Vec<_> = path.canonicalize().into_iter().collect();
+    let mut program = define_lines(defines);
+    program.extend(resolve_includes(lines, dir, &mut stack)?);
+    Ok(program)
+}
+
+/// `DEF NAME VALUE` lines for `NAME=VALUE` command line definitions, a bare
+/// NAME is defined as 1.
+fn define_lines(defines: &[String]) -> Vec<Line> {
+    let program: Vec<String> = defines
+        .iter()
+        .map(|define| match define.split_once('=') {
+            Some((name, value)) => format!("DEF {name} {value}"),
+            None => format!("DEF {define} 1"),
+        })
+        .collect();
+    tokenize(&program.join("\n"), Rc::from("<command line>"))
+}
+
+/*
+LABELS
+
+DEF NAME       global label
+DEF .NAME      local label
+
+A label holds the address of the next instruction. Local labels start with a
+dot and belong to the nearest global label above them: `.loop` after
+`DEF FUNC1` is stored as FUNC1.loop. Inside that scope `.loop` refers to it,
+elsewhere it can be reached as FUNC1.loop. Labels created by a macro expansion
+do not start a new scope.
+
+Labels and DEF values can be used anywhere, also before their definition.
+Defining a name twice, using an undefined name or a DEF value that depends on
+itself is an error.
+*/
+
+/// Full name of `word` inside the scope of the global label `scope`.
+pub fn qualify(word: &str, scope: &str) -> String {
+    if word.len() > 1 && word.starts_with('.') {
+        format!("{scope}{word}")
+    } else {
+        word.to_string()
+    }
+}
+
+/// A DEF NAME VALUE... line, evaluated the first time NAME is used. `here`
+/// is the value of `$` inside it.
+struct Definition<'a> {
+    line: &'a Line,
+    scope: String,
+    here: u16,
+}
+
+/// Names known to the assembler: constants (DEF NAME VALUE) and labels
+/// (DEF NAME), which hold the address of the next instruction or of the data
+/// after them.
+#[derive(Default)]
+struct Symbols<'a> {
+    definitions: HashMap<String, Definition<'a>>,
+    names: RefCell<HashMap<String, Vec<u16>>>,
+    labels: HashMap<String, u16>,
+    externs: HashSet<String>,
+    resolving: RefCell<Vec<String>>,
+    /// Labels whose value was looked up.
+    used: RefCell<HashSet<String>>,
+    /// The first label looked up since this was last taken.
+    addressed: RefCell<Option<String>>,
+}
+
+impl Symbols<'_> {
+    /// Value of the constant `name`, computed from its definition on first
+    /// use. Errors point at the definition.
+    fn constant(&self, name: &str) -> Result<Option<Vec<u16>>, Diagnostic> {
+        if let Some(bytes) = self.names.borrow().get(name) {
+            return Ok(Some(bytes.clone()));
+        }
+        let Some(definition) = self.definitions.get(name) else {
+            return Ok(None);
+        };
+        if self.resolving.borrow().iter().any(|other| other == name) {
+            let mut chain = self.resolving.borrow().clone();
+            chain.push(name.to_string());
+            return Err(Diagnostic::error(
+                definition.line,
+                format!("DEF {name} depends on itself: {}", chain.join(" -> ")),
+            ));
+        }
+
+        self.resolving.borrow_mut().push(name.to_string());
+        let mut bytes = Vec::new();
+        let mut result = Ok(());
+        for word in &definition.line.words[2..] {
+            match self.resolve(
+                word,
+                true,
+                definition.here,
+                &definition.scope,
+                definition.line,
+            ) {
+                Ok(Some(parsed)) => bytes.extend(parsed),
+                Ok(None) => {
+                    result = Err(undefined(definition.line, word));
+                    break;
+                }
+                Err(error) => {
+                    result = Err(error);
+                    break;
+                }
             }
-            contents.push(word);
         }
+        self.resolving.borrow_mut().pop();
+        result?;
+
+        self.names
+            .borrow_mut()
+            .insert(name.to_string(), bytes.clone());
+        Ok(Some(bytes))
+    }
+
+    /// Resolves `word` on `line` to the words it stands for. `operand` is
+    /// false for the first word of a line, `here` is the value of `$` and
+    /// `scope` the global label local labels belong to. Returns Ok(None) if
+    /// `word` is not known at all.
+    fn resolve(
+        &self,
+        word: &str,
+        operand: bool,
+        here: u16,
+        scope: &str,
+        line: &Line,
+    ) -> Result<Option<Vec<u16>>, Diagnostic> {
+        let error = |message: String| Diagnostic::error(line, message);
+        match parse_literal(word, WORD_BITS) {
+            Ok(Some(value)) => return Ok(Some(vec![value])),
+            Err(message) if !is_expression(word) => return Err(error(message)),
+            _ => {}
+        }
+        let name = qualify(word, scope);
+        if let Some(bytes) = self.constant(&name)? {
+            return Ok(Some(bytes));
+        }
+        if let Some(address) = self.label(&name) {
+            return Ok(Some(vec![address]));
+        }
+        if self.externs.contains(word) {
+            return Err(error(format!(
+                "EXTERN {word} can only be used as an instruction operand"
+            )));
+        }
+        if let Some(value) = builtin(word, operand) {
+            return Ok(Some(vec![value]));
+        }
+        if !is_expression(word) {
+            return Ok(None);
+        }
+
+        let value = self.expression(word, here, scope, line)?;
+        Ok(Some(vec![fit_word(value, WORD_BITS).map_err(error)?]))
+    }
+
+    /// Evaluates the expression `text` written on `line`.
+    fn expression(
+        &self,
+        text: &str,
+        here: u16,
+        scope: &str,
+        line: &Line,
+    ) -> Result<i64, Diagnostic> {
+        let error = |message: String| Diagnostic::error(line, message);
+        // Errors of constants used inside the expression point at their
+        // definition, keep them instead of the expression's own error.
+        let nested = RefCell::new(None);
+        let value = evaluate(text, here, &|name| {
+            let value = if self.externs.contains(name) {
+                Err(error(format!(
+                    "EXTERN {name} can only be used as an instruction operand"
+                )))
+            } else {
+                self.value(&qualify(name, scope))
+            };
+            value.unwrap_or_else(|diagnostic| {
+                nested.replace(Some(diagnostic));
+                None
+            })
+        });
+        match (value, nested.into_inner()) {
+            (Ok(value), _) => Ok(value),
+            (Err(_), Some(diagnostic)) => Err(diagnostic),
+            (Err(message), None) => Err(error(message)),
+        }
+    }
+
+    /// Whether `word` is resolved through a DEF name, label or expression
+    /// rather than being a literal or built-in.
+    fn is_symbolic(&self, word: &str, scope: &str) -> bool {
+        let name = qualify(word, scope);
+        self.definitions.contains_key(&name)
+            || self.labels.contains_key(&name)
+            || (is_expression(word) && !matches!(parse_literal(word, WORD_BITS), Ok(Some(_))))
+    }
+
+    /// Value of a name inside an expression.
+    fn value(&self, name: &str) -> Result<Option<i64>, Diagnostic> {
+        Ok(match self.constant(name)? {
+            Some(bytes) => match bytes.as_slice() {
+                [value] => Some(*value as i64),
+                _ => None,
+            },
+            None => self
+                .label(name)
+                .or_else(|| builtin(name, true))
+                .map(i64::from),
+        })
+    }
 
+    /// What the operand `word` names, following DEF constants that stand for
+    /// a single other name. `data_labels` are the labels bound to data.
+    fn role(&self, word: &str, scope: &str, data_labels: &[String]) -> Role {
+        let name = qualify(word, scope);
+        if self.labels.contains_key(&name) {
+            return match data_labels.contains(&name) {
+                true => Role::DataLabel,
+                false => Role::CodeLabel,
+            };
+        }
+        if let Some(definition) = self.definitions.get(&name) {
+            return match &definition.line.words[2..] {
+                [alias] => self.role(alias, &definition.scope, data_labels),
+                _ => Role::Literal,
+            };
+        }
+        match register_address(word) {
+            Some(_) => Role::Register,
+            None => Role::Literal,
+        }
+    }
+
+    /// Address of the label `name`, which is then used.
+    fn label(&self, name: &str) -> Option<u16> {
+        let address = self.labels.get(name).copied();
+        if address.is_some() {
+            self.used.borrow_mut().insert(name.to_string());
+            self.addressed
+                .borrow_mut()
+                .get_or_insert_with(|| name.to_string());
+        }
+        address
+    }
+}
+
+/// What an operand word names, checked against the kind of the operand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Role {
+    Register,
+    CodeLabel,
+    DataLabel,
+    /// A literal, constant or expression.
+    Literal,
+    /// An EXTERN symbol, its value is not known before linking.
+    Extern,
+}
+
+fn undefined(line: &Line, word: &str) -> Diagnostic {
+    Diagnostic::error(line, format!("Undefined symbol {word}"))
+}
+
+/// A data directive, its scope, listing entry and the labels bound to it.
+struct Directive<'a> {
+    line: &'a Line,
+    scope: String,
+    entry: usize,
+    labels: Vec<(String, usize)>,
+    start: u16,
+}
+
+/// Assembles `lines` placed at `layout`.
+pub fn assemble(lines: Vec<Line>, layout: Layout) -> Result<Assembly, Diagnostic> {
+    if !layout.pic {
+        return assemble_at(lines, layout);
+    }
+
+    // Moving position-independent code changes none of its words.
+    let moved = assemble_at(
+        lines.clone(),
+        Layout {
+            code: layout.code + 1,
+            ..layout
+        },
+    )?;
+    let assembly = assemble_at(lines, layout)?;
+    let message = "Absolute code address in position-independent code".to_string();
+    for (index, row) in assembly.bytecode.iter().enumerate() {
+        if *row != moved.bytecode[index] {
+            let location = &assembly.source_map.locations[index];
+            return Err(Diagnostic::at(
+                location.file.clone(),
+                location.line,
+                message,
+            ));
+        }
+    }
+    let data_start = PROGRAM_MEMORY_START + layout.data as usize;
+    if let Some(index) = (0..assembly.data.len()).find(|&i| assembly.data[i] != moved.data[i])
+        && let Some(entry) = assembly.data_entry((data_start + index) as u16)
+    {
+        return Err(Diagnostic::at(entry.file.clone(), entry.line, message));
+    }
+    Ok(assembly)
+}
+
+/// How far assembling got.
+enum Pass {
+    Assembled(Assembly),
+    /// An INCLUDE inside an .if block was reached, see include.rs.
+    Include(Line),
+}
+
+fn assemble_at(mut lines: Vec<Line>, layout: Layout) -> Result<Assembly, Diagnostic> {
+    loop {
+        match assemble_pass(lines.clone(), layout)? {
+            Pass::Assembled(assembly) => return Ok(assembly),
+            Pass::Include(include) => lines = resolve_include(lines, &include)?,
+        }
+    }
+}
+
+fn assemble_pass(lines: Vec<Line>, layout: Layout) -> Result<Pass, Diagnostic> {
+    let lines = expand_macros(lines)?;
+
+    let mut warnings = Vec::new();
+    let mut symbols = Symbols::default();
+    let mut defined: HashSet<String> = HashSet::new();
+    let mut constants: Vec<(String, usize)> = Vec::new();
+    let mut directives: Vec<Directive> = Vec::new();
+    let mut pending_labels: Vec<(String, usize)> = Vec::new();
+    let mut instructions: Vec<(&Line, String, usize)> = Vec::new();
+    let mut listing: Vec<Entry> = Vec::new();
+    let mut exports: Vec<(&Line, String)> = Vec::new();
+    let mut label_lines: Vec<(String, &Line)> = Vec::new();
+    let mut conditions = Conditions::default();
+    let mut scope = String::new();
+
+    // Labels waiting for the next line are bound to the next instruction
+    // unless that line is a data directive.
+    let bind_code_labels = |pending: &mut Vec<(String, usize)>,
+                            symbols: &mut Symbols,
+                            listing: &mut Vec<Entry>,
+                            address: u16| {
+        for (label, entry) in pending.drain(..) {
+            symbols.labels.insert(label, address);
+            listing[entry].address = Some(address);
+        }
+    };
+
+    //first pass: addresses of every instruction and label, definitions are only collected
+    for line in lines.iter() {
+        let contents = &line.words;
+        let address = layout.code + instructions.len() as u16;
         if contents.is_empty() {
             continue;
         }
-
-        match parse_argument(&contents[0], &defined_names) {
-            Some(bytes) => {
-                if bytes[0] == 0x021 {
-                    let name = contents
-                        .get(1)
-                        .unwrap_or_else(|| panic!("DEF without name on line {index}"))
-                        .to_string();
-
-                    if let Some(field) = contents.get(2..) {
-                        if field.is_empty() {
-                            defined_labels.insert(name, index as u16);
-                        } else {
-                            defined_names.insert(
-                                name,
-                                field
-                                    .iter()
-                                    .flat_map(|str| match parse_argument(str, &defined_names) {
-                                        Some(bytes) => bytes,
-                                        None => {
-                                            panic!("Error parsing argument {str} on line {index}")
-                                        }
-                                    })
-                                    .collect(),
-                            );
-                        }
-                    }
+        let skipped = conditions.consume(
+            line,
+            |text| {
+                // a label right above a condition using it is bound to the next instruction
+                let referenced = names(text);
+                if pending_labels.iter().any(|(label, _)| {
+                    referenced
+                        .iter()
+                        .any(|name| qualify(name, &scope) == *label)
+                }) {
+                    bind_code_labels(&mut pending_labels, &mut symbols, &mut listing, address);
                 }
+                symbols.addressed.take();
+                let value = symbols.expression(text, address, &scope, line)?;
+                let here = referenced.iter().find(|name| *name == "$").cloned();
+                match symbols.addressed.take().or(here) {
+                    Some(name) if layout.object || layout.pic => Err(Diagnostic::error(
+                        line,
+                        format!(
+                            "The condition depends on the address of {name}, which is not \
+                             known before the program is linked or moved"
+                        ),
+                    )),
+                    _ => Ok(value),
+                }
+            },
+            |name| defined.contains(&qualify(name, &scope)),
+        )?;
+        if skipped {
+            continue;
+        }
+        match contents[0].as_str() {
+            "INCLUDE" if line.expansion.is_empty() => return Ok(Pass::Include(line.clone())),
+            "INCLUDE" => {
+                return Err(Diagnostic::error(
+                    line,
+                    "A macro can't INCLUDE inside an .if block".to_string(),
+                ));
             }
-            None => {
+            "EXPORT" => {
+                exports.extend(contents[1..].iter().map(|name| (line, name.clone())));
                 continue;
             }
+            "EXTERN" => {
+                for name in &contents[1..] {
+                    if !defined.insert(name.clone()) {
+                        return Err(Diagnostic::error(
+                            line,
+                            format!("{name} is already defined"),
+                        ));
+                    }
+                    symbols.externs.insert(name.clone());
+                }
+                continue;
+            }
+            _ => {}
+        }
+        let entry = listing.len();
+        listing.push(Entry {
+            file: line.file.clone(),
+            line: line.line,
+            text: contents.join(" "),
+            address: None,
+            data: false,
+            words: Vec::new(),
+            resolved: Vec::new(),
+        });
+
+        if is_directive(line) {
+            directives.push(Directive {
+                line,
+                scope: scope.clone(),
+                entry,
+                labels: std::mem::take(&mut pending_labels),
+                start: 0,
+            });
+            continue;
+        }
+
+        let opcode = symbols
+            .resolve(&contents[0], false, address, &scope, line)
+            .ok()
+            .flatten()
+            .and_then(|bytes| bytes.first().copied());
+        if opcode == Some(0x021) {
+            let name = contents
+                .get(1)
+                .ok_or_else(|| Diagnostic::error(line, "DEF without name".to_string()))?
+                .to_string();
+            if builtin(&name, false).is_some() {
+                let kind = match Operation::from_name(&name) {
+                    Some(_) => "mnemonic",
+                    None => "register",
+                };
+                warnings.push(Diagnostic::warning(
+                    line,
+                    format!(
+                        "DEF {name} shadows the built-in {kind} {}",
+                        name.to_uppercase()
+                    ),
+                ));
+            }
+
+            let label = contents.len() == 2;
+            if label && !name.starts_with('.') && line.expansion.is_empty() {
+                scope = name.clone();
+            }
+            let name = qualify(&name, &scope);
+            if !defined.insert(name.clone()) {
+                return Err(Diagnostic::error(
+                    line,
+                    format!("{name} is already defined"),
+                ));
+            }
+            if label {
+                label_lines.push((name.clone(), line));
+                pending_labels.push((name, entry));
+            } else {
+                bind_code_labels(&mut pending_labels, &mut symbols, &mut listing, address);
+                symbols.definitions.insert(
+                    name.clone(),
+                    Definition {
+                        line,
+                        scope: scope.clone(),
+                        here: address,
+                    },
+                );
+                constants.push((name, entry));
+            }
+        } else {
+            bind_code_labels(&mut pending_labels, &mut symbols, &mut listing, address);
+            if opcode != Some(0x020) {
+                listing[entry].address = Some(address);
+                instructions.push((line, scope.clone(), entry));
+            }
+        }
+    }
+    conditions.finish()?;
+    let end = layout.code + instructions.len() as u16;
+    bind_code_labels(&mut pending_labels, &mut symbols, &mut listing, end);
+
+    //data layout, only the counts of .fill and .reserve are needed here
+    let mut data_labels: Vec<String> = Vec::new();
+    let mut data_size = 0;
+    for directive in directives.iter_mut() {
+        let line = directive.line;
+        let start = (PROGRAM_MEMORY_START + layout.data as usize + data_size) as u16;
+        for (label, entry) in directive.labels.drain(..) {
+            symbols.labels.insert(label.clone(), start);
+            listing[entry].address = Some(start);
+            listing[entry].data = true;
+            data_labels.push(label);
+        }
+        data_size += directive_size(line, |word| {
+            data_value(word, &symbols, start, &directive.scope, line)
+        })?;
+        if start as usize + data_size > PROGRAM_MEMORY_END + 1 {
+            return Err(Diagnostic::error(
+                line,
+                "Data does not fit in program memory".to_string(),
+            ));
         }
+        directive.start = start;
+        listing[directive.entry].address = Some(start);
+        listing[directive.entry].data = true;
+    }
+
+    //every definition is evaluated, even unused ones, so their errors are reported
+    for (name, entry) in constants.iter() {
+        listing[*entry].words = symbols.constant(name)?.unwrap_or_default();
+    }
+
+    let mut data: Vec<u16> = Vec::new();
+    for directive in directives.iter() {
+        let line = directive.line;
+        let words = directive_data(line, |word| {
+            data_value(word, &symbols, directive.start, &directive.scope, line)
+        })?;
+        listing[directive.entry].words = words.clone();
+        data.extend(words);
     }
 
     //Main bytecode compilation
-    for (index, line) in lines.iter().enumerate() {
-        let line_contents: Vec<&str> = line.split_ascii_whitespace().into_iter().collect();
+    let mut bytecode: Vec<Vec<u16>> = Vec::new();
+    let mut source_map = SourceMap::default();
+    let mut references = Vec::new();
+    for (index, (line, scope, entry)) in instructions.iter().enumerate() {
+        let address = layout.code + index as u16;
+        let mut bytes = Vec::new();
+        let mut sources = Vec::new();
+        for (position, str) in line.words.iter().enumerate() {
+            if symbols.externs.contains(str) && position > 0 {
+                if !layout.object {
+                    return Err(Diagnostic::error(
+                        line,
+                        format!("EXTERN {str} is only available when linking objects"),
+                    ));
+                }
+                references.push((index, bytes.len(), str.clone()));
+                bytes.push(0);
+                sources.push((str.as_str(), Role::Extern));
+                continue;
+            }
+            match symbols.resolve(str, position > 0, address, scope, line)? {
+                Some(parsed) => {
+                    if symbols.is_symbolic(str, scope) {
+                        listing[*entry].resolved.push((str.clone(), parsed.clone()));
+                    }
+                    let role = symbols.role(str, scope, &data_labels);
+                    sources.extend(parsed.iter().map(|_| (str.as_str(), role)));
+                    bytes.extend(parsed);
+                }
+                None => return Err(undefined(line, str)),
+            }
+        }
+        if bytes.is_empty() {
+            return Err(Diagnostic::error(line, "Unknown opcode".to_string()));
+        }
+        if layout.pic
+            && let Some(relative) = Operation::try_from_u16(bytes[0]).and_then(Operation::relative)
+        {
+            bytes[0] = relative.opcode();
+        }
+        let end = (!layout.object).then_some(layout.code as usize + instructions.len());
+        check_operands(&bytes, &sources, end)
+            .map_err(|message| Diagnostic::error(line, message))?;
 
-        //loop to ignore comments
-        let mut contents = Vec::new();
-        for word in line_contents {
-            if word.starts_with("//") {
-                break;
+        // relative operands hold the distance from this instruction
+        let signature = Operation::from_u16(bytes[0]).signature();
+        for (word, (_, kind)) in signature.iter().enumerate().map(|(i, s)| (i + 1, s)) {
+            if *kind != OperandKind::Relative {
+                continue;
+            }
+            // the linker computes the distance to EXTERN symbols
+            if !references
+                .iter()
+                .any(|reference| reference.0 == index && reference.1 == word)
+            {
+                bytes[word] = bytes[word].wrapping_sub(address);
             }
-            contents.push(word);
         }
+        listing[*entry].words = bytes.clone();
+        bytecode.push(bytes);
+        source_map.locations.push(Location {
+            file: line.file.clone(),
+            line: line.line,
+            column: line.column,
+            label: symbols
+                .labels
+                .get(scope)
+                .map(|address| (scope.clone(), *address)),
+        });
+    }
 
-        if contents.is_empty() {
-            bytecode.push(vec![0x020]);
-            continue;
+    for (line, name) in exports.iter() {
+        if !symbols.labels.contains_key(name) && !symbols.definitions.contains_key(name) {
+            return Err(Diagnostic::error(
+                line,
+                format!("EXPORT of undefined symbol {name}"),
+            ));
         }
+    }
 
-        bytecode.push(
-            contents
-                .iter()
-                .flat_map(|str| match parse_argument(str, &defined_names) {
-                    Some(bytes) => bytes,
-                    None => {
-                        if let Some(label_index) = defined_labels.get(str.to_owned()) {
-                            return vec![*label_index];
-                        }
-                        panic!("Error on line {index} with string {str}")
-                    }
-                })
-                .collect(),
-        );
+    //reachability from the first instruction and the exported code labels
+    let roots = exports
+        .iter()
+        .filter(|(_, name)| !data_labels.contains(name))
+        .filter_map(|(_, name)| symbols.labels.get(name))
+        .map(|address| address.wrapping_sub(layout.code) as usize);
+    let reachable = reachable(&bytecode, layout.code, std::iter::once(0).chain(roots));
+    let mut index = 0;
+    while index < reachable.len() {
+        let count = reachable[index..]
+            .iter()
+            .take_while(|reached| !**reached)
+            .count();
+        if count > 0 {
+            let message = match count {
+                1 => "Unreachable instruction".to_string(),
+                _ => format!("{count} unreachable instructions"),
+            };
+            warnings.push(Diagnostic::warning(instructions[index].0, message));
+        }
+        index += count.max(1);
     }
+    let used = symbols.used.take();
+    for (name, line) in label_lines.iter() {
+        if !used.contains(name) && !exports.iter().any(|(_, export)| export == name) {
+            warnings.push(Diagnostic::warning(
+                line,
+                format!("Label {name} is never used"),
+            ));
+        }
+    }
+
+    let labels = symbols.labels.into_iter().map(|(name, address)| Symbol {
+        kind: if data_labels.contains(&name) {
+            SymbolKind::Data
+        } else {
+            SymbolKind::Label
+        },
+        name,
+        value: vec![address],
+    });
+    let names = symbols
+        .names
+        .into_inner()
+        .into_iter()
+        .map(|(name, value)| Symbol {
+            name,
+            kind: SymbolKind::Constant,
+            value,
+        });
 
-    let mut old_to_new_index: HashMap<u16, u16> = HashMap::new();
-    let mut new_index: u16 = 0;
-    for (old_index, bytes) in bytecode.clone().iter().enumerate() {
-        let opcode = bytes
-            .first()
-            .unwrap_or_else(|| panic!("Unkown opcode on line {old_index}"));
+    Ok(Pass::Assembled(Assembly {
+        bytecode,
+        data,
+        warnings,
+        listing,
+        symbols: labels.chain(names).collect(),
+        source_map,
+        exports: exports.into_iter().map(|(_, name)| name).collect(),
+        references,
+        reachable,
+    }))
+}
+
+/// Checks an encoded instruction against the signature of its operation.
+/// `sources` holds the source word every value came from with what it names
+/// and `end` is the address right after the last instruction, if known.
+fn check_operands(
+    bytes: &[u16],
+    sources: &[(&str, Role)],
+    end: Option<usize>,
+) -> Result<(), String> {
+    let op = Operation::try_from_u16(bytes[0])
+        .ok_or_else(|| format!("{} is not an operation", sources[0].0))?;
+    let signature = op.signature();
+    let operands = &bytes[1..];
+    if operands.len() != signature.len() {
+        let names: Vec<&str> = signature.iter().map(|(name, _)| *name).collect();
+        return Err(format!(
+            "{op:?} takes {} operand{} ({}), {} given",
+            signature.len(),
+            if signature.len() == 1 { "" } else { "s" },
+            names.join(" "),
+            operands.len()
+        ));
+    }
 
-        match opcode {
-            0x020 | 0x021 => continue,
-            _ => {
-                old_to_new_index.insert(old_index as u16, new_index);
-                new_index += 1;
+    for ((&(name, kind), &value), &(word, role)) in
+        signature.iter().zip(operands).zip(&sources[1..])
+    {
+        match (kind, role) {
+            (OperandKind::Source | OperandKind::Dest, Role::CodeLabel) => {
+                return Err(format!(
+                    "{op:?} {name} must be a memory address, {word} is a code label"
+                ));
             }
+            (OperandKind::Source | OperandKind::Dest, _) if value as usize >= MEMORY_SIZE => {
+                return Err(format!(
+                    "{op:?} {name} must be a memory address, {word} (0x{value:X}) is out of range"
+                ));
+            }
+            (OperandKind::Dest, _) if READ_ONLY_REGISTERS.contains(&(value as usize)) => {
+                return Err(format!(
+                    "{op:?} writes to the read-only register {}",
+                    register_name(value as usize).unwrap_or_default()
+                ));
+            }
+            // the machine turns these into NOP, see instruction.rs
+            (OperandKind::Source | OperandKind::Dest, role)
+                if role != Role::Extern && ignores_addresses(op) && !check_address(value) =>
+            {
+                return Err(format!("{word} is ignored by {op:?}"));
+            }
+            (OperandKind::Immediate, Role::Register) => {
+                return Err(format!(
+                    "{op:?} {name} must be a value, {word} is a register"
+                ));
+            }
+            (OperandKind::Label | OperandKind::Relative, Role::Register) => {
+                return Err(format!(
+                    "{op:?} {name} must be a label, {word} is a register"
+                ));
+            }
+            (OperandKind::Label | OperandKind::Relative, Role::DataLabel) => {
+                return Err(format!(
+                    "{op:?} {name} must be a code label, {word} is a data label"
+                ));
+            }
+            (OperandKind::Label | OperandKind::Relative, _)
+                if end.is_some_and(|end| value as usize > end) =>
+            {
+                return Err(format!(
+                    "{op:?} {name} {word} (0x{value:X}) is outside the program"
+                ));
+            }
+            _ => {}
         }
     }
+    Ok(())
+}
+
+/// Whether `op` does nothing when an address check_address rejects is one of
+/// its operands.
+fn ignores_addresses(op: Operation) -> bool {
+    use Operation::*;
+    matches!(
+        op,
+        MOV | IMM | ADD | SUB | MUL | DIV | MOD | AND | OR | XOR | SHL | SHR | INC | DEC | NOT
+    )
+}
 
-    let mut optimized_bytecode: Vec<Vec<u16>> = Vec::new();
-    for (index, bytes) in bytecode.clone().iter().enumerate() {
-        let opcode = bytes
-            .first()
-            .unwrap_or_else(|| panic!("Unkown opcode on line {index}"));
-        match opcode {
-            0x030 | 0x031 | 0x032 | 0x033 | 0x034 | 0x039 => {
-                let mut new_bytes = bytes.clone();
-                let new_target = old_to_new_index
-                    .get(
-                        &(new_bytes
-                            .get(1)
-                            .unwrap_or_else(|| panic!("No argument bytes found on line {index}."))
-                            + 1),
-                    )
-                    .unwrap_or(&new_bytes[1]);
-                new_bytes[1] = *new_target;
+/// Splits a program into lines of words, dropping everything after `//`.
+pub fn tokenize(program: &str, file: Rc<str>) -> Vec<Line> {
+    program
+        .split('\n')
+        .enumerate()
+        .map(|(index, line)| Line {
+            file: file.clone(),
+            line: index + 1,
+            column: line.len() - line.trim_start().len() + 1,
+            words: split_words(line),
+            expansion: Vec::new(),
+            includes: Rc::from([]),
+        })
+        .collect()
+}
 
-                optimized_bytecode.push(new_bytes);
+/// Value of a built-in mnemonic or register name, ignoring case. RET is both
+/// an operation and a register, operands prefer the register.
+pub fn builtin(word: &str, operand: bool) -> Option<u16> {
+    let mnemonic = || Operation::from_name(word).map(Operation::opcode);
+    let register = || register_address(word).map(|address| address as u16);
+    if operand {
+        register().or_else(mnemonic)
+    } else {
+        mnemonic().or_else(register)
+    }
+}
+
+/// Resolves a data directive operand to a single word. `here` is the address
+/// of the directive's first word.
+fn data_value(
+    word: &str,
+    symbols: &Symbols,
+    here: u16,
+    scope: &str,
+    line: &Line,
+) -> Result<u16, Diagnostic> {
+    match symbols.resolve(word, true, here, scope, line)?.as_deref() {
+        Some([value]) => Ok(*value),
+        Some(_) => Err(Diagnostic::error(
+            line,
+            format!("{word} is not a single word"),
+        )),
+        None => Err(undefined(line, word)),
+    }
+}
+
+/// Splits a line on whitespace, keeping quoted text ('...' or "...") and
+/// parenthesized expressions inside one word and stopping at a word that
+/// starts with `//`.
+pub fn split_words(line: &str) -> Vec<String> {
+    split_line(line).0
+}
+
+/// Words of `line` and its comment, starting at the `//`.
+pub fn split_line(line: &str) -> (Vec<String>, Option<&str>) {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quote = None;
+    let mut escaped = false;
+    let mut depth = 0usize;
+    let mut comment = None;
+
+    for (index, character) in line.char_indices() {
+        match quote {
+            Some(open) => {
+                word.push(character);
+                if escaped {
+                    escaped = false;
+                } else if character == '\\' {
+                    escaped = true;
+                } else if character == open {
+                    quote = None;
+                }
+            }
+            None if character.is_ascii_whitespace() && depth == 0 => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            None => {
+                if word.is_empty() && line[index..].starts_with("//") {
+                    comment = Some(&line[index..]);
+                    break;
+                }
+                match character {
+                    '\'' | '"' => quote = Some(character),
+                    '(' => depth += 1,
+                    ')' => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+                word.push(character);
             }
-            0x020 | 0x021 => continue,
-            _ => optimized_bytecode.push(bytes.clone()),
         }
     }
-
-    optimized_bytecode
+    if !word.is_empty() {
+        words.push(word);
+    }
+    (words, comment)
 }
 
-pub fn parse_argument(arg: &&str, defined_names: &HashMap<String, Vec<u16>>) -> Option<Vec<u16>> {
-    match parse_hex(arg) {
-        Some(num) => Some(vec![num]),
-        None => defined_names.get(*arg).cloned(),
+#[cfg(test)]
+mod tests {
+    use super::Layout;
+    use crate::operation::Operation;
+    use crate::testing::{assemble_program, registers};
+
+    #[test]
+    fn pic_jumps_to_the_end() {
+        let program = "
+            JZ END R1
+            IMM 1 R1
+            DEF END
+        ";
+        let pic = Layout {
+            pic: true,
+            ..Layout::default()
+        };
+        let assembly = assemble_program(program, pic).unwrap_or_else(|e| panic!("{e}"));
+        assert_eq!(assembly.bytecode[0], [Operation::JZR.opcode(), 2, 1]);
+        assert!(assemble_program(program, Layout::default()).is_ok());
+        let error = assemble_program("JMP 5", pic).err().unwrap_or_default();
+        assert!(error.contains("outside the program"), "{error}");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn operand_roles() {
+        let error = |program: &str| {
+            assemble_program(program, Layout::default())
+                .err()
+                .unwrap_or_default()
+        };
+        assert_eq!(
+            error("HLT R1"),
+            "test.x1:1: HLT EXIT_CODE must be a value, R1 is a register"
+        );
+        assert_eq!(
+            error("IMM R1 R2"),
+            "test.x1:1: IMM IMM must be a value, R1 is a register"
+        );
+        assert_eq!(
+            error("DEF COUNT R1\nIMM COUNT R2"),
+            "test.x1:2: IMM IMM must be a value, COUNT is a register"
+        );
+        assert_eq!(
+            error("DEF START\nMOV START R1"),
+            "test.x1:2: MOV SRC must be a memory address, START is a code label"
+        );
+        assert_eq!(
+            error("DEF TABLE\n.word 1\nJMP TABLE"),
+            "test.x1:3: JMP ADDR must be a code label, TABLE is a data label"
+        );
+        assert_eq!(
+            error("JZ R1 R2"),
+            "test.x1:1: JZ ADDR must be a label, R1 is a register"
+        );
+
+        assert_eq!(error("IMM 5 R0"), "test.x1:1: R0 is ignored by IMM");
+        assert_eq!(error("MOV R0 R1"), "test.x1:1: R0 is ignored by MOV");
+        assert_eq!(error("ADD R2 R0"), "test.x1:1: R0 is ignored by ADD");
+        assert_eq!(error("INC 0x020"), "test.x1:1: 0x020 is ignored by INC");
+
+        let program = "
+            CMP R0 R1
+            PUSH R0
+            POP R0
+            DEF TABLE
+            .word 7
+            DEF POINTER TABLE
+            IMM TABLE R1
+            MOV POINTER R2
+            IMM (TABLE + 1) R3
+            DEF START
+            IMM START R4
+            JMP END
+            DEF END
+        ";
+        assert!(assemble_program(program, Layout::default()).is_ok());
+    }
+
+    #[test]
+    fn local_labels_belong_to_their_global_label() {
+        let program = "
+                CALL COUNT
+                CALL TWICE
+                JMP END
+            DEF COUNT
+                IMM 2 R1
+            DEF .loop
+                DEC R1
+                JNZ .loop R1
+                RET
+            DEF TWICE
+                IMM 3 R2
+            DEF .loop
+                INC R3
+                INC R3
+                DEC R2
+                JNZ .loop R2
+                IMM 1 R1
+                JMP COUNT.loop
+            DEF END
+        ";
+        let assembly = assemble_program(program, Layout::default()).unwrap();
+        let address = |name: &str| {
+            let symbol = assembly.symbols.iter().find(|symbol| symbol.name == name);
+            symbol.map(|symbol| symbol.value.clone())
+        };
+        assert_eq!(address("COUNT.loop"), Some(vec![4]));
+        assert_eq!(address("TWICE.loop"), Some(vec![8]));
+        assert_eq!(address(".loop"), None);
+        assert_eq!(assembly.bytecode[5][1], 4);
+        assert_eq!(assembly.bytecode[11][1], 8);
+        assert_eq!(assembly.bytecode[13][1], 4);
+        assert_eq!(registers(Ok(assembly)), Ok([0, 0, 6]));
+
+        let error = assemble_program("DEF F\nDEF .x\nINC R1\nDEF .x", Layout::default());
+        assert_eq!(
+            error.err().unwrap_or_default(),
+            "test.x1:4: F.x is already defined"
+        );
+    }
+
+    #[test]
+    fn names_are_used_before_their_definition() {
+        let program = "
+                DEF TOTAL (BASE + STEP)
+                IMM TOTAL R1
+                MOV POINTER R2
+                JMP END
+            DEF POINTER
+                .word BASE STEP TOTAL END
+                IMM 9 R3
+            DEF END
+            DEF BASE 0x10
+            DEF STEP 2
+        ";
+        let assembly = assemble_program(program, Layout::default()).unwrap();
+        assert_eq!(assembly.data, [0x10, 2, 0x12, 4]);
+        assert_eq!(assembly.bytecode[2][1], 4);
+        assert_eq!(registers(Ok(assembly)), Ok([0x12, 0x10, 0]));
+
+        let error = |program: &str| {
+            assemble_program(program, Layout::default())
+                .err()
+                .unwrap_or_default()
+        };
+        assert_eq!(
+            error("IMM 1 R1\n\nJMP MISSING"),
+            "test.x1:3: Undefined symbol MISSING"
+        );
+        assert_eq!(
+            error("DEF A (B + 1)\nIMM A R1\n.word 1\n.word C"),
+            "test.x1:1: Undefined symbol B"
+        );
+        assert_eq!(error("IMM 1 R1\n.word C"), "test.x1:2: Undefined symbol C");
+    }
+}
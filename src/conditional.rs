@@ -0,0 +1,239 @@
+use crate::compiler::{Diagnostic, Line};
+
+/*
+CONDITIONAL ASSEMBLY
+
+.if (SIZE > 0x100)       .ifdef DEBUG          .ifndef DEBUG
+...                      ...                   DEF DEBUG 0
+.elif SIZE > 0x10        .else                 .endif
+...                      ...
+.else                    .endif
+...
+.endif
+
+Only the lines of the first branch whose condition holds are assembled. An
+.if or .elif condition is an expression that holds when it is not zero, it can
+use DEF values and labels defined above it. A label right above a condition
+using it holds the address of the next instruction, even if data follows.
+Objects and position-independent code don't know their addresses yet, there a
+condition may not depend on a label or `$`. .ifdef holds when NAME was defined
+above it, .ifndef when it was not. Blocks can be nested.
+
+`-D NAME=VALUE` on the command line defines NAME as if `DEF NAME VALUE` was the
+first line of the program, `-D NAME` defines it as 1.
+
+An INCLUDE inside a block is only read if its branch is assembled. MACRO is
+handled before conditions, macros are defined also inside skipped blocks.
+*/
+
+/// One open .if block.
+struct Block<'a> {
+    line: &'a Line,
+    active: bool,
+    taken: bool,
+    otherwise: bool,
+}
+
+#[derive(Default)]
+pub struct Conditions<'a> {
+    blocks: Vec<Block<'a>>,
+}
+
+impl<'a> Conditions<'a> {
+    /// Whether the lines of every open block are assembled.
+    fn live(&self) -> bool {
+        self.blocks.iter().all(|block| block.active)
+    }
+
+    /// Handles `line` if it is a condition directive or skipped. `evaluate`
+    /// gives the value of an expression, `defined` whether a name is defined.
+    /// Returns whether the line is used up.
+    pub fn consume(
+        &mut self,
+        line: &'a Line,
+        evaluate: impl FnOnce(&str) -> Result<i64, Diagnostic>,
+        defined: impl FnOnce(&str) -> bool,
+    ) -> Result<bool, Diagnostic> {
+        let error = |message: String| Diagnostic::error(line, message);
+        let directive = line.words[0].to_ascii_lowercase();
+        let argument = || {
+            if line.words.len() > 1 {
+                Ok(line.words[1..].join(" "))
+            } else {
+                Err(error(format!("{directive} without condition")))
+            }
+        };
+        let name = || match &line.words[1..] {
+            [name] => Ok(name.clone()),
+            _ => Err(error(format!("{directive} takes 1 name"))),
+        };
+        let live = self.live();
+        match directive.as_str() {
+            ".if" | ".ifdef" | ".ifndef" => {
+                let active = match directive.as_str() {
+                    _ if !live => false,
+                    ".if" => evaluate(&argument()?)? != 0,
+                    ".ifdef" => defined(&name()?),
+                    _ => !defined(&name()?),
+                };
+                self.blocks.push(Block {
+                    line,
+                    active,
+                    // A skipped block takes none of its branches.
+                    taken: active || !live,
+                    otherwise: false,
+                });
+            }
+            ".elif" | ".else" | ".endif" => {
+                let Some(block) = self.blocks.pop() else {
+                    return Err(error(format!("{directive} without .if")));
+                };
+                if block.otherwise && directive != ".endif" {
+                    return Err(error(format!("{directive} after .else")));
+                }
+                let outer = self.live();
+                match directive.as_str() {
+                    ".elif" => {
+                        let text = argument()?;
+                        let active = !block.taken && outer && evaluate(&text)? != 0;
+                        self.blocks.push(Block {
+                            active,
+                            taken: block.taken || active,
+                            ..block
+                        });
+                    }
+                    ".else" => self.blocks.push(Block {
+                        active: !block.taken,
+                        taken: true,
+                        otherwise: true,
+                        ..block
+                    }),
+                    _ => {}
+                }
+            }
+            _ => return Ok(!live),
+        }
+        Ok(true)
+    }
+
+    /// Checks that every block was closed.
+    pub fn finish(&self) -> Result<(), Diagnostic> {
+        match self.blocks.last() {
+            Some(block) => Err(Diagnostic::error(
+                block.line,
+                format!("{} without .endif", block.line.words[0]),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::Layout;
+    use crate::testing::{assemble_file, directory, registers};
+    use std::fs;
+    use std::path::Path;
+
+    /// R1 to R3 after running `program`, read from `dir` with `defines`.
+    fn run(
+        dir: &Path,
+        program: &str,
+        defines: &[&str],
+        layout: Layout,
+    ) -> Result<[u16; 3], String> {
+        registers(assemble_file(
+            &dir.join("main.x1"),
+            program,
+            defines,
+            layout,
+        ))
+    }
+
+    #[test]
+    fn skipped_includes_are_not_read() {
+        let dir = directory("skipped-includes", &[("debug.x1", "IMM 3 R3")]);
+        let program = "
+            .ifdef DEBUG
+            INCLUDE \"debug.x1\"
+            .endif
+            .ifdef MISSING
+            INCLUDE \"missing.x1\"
+            .endif
+            IMM 1 R1
+        ";
+        let layout = Layout::default();
+        assert_eq!(run(&dir, program, &[], layout), Ok([1, 0, 0]));
+        assert_eq!(run(&dir, program, &["DEBUG"], layout), Ok([1, 0, 3]));
+        let error = run(&dir, program, &["MISSING"], layout).unwrap_err();
+        assert!(error.contains("Cannot include"), "{error}");
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn labels_above_conditions() {
+        let dir = directory("label-conditions", &[]);
+        let program = "
+            IMM 1 R1
+            DEF L
+            .if (L == 1)
+            IMM 2 R2
+            .endif
+        ";
+        assert_eq!(run(&dir, program, &[], Layout::default()), Ok([1, 2, 0]));
+        let object = Layout {
+            object: true,
+            ..Layout::default()
+        };
+        let error = run(&dir, program, &[], object).unwrap_err();
+        assert!(error.contains("depends on the address of L"), "{error}");
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn nested_and_skipped_blocks() {
+        let dir = directory("nested-blocks", &[]);
+        let program = "
+            .if (SIZE > 0x100)
+                IMM 1 R1
+                .ifdef DEBUG
+                    IMM 1 R2
+                .elif (SIZE > 0x1000)
+                    IMM 2 R2
+                .else
+                    IMM 3 R2
+                .endif
+            .elif (SIZE > 0x10)
+                IMM 2 R1
+            .else
+                IMM 3 R1
+                .if (UNDEFINED)
+                    NOT AN INSTRUCTION
+                .endif
+            .endif
+            .ifndef DEBUG
+                IMM 4 R3
+            .endif
+        ";
+        let layout = Layout::default();
+        let with = |defines: &[&str]| run(&dir, program, defines, layout);
+        assert_eq!(with(&["SIZE=0x200"]), Ok([1, 3, 4]));
+        assert_eq!(with(&["SIZE=0x2000"]), Ok([1, 2, 4]));
+        assert_eq!(with(&["SIZE=0x200", "DEBUG"]), Ok([1, 1, 0]));
+        assert_eq!(with(&["SIZE=0x20"]), Ok([2, 0, 4]));
+        let error = with(&["SIZE=1"]).unwrap_err();
+        assert!(error.contains("Undefined symbol UNDEFINED"), "{error}");
+
+        let error = with(&[]).unwrap_err();
+        assert!(error.contains("Undefined symbol SIZE"), "{error}");
+        for (program, message) in [
+            (".if 1\nIMM 1 R1", ".if without .endif"),
+            ("IMM 1 R1\n.endif", ".endif without .if"),
+            (".ifdef A\n.else\n.elif 1\n.endif", ".elif after .else"),
+        ] {
+            let error = run(&dir, program, &[], layout).unwrap_err();
+            assert!(error.contains(message), "{error}");
+        }
+        fs::remove_dir_all(dir).ok();
+    }
+}
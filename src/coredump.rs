@@ -0,0 +1,377 @@
+use crate::hexdump::hexdump;
+use crate::interpreter::{
+    Fault, MEMORY_SIZE, Machine, STACK_BASE_ADDRESS, STACK_POINTER_ADDRESS, register_name,
+};
+use crate::operation::Operation;
+use crate::sourcemap::Location;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+
+pub const DEFAULT_CORE_FILE: &str = "core.json";
+
+pub struct Register {
+    pub name: String,
+    pub address: usize,
+    pub value: u16,
+}
+
+/// Stack cells belonging to one routine. The outermost frame has no call
+/// information; every other frame was entered with CALL.
+pub struct StackFrame {
+    pub call_pc: Option<u16>,
+    pub call_location: Option<String>,
+    pub target: Option<u16>,
+    pub return_address: Option<u16>,
+    pub cells: Vec<(usize, u16)>,
+}
+
+/// Structured snapshot of a machine, renderable as JSON or readable text.
+pub struct CoreDump {
+    pub cycle: u64,
+    pub pc: u16,
+    pub instruction: Option<Vec<u16>>,
+    pub location: Option<Location>,
+    pub fault: Option<Fault>,
+    pub registers: Vec<Register>,
+    pub stack: Vec<StackFrame>,
+    pub memory: Vec<u16>,
+}
+
+impl CoreDump {
+    pub fn new(machine: &Machine) -> CoreDump {
+        let memory = &machine.memory;
+        let registers = (0x000..0x020)
+            .map(|address| Register {
+                name: register_name(address).unwrap_or_else(|| format!("0x{address:03X}")),
+                address,
+                value: memory[address],
+            })
+            .collect();
+
+        // Cells from the stack base up to the stack pointer, split at the
+        // return address slot of every active CALL.
+        let base = memory[STACK_BASE_ADDRESS] as usize;
+        let top = (base + memory[STACK_POINTER_ADDRESS] as usize).min(MEMORY_SIZE - 1);
+        let cells = |from: usize, to: usize| -> Vec<(usize, u16)> {
+            (from..to.min(top + 1))
+                .map(|address| (address, memory[address]))
+                .collect()
+        };
+        let frames = machine.frames();
+        let first_slot = frames.first().map_or(top + 1, |frame| frame.return_slot);
+        let mut stack = vec![StackFrame {
+            call_pc: None,
+            call_location: None,
+            target: None,
+            return_address: None,
+            cells: cells(base + 1, first_slot),
+        }];
+        for (index, frame) in frames.iter().enumerate() {
+            let end = frames
+                .get(index + 1)
+                .map_or(top + 1, |next| next.return_slot);
+            stack.push(StackFrame {
+                call_pc: Some(frame.call_pc),
+                call_location: Some(machine.source_map.describe(frame.call_pc)),
+                target: Some(frame.target),
+                return_address: memory.get(frame.return_slot).copied(),
+                cells: cells(frame.return_slot + 1, end),
+            });
+        }
+
+        CoreDump {
+            cycle: machine.cycle,
+            pc: machine.pc(),
+            instruction: machine.instructions.get(machine.pc() as usize).cloned(),
+            location: machine.source_map.get(machine.pc()).cloned(),
+            fault: machine.fault(),
+            registers,
+            stack,
+            memory: memory.to_vec(),
+        }
+    }
+
+    /// Writes the dump to `path`, as JSON if the path ends with `.json` and as
+    /// text otherwise.
+    pub fn write(&self, path: &str) -> io::Result<()> {
+        if path.ends_with(".json") {
+            fs::write(path, self.to_json())
+        } else {
+            fs::write(path, self.to_text())
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\n");
+        let _ = writeln!(json, "  \"cycle\": {},", self.cycle);
+        let _ = writeln!(json, "  \"pc\": {},", self.pc);
+        match &self.instruction {
+            Some(line) if !line.is_empty() => {
+                let mnemonic = match Operation::try_from_u16(line[0]) {
+                    Some(op) => json_string(&format!("{op:?}")),
+                    None => "null".to_string(),
+                };
+                let _ = writeln!(
+                    json,
+                    "  \"instruction\": {{\"opcode\": {}, \"mnemonic\": {}, \"operands\": {}}},",
+                    line[0],
+                    mnemonic,
+                    json_array(&line[1..])
+                );
+            }
+            _ => json.push_str("  \"instruction\": null,\n"),
+        }
+        match &self.location {
+            Some(location) => {
+                let label = match &location.label {
+                    Some((name, address)) => format!(
+                        "{{\"name\": {}, \"address\": {address}}}",
+                        json_string(name)
+                    ),
+                    None => "null".to_string(),
+                };
+                let _ = writeln!(
+                    json,
+                    "  \"location\": {{\"file\": {}, \"line\": {}, \"column\": {}, \"label\": {label}}},",
+                    json_string(&location.file),
+                    location.line,
+                    location.column
+                );
+            }
+            None => json.push_str("  \"location\": null,\n"),
+        }
+        match &self.fault {
+            Some(fault) => {
+                let _ = writeln!(json, "  \"fault\": {},", json_string(&fault.to_string()));
+            }
+            None => json.push_str("  \"fault\": null,\n"),
+        }
+
+        json.push_str("  \"registers\": [\n");
+        let registers: Vec<String> = self
+            .registers
+            .iter()
+            .map(|register| {
+                format!(
+                    "    {{\"name\": {}, \"address\": {}, \"value\": {}}}",
+                    json_string(&register.name),
+                    register.address,
+                    register.value
+                )
+            })
+            .collect();
+        json.push_str(&registers.join(",\n"));
+        json.push_str("\n  ],\n");
+
+        json.push_str("  \"stack\": [\n");
+        let frames: Vec<String> = self
+            .stack
+            .iter()
+            .map(|frame| {
+                let cells: Vec<String> = frame
+                    .cells
+                    .iter()
+                    .map(|(address, value)| format!("{{\"address\": {address}, \"value\": {value}}}"))
+                    .collect();
+                format!(
+                    "    {{\"call_pc\": {}, \"call_location\": {}, \"target\": {}, \"return_address\": {}, \"cells\": [{}]}}",
+                    json_option(frame.call_pc),
+                    frame
+                        .call_location
+                        .as_deref()
+                        .map_or("null".to_string(), json_string),
+                    json_option(frame.target),
+                    json_option(frame.return_address),
+                    cells.join(", ")
+                )
+            })
+            .collect();
+        json.push_str(&frames.join(",\n"));
+        json.push_str("\n  ],\n");
+
+        let _ = writeln!(json, "  \"memory\": {}", json_array(&self.memory));
+        json.push_str("}\n");
+        json
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::from("\nINTERPRETER DUMP:\n\n");
+        if let Some(fault) = &self.fault {
+            let _ = writeln!(text, "Fault: {fault}");
+        }
+        let _ = writeln!(text, "Cycle: {}", self.cycle);
+        match &self.instruction {
+            Some(line) if !line.is_empty() => {
+                let _ = writeln!(
+                    text,
+                    "Current Instruction: {} {:?} at [0x{:X}]",
+                    describe_opcode(line[0]),
+                    line[1..].to_vec(),
+                    self.pc
+                );
+            }
+            _ => {
+                let _ = writeln!(text, "Current Instruction: none at [0x{:X}]", self.pc);
+            }
+        }
+        if let Some(location) = &self.location {
+            let _ = write!(text, "Source: {location}");
+            if let Some((label, address)) = &location.label {
+                let _ = write!(text, " in {label}+{}", self.pc.saturating_sub(*address));
+            }
+            text.push('\n');
+        }
+
+        text.push_str("\nRegisters:\n");
+        for register in &self.registers {
+            let _ = writeln!(
+                text,
+                "  {:<6} [0x{:03X}] = 0x{:X}",
+                register.name, register.address, register.value
+            );
+        }
+
+        text.push_str("\nStack:\n");
+        for frame in &self.stack {
+            match (frame.call_pc, frame.target, frame.return_address) {
+                (Some(call_pc), Some(target), Some(return_address)) => {
+                    let _ = write!(
+                        text,
+                        "  CALL 0x{target:X} from 0x{call_pc:X}, returns to 0x{return_address:X}"
+                    );
+                    if let Some(location) = &frame.call_location {
+                        let _ = write!(text, " (called at {location})");
+                    }
+                    text.push('\n');
+                }
+                _ => text.push_str("  <outermost>\n"),
+            }
+            for (address, value) in &frame.cells {
+                let _ = writeln!(text, "    [0x{address:03X}] = 0x{value:X}");
+            }
+        }
+
+        text.push_str("\nMemory:\n");
+        text.push_str(&hexdump(&self.memory, 0..=MEMORY_SIZE - 1, &[], false));
+        text.push_str("\nDUMP END\n\n");
+        text
+    }
+}
+
+pub fn describe_opcode(opcode: u16) -> String {
+    match Operation::try_from_u16(opcode) {
+        Some(op) => format!("{op:?}"),
+        None => format!("{opcode:#X}"),
+    }
+}
+
+pub fn json_string(string: &str) -> String {
+    let mut json = String::from("\"");
+    for character in string.chars() {
+        match character {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            character if (character as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", character as u32);
+            }
+            character => json.push(character),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn json_array(values: &[u16]) -> String {
+    let values: Vec<String> = values.iter().map(u16::to_string).collect();
+    format!("[{}]", values.join(", "))
+}
+
+fn json_option(value: Option<u16>) -> String {
+    value.map_or("null".to_string(), |value| value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CoreDump;
+    use crate::compiler::Layout;
+    use crate::interpreter::{Fault, STACK_BASE, State};
+    use crate::json::Json;
+    use crate::testing::{assemble_program, boot};
+
+    const PROGRAM: &str = "
+        IMM 5 R1
+        CALL DIVIDE
+    DEF DIVIDE
+        PUSH R1
+        DIV R2 R1
+    ";
+
+    fn faulted() -> CoreDump {
+        let assembly = assemble_program(PROGRAM, Layout::default()).unwrap();
+        let mut machine = boot(assembly.bytecode, &assembly.data);
+        machine.source_map = assembly.source_map;
+        assert_eq!(machine.run(), State::Faulted(Fault::DivideByZero));
+        CoreDump::new(&machine)
+    }
+
+    #[test]
+    fn json_core_dump() {
+        let json = Json::parse(&faulted().to_json()).unwrap();
+        let number = |value: &Json| value.as_usize().unwrap();
+        assert_eq!(number(json.get("cycle")), 4);
+        assert_eq!(number(json.get("pc")), 3);
+        let instruction = json.get("instruction");
+        assert_eq!(instruction.get("mnemonic").as_str(), Some("DIV"));
+        assert_eq!(instruction.get("operands").as_array().len(), 2);
+        let location = json.get("location");
+        assert_eq!(location.get("file").as_str(), Some("test.x1"));
+        assert_eq!(number(location.get("line")), 6);
+        assert_eq!(location.get("label").get("name").as_str(), Some("DIVIDE"));
+        assert_eq!(json.get("fault").as_str(), Some("Division by zero"));
+
+        let registers = json.get("registers").as_array();
+        assert_eq!(registers.len(), 0x20);
+        assert_eq!(registers[1].get("name").as_str(), Some("R1"));
+        assert_eq!(number(registers[1].get("value")), 5);
+
+        let stack = json.get("stack").as_array();
+        assert_eq!(stack.len(), 2);
+        assert_eq!(stack[0].get("call_pc"), &Json::Null);
+        assert_eq!(stack[0].get("cells").as_array(), []);
+        assert_eq!(number(stack[1].get("call_pc")), 1);
+        assert_eq!(number(stack[1].get("target")), 2);
+        // RET resumes after the CALL at this address
+        assert_eq!(number(stack[1].get("return_address")), 1);
+        assert_eq!(stack[1].get("call_location").as_str(), Some("test.x1:3:9"));
+        // CALL fills the cell at the stack pointer, PUSH the one above it
+        let cells: Vec<(usize, usize)> = stack[1]
+            .get("cells")
+            .as_array()
+            .iter()
+            .map(|cell| (number(cell.get("address")), number(cell.get("value"))))
+            .collect();
+        assert_eq!(cells, [(STACK_BASE + 1, 0), (STACK_BASE + 2, 5)]);
+
+        let memory = json.get("memory").as_array();
+        assert_eq!(memory.len(), 4096);
+        assert_eq!(number(&memory[1]), 5);
+    }
+
+    #[test]
+    fn text_core_dump() {
+        let text = faulted().to_text();
+        for expected in [
+            "Fault: Division by zero\n",
+            "Cycle: 4\n",
+            "Current Instruction: DIV",
+            "Source: test.x1:6:9 in DIVIDE+1\n",
+            "  R1     [0x001] = 0x5\n",
+            "  CALL 0x2 from 0x1, returns to 0x1 (called at test.x1:3:9)\n",
+            "    [0xFE2] = 0x5\n",
+        ] {
+            assert!(text.contains(expected), "{expected:?} is not in {text}");
+        }
+    }
+}
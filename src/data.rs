@@ -0,0 +1,162 @@
+use crate::compiler::{Diagnostic, Line};
+use crate::operation::parse_escapes;
+
+/*
+DATA DIRECTIVES
+
+.word VALUE ...     -> one word per VALUE
+.string "text"      -> one word per character followed by a zero word
+.pstring "text"     -> the length followed by one word per character
+.fill N VALUE       -> N copies of VALUE
+.reserve N          -> N zero words
+
+Data is placed in program memory starting at 0x040, in source order. A label
+(DEF NAME) directly before a data directive is bound to the address of that
+data instead of an instruction.
+*/
+
+pub fn is_directive(line: &Line) -> bool {
+    line.words.first().is_some_and(|word| word.starts_with('.'))
+}
+
+/// Number of words the data directive on `line` produces. Only the count of
+/// .fill and .reserve is resolved with `value`.
+pub fn directive_size(
+    line: &Line,
+    value: impl Fn(&str) -> Result<u16, Diagnostic>,
+) -> Result<usize, Diagnostic> {
+    match line.words[0].to_ascii_lowercase().as_str() {
+        ".fill" | ".reserve" if line.words.len() > 1 => Ok(value(&line.words[1])? as usize),
+        _ => directive_data(line, |_| Ok(0)).map(|words| words.len()),
+    }
+}
+
+/// Words produced by the data directive on `line`. `value` resolves a single
+/// operand word (literal or name) to its value.
+pub fn directive_data(
+    line: &Line,
+    value: impl Fn(&str) -> Result<u16, Diagnostic>,
+) -> Result<Vec<u16>, Diagnostic> {
+    let directive = line.words[0].to_ascii_lowercase();
+    let args = &line.words[1..];
+    let count = |expected: usize| {
+        if args.len() == expected {
+            Ok(())
+        } else {
+            Err(Diagnostic::error(
+                line,
+                format!(
+                    "{directive} takes {expected} arguments, {} given",
+                    args.len()
+                ),
+            ))
+        }
+    };
+
+    match directive.as_str() {
+        ".word" => args.iter().map(|arg| value(arg)).collect(),
+        ".string" | ".pstring" => {
+            count(1)?;
+            let text = args[0]
+                .strip_prefix('"')
+                .and_then(|text| text.strip_suffix('"'))
+                .ok_or_else(|| {
+                    Diagnostic::error(line, format!("{directive} expects a quoted string"))
+                })?;
+            let mut words =
+                parse_escapes(text).map_err(|message| Diagnostic::error(line, message))?;
+            if directive == ".string" {
+                words.push(0);
+            } else {
+                words.insert(0, words.len() as u16);
+            }
+            Ok(words)
+        }
+        ".fill" => {
+            count(2)?;
+            Ok(vec![value(&args[1])?; value(&args[0])? as usize])
+        }
+        ".reserve" => {
+            count(1)?;
+            Ok(vec![0; value(&args[0])? as usize])
+        }
+        _ => Err(Diagnostic::error(
+            line,
+            format!("Unknown directive {directive}"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::Layout;
+    use crate::interpreter::PROGRAM_MEMORY_START;
+    use crate::testing::{assemble_program, run_program};
+
+    #[test]
+    fn directives_produce_their_words() {
+        let program = "
+            DEF COUNT 2
+            .word 1 -1 'a' (COUNT + 1)
+            .string \"hi\\n\"
+            .pstring \"ok\"
+            .fill COUNT 0x7
+            .reserve 3
+            .string \"\"
+        ";
+        let assembly = assemble_program(program, Layout::default()).unwrap();
+        assert_eq!(
+            assembly.data,
+            [
+                1, 0xFFFF, 0x61, 3, 0x68, 0x69, 0x0A, 0, 2, 0x6F, 0x6B, 7, 7, 0, 0, 0, 0
+            ]
+        );
+    }
+
+    #[test]
+    fn labels_hold_data_addresses() {
+        let program = "
+                MOV NAME R1
+                MOV (NAME + 1) R2
+                MOV AFTER R3
+                JMP END
+            DEF BUFFER
+                .reserve 4
+            DEF NAME
+                .pstring \"abc\"
+            DEF AFTER
+                .word 0x55
+            DEF END
+        ";
+        let start = PROGRAM_MEMORY_START as u16;
+        let assembly = assemble_program(program, Layout::default()).unwrap();
+        let address = |name: &str| {
+            let symbol = assembly.symbols.iter().find(|symbol| symbol.name == name);
+            symbol.map(|symbol| symbol.value.clone())
+        };
+        assert_eq!(address("BUFFER"), Some(vec![start]));
+        assert_eq!(address("NAME"), Some(vec![start + 4]));
+        assert_eq!(address("AFTER"), Some(vec![start + 8]));
+        let machine = run_program(program);
+        assert_eq!(machine.memory[1..4], [3, 0x61, 0x55]);
+    }
+
+    #[test]
+    fn malformed_directives() {
+        let error = |program: &str| {
+            assemble_program(program, Layout::default())
+                .err()
+                .unwrap_or_default()
+        };
+        assert_eq!(
+            error(".fill 3"),
+            "test.x1:1: .fill takes 2 arguments, 1 given"
+        );
+        assert_eq!(
+            error(".string abc"),
+            "test.x1:1: .string expects a quoted string"
+        );
+        assert_eq!(error(".string \"\\q\""), "test.x1:1: Unknown escape \\q");
+        assert_eq!(error(".bytes 1"), "test.x1:1: Unknown directive .bytes");
+    }
+}
@@ -0,0 +1,188 @@
+use crate::coredump::DEFAULT_CORE_FILE;
+use crate::hexdump::hexdump;
+use crate::interpreter::MEMORY_SIZE;
+use crate::interpreter::{Access, Machine, State, WatchAction, Watchpoint};
+use crate::operation::parse_number;
+use std::io::{self, BufRead, IsTerminal, Write};
+
+/*
+DEBUGGER COMMANDS
+
+step [N]        / s   -> executes N instructions (default 1)
+back [N]        / b   -> undoes N instructions (default 1)
+continue        / c   -> runs until the program halts, finishes or hits a watchpoint
+last-write ADDR / lw  -> runs backward to the previous write of ADDR
+rewind CYCLE    / rw  -> moves to the state before CYCLE executed
+watch ADDR[-END] [r|w|c] [log]
+                / w   -> pauses (or logs) on reads, writes or changes of ADDR..=END
+unwatch ID            -> removes a watchpoint
+watches               -> lists watchpoints
+mem [START [END]] / m -> hexdump of memory, cells changed by the last step are marked
+regs            / r   -> prints an interpreter dump
+dump [PATH]           -> writes a core dump (JSON for .json paths, text otherwise)
+quit            / q   -> exits the debugger
+*/
+
+pub const DEFAULT_HISTORY: usize = 100_000;
+
+pub fn debug(mut machine: Machine) {
+    let stdin = io::stdin();
+
+    print_location(&machine);
+    loop {
+        print!("(x1) ");
+        io::stdout().flush().unwrap();
+
+        let mut input = String::new();
+        if stdin.lock().read_line(&mut input).unwrap_or(0) == 0 {
+            break;
+        }
+        let words: Vec<&str> = input.split_ascii_whitespace().collect();
+        let Some(command) = words.first() else {
+            continue;
+        };
+        let argument = words.get(1).and_then(|word| parse_number(word));
+
+        match *command {
+            "step" | "s" => {
+                for _ in 0..argument.unwrap_or(1) {
+                    let state = machine.step();
+                    if state != State::Running {
+                        print_state(state);
+                        break;
+                    }
+                }
+            }
+            "back" | "b" => {
+                for _ in 0..argument.unwrap_or(1) {
+                    if !machine.step_back() {
+                        println!("No more history.");
+                        break;
+                    }
+                }
+            }
+            "continue" | "c" => print_state(machine.run()),
+            "last-write" | "lw" => match argument {
+                Some(address) => match machine.run_back_to_write(address as usize) {
+                    Some(cycle) => println!("0x{address:X} last written on cycle {cycle}."),
+                    None => println!("No recorded write to 0x{address:X}."),
+                },
+                None => println!("Usage: last-write ADDR"),
+            },
+            "rewind" | "rw" => match argument {
+                Some(cycle) => {
+                    if !machine.rewind_to(cycle) {
+                        println!(
+                            "Cannot reach cycle {cycle}, oldest recorded cycle is {}.",
+                            machine.oldest_cycle()
+                        );
+                    }
+                }
+                None => println!("Usage: rewind CYCLE"),
+            },
+            "watch" | "w" => match parse_watchpoint(&words[1..]) {
+                Some(watchpoint) => {
+                    let id = machine.add_watchpoint(watchpoint);
+                    println!("Watchpoint {id} set.");
+                }
+                None => println!("Usage: watch ADDR[-END] [r|w|c] [log]"),
+            },
+            "unwatch" => match argument {
+                Some(id) if machine.remove_watchpoint(id as usize) => {
+                    println!("Watchpoint {id} removed.")
+                }
+                _ => println!("Usage: unwatch ID"),
+            },
+            "watches" => {
+                for (id, watchpoint) in machine.watchpoints() {
+                    println!(
+                        "{id}: 0x{:X}-0x{:X} {:?} {:?}",
+                        watchpoint.start, watchpoint.end, watchpoint.access, watchpoint.action
+                    );
+                }
+            }
+            "mem" | "m" => {
+                let start = argument.unwrap_or(0) as usize;
+                let end = words
+                    .get(2)
+                    .and_then(|word| parse_number(word))
+                    .map_or(MEMORY_SIZE - 1, |end| end as usize);
+                print!(
+                    "{}",
+                    hexdump(
+                        &machine.memory,
+                        start..=end,
+                        &machine.last_writes(),
+                        io::stdout().is_terminal()
+                    )
+                );
+            }
+            "regs" | "r" => machine.core_dump(),
+            "dump" => {
+                let path = words.get(1).copied().unwrap_or(DEFAULT_CORE_FILE);
+                match machine.dump().write(path) {
+                    Ok(()) => println!("Core dumped to {path}."),
+                    Err(error) => println!("Error writing {path}: {error}"),
+                }
+            }
+            "quit" | "q" => break,
+            _ => println!("Unknown command {command}."),
+        }
+        for hit in machine.take_watch_log() {
+            println!(
+                "Watchpoint {} {:?} 0x{:X} at {} (cycle {}): 0x{:X} -> 0x{:X}",
+                hit.id,
+                hit.access,
+                hit.address,
+                machine.source_map.describe(hit.pc),
+                hit.cycle,
+                hit.old,
+                hit.new
+            );
+        }
+        print_location(&machine);
+    }
+}
+
+fn print_location(machine: &Machine) {
+    println!(
+        "[cycle {}] {}",
+        machine.cycle,
+        machine.describe(machine.pc())
+    );
+}
+
+fn print_state(state: State) {
+    match state {
+        State::Running => {}
+        State::Halted(code) => println!("Halted with exit code 0x{code:X}."),
+        State::Finished => println!("Program finished."),
+        State::Paused => println!("Paused by watchpoint."),
+        State::Faulted(fault) => println!("Faulted: {fault}."),
+    }
+}
+
+fn parse_watchpoint(words: &[&str]) -> Option<Watchpoint> {
+    let range = words.first()?;
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (parse_number(start)?, parse_number(end)?),
+        None => (parse_number(range)?, parse_number(range)?),
+    };
+    let mut access = Access::Write;
+    let mut action = WatchAction::Pause;
+    for word in &words[1..] {
+        match *word {
+            "r" => access = Access::Read,
+            "w" => access = Access::Write,
+            "c" => access = Access::Change,
+            "log" => action = WatchAction::Log,
+            _ => return None,
+        }
+    }
+    Some(Watchpoint {
+        start: start as usize,
+        end: end as usize,
+        access,
+        action,
+    })
+}
@@ -0,0 +1,331 @@
+use crate::operation::literal_value;
+
+/*
+CONSTANT EXPRESSIONS
+
+(LEN - 1)    (BUF + 16)    ($ + 2)    (1 << 4 | MASK)    (SIZE >= 0x100)
+
+Evaluated at assembly time with 64 bit signed arithmetic. Operators, from
+lowest to highest precedence:
+
+||           logical or
+&&           logical and
+|            bitwise or
+^            bitwise xor
+&            bitwise and
+== !=        equality
+< > <= >=    comparison
+<< >>        shifts
++ -          addition, subtraction
+* / %        multiplication, division, remainder
+- ~ ! +      unary negation, bitwise not, logical not, plus
+
+Operands are literals, names (constants and labels) and `$`, the current
+address. Comparisons and logical operators give 1 for true and 0 for false.
+*/
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Value(String),
+    Here,
+    Operator(&'static str),
+    Open,
+    Close,
+}
+
+pub const OPERATORS: [&str; 20] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "<", ">", "!", "+", "-", "*", "/", "%", "&",
+    "|", "^", "~",
+];
+
+/// Whether `word` should be treated as an expression rather than a single
+/// literal or name.
+pub fn is_expression(word: &str) -> bool {
+    word == "$"
+        || word.starts_with('(')
+        || word
+            .get(1..)
+            .is_some_and(|rest| OPERATORS.iter().any(|operator| rest.contains(operator)))
+}
+
+/// Names used in `text`, `$` included.
+pub fn names(text: &str) -> Vec<String> {
+    let tokens = lex(text).unwrap_or_default();
+    tokens
+        .into_iter()
+        .filter_map(|token| match token {
+            Token::Value(value) if !matches!(literal_value(&value), Ok(Some(_))) => Some(value),
+            Token::Here => Some("$".to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// `text` with every name `replace` gives a replacement for replaced.
+/// Numbers, operators, spacing and quoted characters are kept as written.
+pub fn rename(text: &str, replace: impl Fn(&str) -> Option<String>) -> String {
+    let name = |c: char| c.is_ascii_alphanumeric() || "_.@".contains(c);
+    let mut renamed = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(character) = rest.chars().next() {
+        let end = if matches!(character, '\'' | '"') {
+            closing_quote(rest).map_or(rest.len(), |end| end + 1)
+        } else if name(character) {
+            rest.find(|c: char| !name(c)).unwrap_or(rest.len())
+        } else {
+            character.len_utf8()
+        };
+        match replace(&rest[..end]) {
+            Some(replacement) if name(character) => renamed.push_str(&replacement),
+            _ => renamed.push_str(&rest[..end]),
+        }
+        rest = &rest[end..];
+    }
+    renamed
+}
+
+/// Evaluates `text`. `resolve` returns the value of a name, `here` is the
+/// value of `$`.
+pub fn evaluate(
+    text: &str,
+    here: u16,
+    resolve: &dyn Fn(&str) -> Option<i64>,
+) -> Result<i64, String> {
+    let tokens = lex(text)?;
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+        here,
+        resolve,
+    };
+    let value = parser.expression(0)?;
+    match parser.tokens.get(parser.position) {
+        None => Ok(value),
+        Some(token) => Err(format!("Unexpected {} in {text}", describe(token))),
+    }
+}
+
+fn lex(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text;
+    while let Some(character) = rest.chars().next() {
+        if character.is_ascii_whitespace() {
+            rest = &rest[1..];
+        } else if character == '(' {
+            tokens.push(Token::Open);
+            rest = &rest[1..];
+        } else if character == ')' {
+            tokens.push(Token::Close);
+            rest = &rest[1..];
+        } else if character == '$' {
+            tokens.push(Token::Here);
+            rest = &rest[1..];
+        } else if let Some(operator) = OPERATORS
+            .iter()
+            .find(|operator| rest.starts_with(**operator))
+        {
+            tokens.push(Token::Operator(operator));
+            rest = &rest[operator.len()..];
+        } else if character == '\'' {
+            let end = closing_quote(rest)
+                .ok_or_else(|| format!("Unterminated character literal in {text}"))?;
+            tokens.push(Token::Value(rest[..=end].to_string()));
+            rest = &rest[end + 1..];
+        } else if character.is_ascii_alphanumeric() || "_.@".contains(character) {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || "_.@".contains(c)))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Value(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else {
+            return Err(format!("Unexpected character {character} in {text}"));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Index of the quote closing the character literal or string at the start
+/// of `text`.
+fn closing_quote(text: &str) -> Option<usize> {
+    let quote = text.chars().next()?;
+    let mut escaped = false;
+    for (index, character) in text.char_indices().skip(1) {
+        match character {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            _ if character == quote => return Some(index),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Value(value) => value.clone(),
+        Token::Here => "$".to_string(),
+        Token::Operator(operator) => operator.to_string(),
+        Token::Open => "(".to_string(),
+        Token::Close => ")".to_string(),
+    }
+}
+
+/// Binding strength of a binary operator, 1 for the loosest.
+pub fn precedence(operator: &str) -> usize {
+    match operator {
+        "||" => 1,
+        "&&" => 2,
+        "|" => 3,
+        "^" => 4,
+        "&" => 5,
+        "==" | "!=" => 6,
+        "<" | ">" | "<=" | ">=" => 7,
+        "<<" | ">>" => 8,
+        "+" | "-" => 9,
+        _ => 10,
+    }
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    here: u16,
+    resolve: &'a dyn Fn(&str) -> Option<i64>,
+}
+
+impl Parser<'_> {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    /// Parses binary operators binding tighter than `min`.
+    fn expression(&mut self, min: usize) -> Result<i64, String> {
+        let mut left = self.unary()?;
+        while let Some(Token::Operator(operator)) = self.tokens.get(self.position).cloned() {
+            if operator == "~" || operator == "!" || precedence(operator) <= min {
+                break;
+            }
+            self.position += 1;
+            let right = self.expression(precedence(operator))?;
+            left = apply(operator, left, right)?;
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        match self.next() {
+            Some(Token::Operator("-")) => self.unary()?.checked_neg().ok_or_else(overflow),
+            Some(Token::Operator("~")) => Ok(!self.unary()?),
+            Some(Token::Operator("!")) => Ok((self.unary()? == 0) as i64),
+            Some(Token::Operator("+")) => self.unary(),
+            Some(Token::Open) => {
+                let value = self.expression(0)?;
+                match self.next() {
+                    Some(Token::Close) => Ok(value),
+                    _ => Err("Missing )".to_string()),
+                }
+            }
+            Some(Token::Here) => Ok(self.here as i64),
+            Some(Token::Value(word)) => match literal_value(&word)? {
+                Some(value) => Ok(value),
+                None => (self.resolve)(&word).ok_or_else(|| format!("Undefined symbol {word}")),
+            },
+            Some(token) => Err(format!("Unexpected {}", describe(&token))),
+            None => Err("Unexpected end of expression".to_string()),
+        }
+    }
+}
+
+fn overflow() -> String {
+    "Expression overflows".to_string()
+}
+
+/// Result of the binary `operator`.
+pub fn apply(operator: &str, left: i64, right: i64) -> Result<i64, String> {
+    let shift = || {
+        u32::try_from(right)
+            .ok()
+            .filter(|shift| *shift < 64)
+            .ok_or_else(|| format!("Invalid shift amount {right}"))
+    };
+    match operator {
+        "||" => Ok((left != 0 || right != 0) as i64),
+        "&&" => Ok((left != 0 && right != 0) as i64),
+        "==" => Ok((left == right) as i64),
+        "!=" => Ok((left != right) as i64),
+        "<" => Ok((left < right) as i64),
+        ">" => Ok((left > right) as i64),
+        "<=" => Ok((left <= right) as i64),
+        ">=" => Ok((left >= right) as i64),
+        "|" => Ok(left | right),
+        "^" => Ok(left ^ right),
+        "&" => Ok(left & right),
+        "<<" => left.checked_shl(shift()?).ok_or_else(overflow),
+        ">>" => Ok(left >> shift()?),
+        "+" => left.checked_add(right).ok_or_else(overflow),
+        "-" => left.checked_sub(right).ok_or_else(overflow),
+        "*" => left.checked_mul(right).ok_or_else(overflow),
+        "/" | "%" if right == 0 => Err("Division by zero in expression".to_string()),
+        "/" => left.checked_div(right).ok_or_else(overflow),
+        _ => left.checked_rem(right).ok_or_else(overflow),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::evaluate;
+    use crate::compiler::Layout;
+    use crate::testing::{assemble_program, registers};
+
+    fn value(text: &str) -> Result<i64, String> {
+        evaluate(text, 5, &|name| (name == "MASK").then_some(0xF0))
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(value("(1 + 2 * 3)"), Ok(7));
+        assert_eq!(value("((1 + 2) * 3)"), Ok(9));
+        assert_eq!(value("(10 - 4 - 3)"), Ok(3));
+        assert_eq!(value("(1 << 4 | 3)"), Ok(19));
+        assert_eq!(value("(MASK & 0x30 ^ 0x01)"), Ok(0x31));
+        assert_eq!(value("(-2 * -3 % 4)"), Ok(2));
+        assert_eq!(value("(1 + 2 == 3 && 4 > 5 || !0)"), Ok(1));
+        assert_eq!(value("(~0 & 0xFF)"), Ok(0xFF));
+        assert_eq!(value("($ + 2)"), Ok(7));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            value("(1 / 0)"),
+            Err("Division by zero in expression".into())
+        );
+        assert_eq!(value("(1 << 64)"), Err("Invalid shift amount 64".into()));
+        assert_eq!(value("((1 + 2)"), Err("Missing )".into()));
+        assert_eq!(value("(1 +)"), Err("Unexpected )".into()));
+        assert_eq!(value("(SIZE + 1)"), Err("Undefined symbol SIZE".into()));
+    }
+
+    #[test]
+    fn operands() {
+        let program = "
+            DEF LEN 4
+            DEF LAST (LEN - 1)
+            IMM (LEN * 2 + 1) R1
+            IMM (LAST << 4) R2
+            DEF HERE
+            IMM ($ - HERE + 1) R3
+        ";
+        let layout = Layout::default();
+        assert_eq!(
+            registers(assemble_program(program, layout)),
+            Ok([9, 0x30, 1])
+        );
+        let error = assemble_program("IMM (1 / (2 - 2)) R1", layout)
+            .err()
+            .unwrap();
+        assert!(error.contains("Division by zero"), "{error}");
+    }
+}
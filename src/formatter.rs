@@ -0,0 +1,337 @@
+use crate::compiler::split_line;
+use crate::operation::Operation;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/*
+FORMATTING
+
+eightbit fmt program.x1 ... [--check]
+
+// constants                    comments are indented like the line below them
+DEF SIZE   0x10                 constants, with the values of a group aligned
+DEF SCREEN 0x1F0
+
+DEF MAIN                        labels at the start of the line
+    IMM  0x1F R1   // counter   instructions, data and macro calls indented,
+    CALL MAIN      // again     comments aligned within a paragraph
+    .word 1 2 3
+
+MACRO, ENDM, INCLUDE, EXPORT, EXTERN and the conditional directives start at
+the beginning of the line as well. Operands follow the mnemonic after padding
+it to the longest one, words are separated by one space.
+
+Hexadecimal digits are written in upper case (0x1F). Runs of blank lines
+become one, a global label and the comments right above it are separated
+from the code before them by a blank line, and the file has no blank lines at
+its start or end. Words and comments are otherwise kept as written.
+
+With --check nothing is written, the files that are not formatted are listed
+and the exit status is 1 if there are any.
+*/
+
+const INDENT: &str = "    ";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Blank,
+    Comment,
+    Label,
+    Constant,
+    Directive,
+    Code,
+}
+
+struct Row {
+    kind: Kind,
+    words: Vec<String>,
+    comment: Option<String>,
+}
+
+/// `text` in the canonical layout.
+pub fn format(text: &str) -> String {
+    let mut rows: Vec<Row> = Vec::new();
+    for line in text.lines() {
+        let row = parse(line);
+        let blank = row.kind == Kind::Blank;
+        if blank && rows.last().is_none_or(|last| last.kind == Kind::Blank) {
+            continue;
+        }
+        if row.kind == Kind::Label && !row.words[1].starts_with('.') {
+            separate(&mut rows);
+        }
+        rows.push(row);
+    }
+    while rows.last().is_some_and(|last| last.kind == Kind::Blank) {
+        rows.pop();
+    }
+
+    let mut lines: Vec<String> = Vec::new();
+    for (index, row) in rows.iter().enumerate() {
+        let line = match row.kind {
+            Kind::Blank => String::new(),
+            Kind::Comment => {
+                let next = rows[index..].iter().find(|row| row.kind != Kind::Comment);
+                match next {
+                    Some(next) if next.kind == Kind::Code => INDENT.to_string(),
+                    _ => String::new(),
+                }
+            }
+            Kind::Label | Kind::Directive => row.words.join(" "),
+            Kind::Constant => {
+                let group = |row: &&Row| row.kind == Kind::Constant;
+                let before = rows[..index].iter().rev().take_while(group);
+                let after = rows[index..].iter().take_while(group);
+                let width = before.chain(after).map(|row| row.words[1].len()).max();
+                format!(
+                    "{} {:<width$} {}",
+                    row.words[0],
+                    row.words[1],
+                    row.words[2..].join(" "),
+                    width = width.unwrap_or_default()
+                )
+            }
+            Kind::Code => {
+                let width = match Operation::from_name(&row.words[0]) {
+                    Some(_) if row.words.len() > 1 => mnemonic_width(),
+                    _ => 0,
+                };
+                let operands = row.words[1..].join(" ");
+                format!("{INDENT}{:<width$} {operands}", row.words[0])
+                    .trim_end()
+                    .to_string()
+            }
+        };
+        lines.push(line);
+    }
+
+    // comments after code line up within each paragraph
+    let mut start = 0;
+    while start < rows.len() {
+        let end = (start..rows.len())
+            .find(|index| rows[*index].kind == Kind::Blank)
+            .unwrap_or(rows.len());
+        let column = (start..end)
+            .filter(|index| rows[*index].comment.is_some() && rows[*index].kind != Kind::Comment)
+            .map(|index| lines[index].len() + 2)
+            .max()
+            .unwrap_or_default();
+        for index in start..end {
+            if let Some(comment) = &rows[index].comment {
+                let line = &mut lines[index];
+                if rows[index].kind != Kind::Comment {
+                    let padding = column - line.len();
+                    line.push_str(&" ".repeat(padding));
+                }
+                line.push_str(comment);
+            }
+        }
+        start = end + 1;
+    }
+
+    let mut formatted = lines.join("\n");
+    if !formatted.is_empty() {
+        formatted.push('\n');
+    }
+    formatted
+}
+
+/// The `paths` whose files are not formatted. They are rewritten in the
+/// canonical layout unless `check` is set.
+pub fn format_files<'a>(
+    paths: impl IntoIterator<Item = &'a Path>,
+    check: bool,
+) -> io::Result<Vec<PathBuf>> {
+    let mut unformatted = Vec::new();
+    for path in paths {
+        let text = fs::read_to_string(path)?;
+        let formatted = format(&text);
+        if formatted == text {
+            continue;
+        }
+        if !check {
+            fs::write(path, formatted)?;
+        }
+        unformatted.push(path.to_path_buf());
+    }
+    Ok(unformatted)
+}
+
+fn parse(line: &str) -> Row {
+    let (words, comment) = split_line(line);
+    let words: Vec<String> = words.iter().map(|word| upper_hex(word)).collect();
+    let comment = comment.map(|comment| comment.trim_end().to_string());
+    let kind = match words.first().map(String::as_str) {
+        None if comment.is_some() => Kind::Comment,
+        None => Kind::Blank,
+        Some(first) if Operation::from_name(first) == Some(Operation::DEF) => match words.len() {
+            2 => Kind::Label,
+            _ if words.len() > 2 => Kind::Constant,
+            _ => Kind::Directive,
+        },
+        Some("MACRO" | "ENDM" | "INCLUDE" | "EXPORT" | "EXTERN") => Kind::Directive,
+        Some(first)
+            if matches!(
+                first.to_ascii_lowercase().as_str(),
+                ".if" | ".ifdef" | ".ifndef" | ".elif" | ".else" | ".endif"
+            ) =>
+        {
+            Kind::Directive
+        }
+        Some(_) => Kind::Code,
+    };
+    Row {
+        kind,
+        words,
+        comment,
+    }
+}
+
+/// Puts a blank line before the comments at the end of `rows`, unless they
+/// follow a label or directive or start the file.
+fn separate(rows: &mut Vec<Row>) {
+    let start = rows.len()
+        - rows
+            .iter()
+            .rev()
+            .take_while(|row| row.kind == Kind::Comment)
+            .count();
+    let previous = start.checked_sub(1).map(|index| rows[index].kind);
+    if matches!(previous, Some(Kind::Code | Kind::Constant)) {
+        rows.insert(
+            start,
+            Row {
+                kind: Kind::Blank,
+                words: Vec::new(),
+                comment: None,
+            },
+        );
+    }
+}
+
+fn mnemonic_width() -> usize {
+    Operation::ALL
+        .iter()
+        .map(|op| op.name().len())
+        .max()
+        .unwrap_or_default()
+}
+
+/// `word` with the digits of its hexadecimal numbers in upper case.
+/// Characters and strings are left alone.
+fn upper_hex(word: &str) -> String {
+    let name = |c: char| c.is_ascii_alphanumeric() || "_.@".contains(c);
+    let mut result = String::with_capacity(word.len());
+    let mut quote = None;
+    let mut escaped = false;
+    let mut previous = ' ';
+    let mut index = 0;
+    while let Some(character) = word[index..].chars().next() {
+        match quote {
+            Some(open) => {
+                if escaped {
+                    escaped = false;
+                } else if character == '\\' {
+                    escaped = true;
+                } else if character == open {
+                    quote = None;
+                }
+            }
+            None if !name(previous) && word[index..].starts_with("0x") => {
+                let end = word[index..]
+                    .find(|c: char| !name(c))
+                    .map_or(word.len(), |end| index + end);
+                result.push_str("0x");
+                result.push_str(&word[index + 2..end].to_ascii_uppercase());
+                previous = 'x';
+                index = end;
+                continue;
+            }
+            None if matches!(character, '\'' | '"') => quote = Some(character),
+            None => {}
+        }
+        result.push(character);
+        previous = character;
+        index += character.len_utf8();
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format, format_files};
+    use crate::compiler::Layout;
+    use crate::testing::{assemble_program, directory};
+    use std::fs;
+
+    const MESSY: &str = "
+
+// the counter
+def size 0x1f
+DEF SCREEN_START 0x1f0
+   IMM   0xab R1 // load
+INC R1   //   again
+
+
+    .word 0xff 'a'
+  .string   \"a  b\"
+// a routine
+DEF ROUTINE
+      RET
+";
+
+    const FORMATTED: &str = "// the counter
+def size         0x1F
+DEF SCREEN_START 0x1F0
+    IMM   0xAB R1  // load
+    INC   R1       //   again
+
+    .word 0xFF 'a'
+    .string \"a  b\"
+
+// a routine
+DEF ROUTINE
+    RET
+";
+
+    #[test]
+    fn formats_to_the_canonical_layout() {
+        assert_eq!(format(MESSY), FORMATTED);
+        assert_eq!(format(""), "");
+        assert_eq!(format("\n\n"), "");
+    }
+
+    #[test]
+    fn formatting_is_idempotent_and_keeps_the_program() {
+        let sample = include_str!("../program.x1");
+        for text in [sample, MESSY] {
+            let formatted = format(text);
+            assert_eq!(format(&formatted), formatted);
+            let comments = |text: &str| {
+                text.lines()
+                    .filter_map(|line| line.find("//").map(|at| line[at..].trim_end().to_string()))
+                    .collect::<Vec<String>>()
+            };
+            assert_eq!(comments(&formatted), comments(text));
+            let before = assemble_program(text, Layout::default()).unwrap();
+            let after = assemble_program(&formatted, Layout::default()).unwrap();
+            assert_eq!(after.bytecode, before.bytecode);
+            assert_eq!(after.data, before.data);
+        }
+    }
+
+    #[test]
+    fn checking_lists_unformatted_files() {
+        let dir = directory("format", &[("messy.x1", MESSY), ("tidy.x1", FORMATTED)]);
+        let (messy, tidy) = (dir.join("messy.x1"), dir.join("tidy.x1"));
+        let paths = [messy.as_path(), tidy.as_path()];
+
+        assert_eq!(format_files(paths, true).unwrap(), [messy.as_path()]);
+        assert_eq!(fs::read_to_string(&messy).unwrap(), MESSY);
+
+        assert_eq!(format_files(paths, false).unwrap(), [messy.as_path()]);
+        assert_eq!(fs::read_to_string(&messy).unwrap(), FORMATTED);
+        assert!(format_files(paths, true).unwrap().is_empty());
+    }
+}
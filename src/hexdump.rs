@@ -0,0 +1,169 @@
+use crate::interpreter::{
+    MEMORY_SIZE, PROGRAM_MEMORY_END, PROGRAM_MEMORY_START, RESERVED_REGISTERS_END,
+    RETURN_REGISTER_ADDRESS, STACK_BASE, STACK_END,
+};
+use std::fmt::Write as _;
+use std::ops::RangeInclusive;
+
+/*
+MEMORY MAP (see interpreter.rs)
+
+0x000 -> 0x00F arithmetic registers
+0x010 -> 0x01F reserved registers
+0x020 -> 0x03F reserved
+0x040 -> 0xFDF program memory
+0xFE0 -> 0xFEF stack
+0xFF0 -> 0xFFF unmapped
+*/
+
+pub const REGIONS: [(&str, usize, usize); 6] = [
+    ("arithmetic registers", 0x000, RETURN_REGISTER_ADDRESS - 1),
+    (
+        "reserved registers",
+        RETURN_REGISTER_ADDRESS,
+        RESERVED_REGISTERS_END,
+    ),
+    (
+        "reserved",
+        RESERVED_REGISTERS_END + 1,
+        PROGRAM_MEMORY_START - 1,
+    ),
+    ("program memory", PROGRAM_MEMORY_START, PROGRAM_MEMORY_END),
+    ("stack", STACK_BASE, STACK_END),
+    ("unmapped", STACK_END + 1, MEMORY_SIZE - 1),
+];
+
+const WORDS_PER_ROW: usize = 8;
+
+const BOLD: &str = "\x1b[1m";
+const CHANGED: &str = "\x1b[1;33m";
+const RESET: &str = "\x1b[0m";
+
+/// Renders `range` of `memory` as a hexdump with one header per memory map
+/// region. Runs of all-zero rows collapse into `*`. Cells listed in `changed`
+/// are prefixed with `*` (or shown in yellow with `color`), and with `color`
+/// non-zero cells are bold.
+pub fn hexdump(
+    memory: &[u16],
+    range: RangeInclusive<usize>,
+    changed: &[usize],
+    color: bool,
+) -> String {
+    let start = *range.start();
+    let end = (*range.end()).min(MEMORY_SIZE - 1);
+    let mut text = String::new();
+
+    for (name, region_start, region_end) in REGIONS {
+        if region_end < start || region_start > end {
+            continue;
+        }
+        let _ = writeln!(text, "; {name} (0x{region_start:03X}-0x{region_end:03X})");
+
+        let first = start.max(region_start);
+        let last = end.min(region_end);
+        let mut skipping = false;
+        let mut row = first - first % WORDS_PER_ROW;
+        while row <= last {
+            let addresses = row.max(first)..=(row + WORDS_PER_ROW - 1).min(last);
+            let quiet = addresses
+                .clone()
+                .all(|address| memory[address] == 0 && !changed.contains(&address));
+            if quiet {
+                if !skipping {
+                    text.push_str("*\n");
+                    skipping = true;
+                }
+                row += WORDS_PER_ROW;
+                continue;
+            }
+            skipping = false;
+
+            let _ = write!(text, "0x{row:03X} ");
+            let mut ascii = String::new();
+            for (address, &value) in (row..).zip(&memory[row..row + WORDS_PER_ROW]) {
+                if !addresses.contains(&address) {
+                    text.push_str("     ");
+                    ascii.push(' ');
+                    continue;
+                }
+                let was_changed = changed.contains(&address);
+                match (color, was_changed, value != 0) {
+                    (true, true, _) => {
+                        let _ = write!(text, " {CHANGED}{value:04X}{RESET}");
+                    }
+                    (true, false, true) => {
+                        let _ = write!(text, " {BOLD}{value:04X}{RESET}");
+                    }
+                    (false, true, _) => {
+                        let _ = write!(text, "*{value:04X}");
+                    }
+                    _ => {
+                        let _ = write!(text, " {value:04X}");
+                    }
+                }
+                ascii.push(printable(value));
+            }
+            let _ = writeln!(text, "  |{ascii}|");
+            row += WORDS_PER_ROW;
+        }
+    }
+    text
+}
+
+fn printable(value: u16) -> char {
+    match u8::try_from(value) {
+        Ok(byte) if byte.is_ascii_graphic() || byte == b' ' => byte as char,
+        _ => '.',
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{REGIONS, hexdump};
+    use crate::interpreter::MEMORY_SIZE;
+
+    #[test]
+    fn regions_cover_memory() {
+        assert_eq!(REGIONS[0].1, 0);
+        assert_eq!(REGIONS[REGIONS.len() - 1].2, MEMORY_SIZE - 1);
+        for pair in REGIONS.windows(2) {
+            assert_eq!(pair[0].2 + 1, pair[1].1, "{} and {}", pair[0].0, pair[1].0);
+        }
+    }
+
+    #[test]
+    fn dumps_rows_by_region() {
+        let mut memory = vec![0; MEMORY_SIZE];
+        memory[0x001] = 0x41;
+        memory[0x010] = 0x1234;
+        memory[0x042] = 0x7E;
+        let expected = "\
+; arithmetic registers (0x000-0x00F)
+0x000  0000 0041 0000 0000 0000 0000 0000 0000  |.A......|
+*
+; reserved registers (0x010-0x01F)
+0x010  1234 0000 0000 0000 0000 0000 0000 0000  |........|
+*
+; reserved (0x020-0x03F)
+*
+; program memory (0x040-0xFDF)
+0x040  0000 0000 007E 0000                      |..~.    |
+";
+        assert_eq!(hexdump(&memory, 0x000..=0x043, &[], false), expected);
+
+        // a partial row, and changed cells show even when zero
+        let expected = "\
+; program memory (0x040-0xFDF)
+0x040           *007E 0000*0000                 |  ~..   |
+";
+        assert_eq!(
+            hexdump(&memory, 0x042..=0x044, &[0x042, 0x044], false),
+            expected
+        );
+        let colored = hexdump(&memory, 0x040..=0x047, &[0x041], true);
+        assert!(
+            colored.contains("\x1b[1;33m0000\x1b[0m \x1b[1m007E\x1b[0m"),
+            "{colored:?}"
+        );
+    }
+}
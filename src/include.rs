@@ -0,0 +1,198 @@
+use crate::compiler::{Diagnostic, Line, tokenize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/*
+INCLUDES
+
+INCLUDE "path.x1"
+
+Replaces the line with the lines of path.x1, resolved relative to the
+directory of the including file. Lines keep the name of the file they came
+from for diagnostics. A file may not include itself, directly or through
+other files.
+
+An INCLUDE inside an .if block is only read once the assembler reaches it in
+a branch that is assembled, see conditional.rs.
+*/
+
+/// Replaces every INCLUDE line of `lines` outside .if blocks with the
+/// included file. `dir` is the directory of the file `lines` came from and
+/// `stack` holds the canonical paths of the files currently being included.
+pub fn resolve_includes(
+    lines: Vec<Line>,
+    dir: &Path,
+    stack: &mut Vec<PathBuf>,
+) -> Result<Vec<Line>, Diagnostic> {
+    let includes: Rc<[PathBuf]> = Rc::from(stack.as_slice());
+    let mut depth = 0usize;
+    let mut resolved = Vec::new();
+    for mut line in lines {
+        line.includes = includes.clone();
+        let first = line.words.first().map(String::as_str).unwrap_or_default();
+        match first.to_ascii_lowercase().as_str() {
+            ".if" | ".ifdef" | ".ifndef" => depth += 1,
+            ".endif" => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        if first != "INCLUDE" || depth > 0 {
+            resolved.push(line);
+            continue;
+        }
+
+        let name = line.words[1..].join(" ");
+        let name = name.trim_matches('"');
+        if name.is_empty() {
+            return Err(Diagnostic::error(&line, "INCLUDE without path".to_string()));
+        }
+        let path = dir.join(name);
+        resolved.extend(read_source(&path, &line, stack)?);
+    }
+    Ok(resolved)
+}
+
+/// Replaces `include`, an INCLUDE line left inside an .if block, with the
+/// included file wherever it appears in `lines`.
+pub fn resolve_include(lines: Vec<Line>, include: &Line) -> Result<Vec<Line>, Diagnostic> {
+    let mut resolved = Vec::new();
+    for line in lines {
+        if line != *include {
+            resolved.push(line);
+            continue;
+        }
+        let path = Path::new(&*line.file);
+        let dir = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+        let mut stack = line.includes.to_vec();
+        resolved.extend(resolve_includes(vec![line], &dir, &mut stack)?);
+    }
+    Ok(resolved)
+}
+
+/// Reads every INCLUDE left inside an .if block that can be read, for
+/// looking at all branches of a program.
+pub fn resolve_readable(lines: Vec<Line>) -> Vec<Line> {
+    let mut resolved = Vec::new();
+    for line in lines {
+        if line.words.first().map(String::as_str) == Some("INCLUDE")
+            && let Ok(included) = resolve_include(vec![line.clone()], &line)
+        {
+            resolved.extend(resolve_readable(included));
+        } else {
+            resolved.push(line);
+        }
+    }
+    resolved
+}
+
+fn read_source(
+    path: &Path,
+    include: &Line,
+    stack: &mut Vec<PathBuf>,
+) -> Result<Vec<Line>, Diagnostic> {
+    let error = |message: String| Diagnostic::error(include, message);
+
+    let canonical = path
+        .canonicalize()
+        .map_err(|err| error(format!("Cannot include {}: {err}", path.display())))?;
+    if stack.contains(&canonical) {
+        let cycle: Vec<String> = stack
+            .iter()
+            .chain([&canonical])
+            .map(|file| file.display().to_string())
+            .collect();
+        return Err(error(format!("Include cycle: {}", cycle.join(" -> "))));
+    }
+    let program = fs::read_to_string(path)
+        .map_err(|err| error(format!("Cannot include {}: {err}", path.display())))?;
+
+    let lines = tokenize(&program, Rc::from(path.display().to_string()));
+    let dir = path.parent().unwrap_or(Path::new("."));
+    stack.push(canonical);
+    let resolved = resolve_includes(lines, dir, stack);
+    stack.pop();
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::Layout;
+    use crate::testing::{assemble_file, directory, registers};
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn includes_are_relative_to_their_file() {
+        let dir = directory(
+            "relative-includes",
+            &[
+                ("lib/outer.x1", "IMM 1 R1\nINCLUDE \"inner.x1\""),
+                ("lib/inner.x1", "IMM 2 R2"),
+            ],
+        );
+        let program = "INCLUDE \"lib/outer.x1\"\nIMM 3 R3";
+        let assembly = assemble_file(&dir.join("main.x1"), program, &[], Layout::default());
+        assert_eq!(registers(assembly), Ok([1, 2, 3]));
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn include_cycles_are_rejected() {
+        let dir = directory(
+            "include-cycle",
+            &[
+                ("a.x1", "INCLUDE \"b.x1\""),
+                ("b.x1", "IMM 1 R1\nINCLUDE \"a.x1\""),
+            ],
+        );
+        let error = assemble_file(
+            &dir.join("main.x1"),
+            "INCLUDE \"a.x1\"",
+            &[],
+            Layout::default(),
+        );
+        let (a, b) = (dir.join("a.x1"), dir.join("b.x1"));
+        let canonical = |path: &Path| path.canonicalize().unwrap().display().to_string();
+        assert_eq!(
+            error.err().unwrap_or_default(),
+            format!(
+                "{}:2: Include cycle: {} -> {} -> {}",
+                b.display(),
+                canonical(&a),
+                canonical(&b),
+                canonical(&a)
+            )
+        );
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn errors_name_the_included_file() {
+        let dir = directory("include-errors", &[("lib.x1", "IMM 1 R1\n\nJMP MISSING")]);
+        let program = "IMM 2 R2\nINCLUDE \"lib.x1\"";
+        let error = assemble_file(&dir.join("main.x1"), program, &[], Layout::default());
+        assert_eq!(
+            error.err().unwrap_or_default(),
+            format!(
+                "{}:3: Undefined symbol MISSING",
+                dir.join("lib.x1").display()
+            )
+        );
+
+        let error = assemble_file(
+            &dir.join("main.x1"),
+            "\nINCLUDE \"none.x1\"",
+            &[],
+            Layout::default(),
+        );
+        let error = error.err().unwrap_or_default();
+        assert!(
+            error.starts_with(&format!(
+                "{}:2: Cannot include",
+                dir.join("main.x1").display()
+            )),
+            "{error}"
+        );
+        fs::remove_dir_all(dir).ok();
+    }
+}
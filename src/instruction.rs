@@ -0,0 +1,277 @@
+use crate::interpreter::{Fault, check_address};
+use crate::operation::Operation;
+
+/*
+DECODED INSTRUCTIONS
+
+The machine decodes its program once, when it is created, so executing an
+instruction doesn't look at its words again:
+
+- relative jumps and calls hold the address they go to, like absolute ones
+- MOV, IMM and arithmetic naming an address they ignore become NOP, as do DEF
+  and empty instructions
+- an unknown opcode, a missing operand or a jump or call past the end of the
+  program becomes the fault it causes, raised only if the instruction is
+  executed. Jumping to the end itself finishes the program.
+
+Instructions keep their words in Machine::instructions for describing them.
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Nop,
+    Move {
+        src: usize,
+        dest: usize,
+    },
+    Immediate {
+        value: u16,
+        dest: usize,
+    },
+    /// ADD, SUB, MUL, DIV, MOD, AND, OR, XOR, SHL and SHR.
+    Arithmetic {
+        op: Operation,
+        src: usize,
+        dest: usize,
+    },
+    /// INC, DEC and NOT.
+    Unary {
+        op: Operation,
+        dest: usize,
+    },
+    Jump {
+        target: u16,
+    },
+    JumpGreater {
+        target: u16,
+        arg1: usize,
+        arg2: usize,
+    },
+    JumpLess {
+        target: u16,
+        arg1: usize,
+        arg2: usize,
+    },
+    JumpZero {
+        target: u16,
+        arg: usize,
+    },
+    JumpNotZero {
+        target: u16,
+        arg: usize,
+    },
+    Compare {
+        arg1: usize,
+        arg2: usize,
+    },
+    Push {
+        src: usize,
+    },
+    Pop {
+        dest: usize,
+    },
+    Call {
+        target: u16,
+    },
+    Return,
+    Halt {
+        code: u16,
+    },
+    Invalid(Fault),
+}
+
+impl Instruction {
+    /// Decodes the words `line` of the instruction at `pc`, in a program of
+    /// `len` instructions.
+    pub fn decode(line: &[u16], pc: u16, len: usize) -> Instruction {
+        decode(line, pc, len).unwrap_or_else(Instruction::Invalid)
+    }
+}
+
+fn decode(line: &[u16], pc: u16, len: usize) -> Result<Instruction, Fault> {
+    let Some(&opcode) = line.first() else {
+        return Ok(Instruction::Nop);
+    };
+    let op = Operation::try_from_u16(opcode).ok_or(Fault::UnknownOperation(opcode))?;
+    let operand = |index: usize, name: &'static str| {
+        line.get(index)
+            .copied()
+            .ok_or(Fault::MissingOperand(op, name))
+    };
+    let address = |index: usize, name: &'static str| operand(index, name).map(usize::from);
+    let target = || {
+        let target = match op.is_relative() {
+            true => pc.wrapping_add(operand(1, "OFFSET")?),
+            false => operand(1, "ADDR")?,
+        };
+        match usize::from(target) <= len {
+            true => Ok(target),
+            false => Err(Fault::InvalidTarget(target)),
+        }
+    };
+    // MOV, IMM and arithmetic do nothing unless check_address allows every address
+    let used = |addresses: &[u16]| addresses.iter().all(|address| check_address(*address));
+
+    Ok(match op {
+        Operation::NOP | Operation::DEF => Instruction::Nop,
+        Operation::MOV => {
+            let src = operand(1, "SRC")?;
+            let dest = operand(2, "DEST")?;
+            match used(&[src, dest]) {
+                true => Instruction::Move {
+                    src: src.into(),
+                    dest: dest.into(),
+                },
+                false => Instruction::Nop,
+            }
+        }
+        Operation::ADD
+        | Operation::SUB
+        | Operation::MUL
+        | Operation::DIV
+        | Operation::MOD
+        | Operation::AND
+        | Operation::OR
+        | Operation::XOR
+        | Operation::SHL
+        | Operation::SHR => {
+            let src = operand(1, "SRC")?;
+            let dest = operand(2, "DEST")?;
+            match used(&[src, dest]) {
+                true => Instruction::Arithmetic {
+                    op,
+                    src: src.into(),
+                    dest: dest.into(),
+                },
+                false => Instruction::Nop,
+            }
+        }
+        Operation::INC | Operation::DEC | Operation::NOT => {
+            let dest = operand(1, "DEST")?;
+            match used(&[dest]) {
+                true => Instruction::Unary {
+                    op,
+                    dest: dest.into(),
+                },
+                false => Instruction::Nop,
+            }
+        }
+        Operation::JMP | Operation::JR => Instruction::Jump { target: target()? },
+        Operation::JG | Operation::JGR => Instruction::JumpGreater {
+            target: target()?,
+            arg1: address(2, "ARG1")?,
+            arg2: address(3, "ARG2")?,
+        },
+        Operation::JL | Operation::JLR => Instruction::JumpLess {
+            target: target()?,
+            arg1: address(2, "ARG1")?,
+            arg2: address(3, "ARG2")?,
+        },
+        Operation::JZ | Operation::JZR => Instruction::JumpZero {
+            target: target()?,
+            arg: address(2, "ARG1")?,
+        },
+        Operation::JNZ | Operation::JNZR => Instruction::JumpNotZero {
+            target: target()?,
+            arg: address(2, "ARG1")?,
+        },
+        Operation::CMP => Instruction::Compare {
+            arg1: address(1, "ARG1")?,
+            arg2: address(2, "ARG2")?,
+        },
+        Operation::PUSH => Instruction::Push {
+            src: address(1, "SRC")?,
+        },
+        Operation::POP => Instruction::Pop {
+            dest: address(1, "DEST")?,
+        },
+        Operation::IMM => {
+            let value = operand(1, "IMM")?;
+            let dest = operand(2, "DEST")?;
+            match used(&[dest]) {
+                true => Instruction::Immediate {
+                    value,
+                    dest: dest.into(),
+                },
+                false => Instruction::Nop,
+            }
+        }
+        Operation::CALL | Operation::CALLR => Instruction::Call { target: target()? },
+        Operation::RET => Instruction::Return,
+        Operation::HLT => Instruction::Halt {
+            code: operand(1, "EXIT_CODE")?,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Instruction;
+    use crate::interpreter::{Fault, Machine, State};
+    use crate::operation::Operation;
+
+    #[test]
+    fn relative_targets_are_absolute() {
+        let jump = [Operation::JR.opcode(), 3];
+        assert_eq!(
+            Instruction::decode(&jump, 4, 8),
+            Instruction::Jump { target: 7 }
+        );
+        let back = [Operation::JNZR.opcode(), 0xFFFF, 2];
+        assert_eq!(
+            Instruction::decode(&back, 4, 8),
+            Instruction::JumpNotZero { target: 3, arg: 2 }
+        );
+    }
+
+    #[test]
+    fn ignored_addresses_are_nops() {
+        let mov = [Operation::MOV.opcode(), 0, 1];
+        assert_eq!(Instruction::decode(&mov, 0, 8), Instruction::Nop);
+        let imm = [Operation::IMM.opcode(), 5, 0x020];
+        assert_eq!(Instruction::decode(&imm, 0, 8), Instruction::Nop);
+        assert_eq!(Instruction::decode(&[], 0, 8), Instruction::Nop);
+    }
+
+    #[test]
+    fn faults_happen_when_executed() {
+        let missing = Fault::MissingOperand(Operation::ADD, "DEST");
+        let add = vec![Operation::ADD.opcode(), 1];
+        assert_eq!(
+            Instruction::decode(&add, 0, 8),
+            Instruction::Invalid(missing)
+        );
+
+        let skip = vec![Operation::JMP.opcode(), 2];
+        let mut machine = Machine::new(vec![skip, add.clone()]);
+        assert_eq!(machine.run(), State::Finished);
+        let mut machine = Machine::new(vec![vec![Operation::NOP.opcode()], add]);
+        assert_eq!(machine.run(), State::Faulted(missing));
+        assert_eq!(machine.pc(), 1);
+    }
+
+    #[test]
+    fn targets_stay_in_the_program() {
+        let end = [Operation::JMP.opcode(), 2];
+        assert_eq!(
+            Instruction::decode(&end, 0, 2),
+            Instruction::Jump { target: 2 }
+        );
+        let past = [Operation::CALL.opcode(), 3];
+        let fault = Fault::InvalidTarget(3);
+        assert_eq!(
+            Instruction::decode(&past, 0, 2),
+            Instruction::Invalid(fault)
+        );
+        let back = [Operation::JZR.opcode(), 0xFFFE, 1];
+        let fault = Fault::InvalidTarget(0xFFFF);
+        assert_eq!(
+            Instruction::decode(&back, 1, 2),
+            Instruction::Invalid(fault)
+        );
+
+        let mut machine = Machine::new(vec![past.to_vec(), vec![Operation::NOP.opcode()]]);
+        assert_eq!(machine.run(), State::Faulted(Fault::InvalidTarget(3)));
+        assert_eq!(machine.pc(), 0);
+    }
+}
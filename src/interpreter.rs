@@ -1,4 +1,9 @@
+use crate::coredump::{CoreDump, describe_opcode};
+use crate::instruction::Instruction;
 use crate::operation::Operation;
+use crate::sourcemap::SourceMap;
+use std::collections::VecDeque;
+use std::fmt;
 
 /*
 0x000 -> 0x00F arithematic registers
@@ -17,440 +22,798 @@ RESERVED REGISTERS
 0x01E -> program_counter - used to get the current instruction
 */
 
-const RETURN_REGISTER_ADDRESS: usize = 0x010;
-const STACK_POINTER_ADDRESS: usize = 0x01A;
-const STACK_BASE_ADDRESS: usize = 0x01B;
-const CARRY_REGISTER_ADDRESS: usize = 0x01C; // CARRY REGISTER
-const PROGRAM_COUNTER_ADDRESS: usize = 0x01E; // PROGRAM COUNTER
+pub const RETURN_REGISTER_ADDRESS: usize = 0x010;
+pub const STACK_POINTER_ADDRESS: usize = 0x01A;
+pub const STACK_BASE_ADDRESS: usize = 0x01B;
+pub const CARRY_REGISTER_ADDRESS: usize = 0x01C; // CARRY REGISTER
+pub const PROGRAM_COUNTER_ADDRESS: usize = 0x01E; // PROGRAM COUNTER
+pub const RESERVED_REGISTERS_END: usize = 0x01F;
 
-const STACK_BASE: usize = 0xFE0; // STACK_BASE (Loaded into memory on startup)
+/// Registers instructions may not write to directly.
+pub const READ_ONLY_REGISTERS: [usize; 2] = [STACK_BASE_ADDRESS, PROGRAM_COUNTER_ADDRESS];
 
-pub fn run_raw(instructions: Vec<Vec<u16>>) {
-    let mut memory: [u16; 4096] = [0; 4096];
-    memory[STACK_BASE_ADDRESS] = STACK_BASE as u16;
+pub const STACK_BASE: usize = 0xFE0; // STACK_BASE (Loaded into memory on startup)
+pub const STACK_END: usize = 0xFEF;
 
-    loop {
-        if let Some(line) = instructions.get(memory[PROGRAM_COUNTER_ADDRESS] as usize) {
-            if line.is_empty() {
-                continue;
+pub const PROGRAM_MEMORY_START: usize = 0x040;
+pub const PROGRAM_MEMORY_END: usize = 0xFDF;
+
+pub const MEMORY_SIZE: usize = 4096;
+
+/// Names of the arithmetic and reserved registers, by address. Reserved cells
+/// without a purpose are left unnamed.
+///
+/// R0 is named like the others, but MOV, IMM and arithmetic ignore it (see
+/// check_address) and the assembler rejects it as their operand.
+pub fn register_name(address: usize) -> Option<String> {
+    match address {
+        0x000..=0x00F => Some(format!("R{address}")),
+        RETURN_REGISTER_ADDRESS => Some("RET".to_string()),
+        STACK_POINTER_ADDRESS => Some("SP".to_string()),
+        STACK_BASE_ADDRESS => Some("BP".to_string()),
+        CARRY_REGISTER_ADDRESS => Some("FLAGS".to_string()),
+        PROGRAM_COUNTER_ADDRESS => Some("PC".to_string()),
+        _ => None,
+    }
+}
+
+/// Address of a named register, ignoring case.
+pub fn register_address(name: &str) -> Option<usize> {
+    (0x000..0x020).find(|address| {
+        register_name(*address).is_some_and(|register| register.eq_ignore_ascii_case(name))
+    })
+}
+
+/// Result of executing a single instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// The machine can keep stepping.
+    Running,
+    /// HLT was executed with the given exit code.
+    Halted(u16),
+    /// The program counter ran past the last instruction.
+    Finished,
+    /// A watchpoint with the Pause action was hit by the last instruction.
+    Paused,
+    /// The last instruction could not be executed.
+    Faulted(Fault),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    UnknownOperation(u16),
+    MissingOperand(Operation, &'static str),
+    InvalidAddress(usize),
+    InvalidTarget(u16),
+    DivideByZero,
+    StackUnderflow,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::UnknownOperation(num) => write!(f, "Unknown Operation. {num:#X}"),
+            Fault::MissingOperand(op, name) => write!(f, "No {name} for {op:?}"),
+            Fault::InvalidAddress(address) => write!(f, "Invalid address {address:#X}"),
+            Fault::InvalidTarget(target) => {
+                write!(f, "Jump target {target:#X} is outside the program")
             }
-            let op = Operation::from_u16(*line.first().unwrap());
-            match op {
-                Operation::NOP => {
-                    memory[PROGRAM_COUNTER_ADDRESS] += 1;
-                    continue;
-                }
-                Operation::DEF => {
-                    memory[PROGRAM_COUNTER_ADDRESS] += 1;
-                    continue;
-                }
-                Operation::MOV => {
-                    let src = *line.get(1).unwrap_or_else(|| {
-                        panic!("No SRC for MOV on line {}", memory[PROGRAM_COUNTER_ADDRESS])
-                    });
-                    let dest = *line.get(2).unwrap_or_else(|| {
-                        panic!(
-                            "No DEST for MOV on line {}",
-                            memory[PROGRAM_COUNTER_ADDRESS]
-                        )
-                    });
-
-                    //Double check that a valid address was given
-                    if check_address(src) && check_address(dest) {
-                        memory[dest as usize] = memory[src as usize];
-                    }
-                }
-                Operation::ADD => {
-                    let src = *line.get(1).unwrap_or_else(|| {
-                        panic!("No SRC for ADD on line {}", memory[PROGRAM_COUNTER_ADDRESS])
-                    });
-                    let dest = *line.get(2).unwrap_or_else(|| {
-                        panic!(
-                            "No DEST for ADD on line {}",
-                            memory[PROGRAM_COUNTER_ADDRESS]
-                        )
-                    });
-
-                    //Double check that a valid address was given
-                    if check_address(src) && check_address(dest) {
-                        memory[dest as usize] += memory[src as usize];
-                    }
-                }
-                Operation::SUB => {
-                    let src = *line.get(1).unwrap_or_else(|| {
-                        panic!("No SRC for SUB on line {}", memory[PROGRAM_COUNTER_ADDRESS])
-                    });
-                    let dest = *line.get(2).unwrap_or_else(|| {
-                        panic!(
-                            "No DEST for SUB on line {}",
-                            memory[PROGRAM_COUNTER_ADDRESS]
-                        )
-                    });
-
-                    //Double check that a valid address was given
-                    if check_address(src) && check_address(dest) {
-                        memory[dest as usize] -= memory[src as usize];
-                    }
-                }
-                Operation::INC => {
-                    let dest = *line.get(1).unwrap_or_else(|| {
-                        panic!(
-                            "No DEST for INC on line {}",
-                            memory[PROGRAM_COUNTER_ADDRESS]
-                        )
-                    });
-
-                    if check_address(dest) {
-                        memory[dest as usize] += 1;
-                    }
-                }
-                Operation::DEC => {
-                    let dest = *line.get(1).unwrap_or_else(|| {
-                        panic!(
-                            "No DEST for DEC on line {}",
-                            memory[PROGRAM_COUNTER_ADDRESS]
-                        )
-                    });
-
-                    if check_address(dest) {
-                        memory[dest as usize] -= 1;
-                    }
-                }
-                Operation::MUL => {
-                    let src = *line.get(1).unwrap_or_else(|| {
-                        panic!("No SRC for MUL on line {}", memory[PROGRAM_COUNTER_ADDRESS])
-                    });
-                    let dest = *line.get(2).unwrap_or_else(|| {
-                        panic!(
-                            "No DEST for MUL on line {}",
-                            memory[PROGRAM_COUNTER_ADDRESS]
-                        )
-                    });
-
-                    //Double check that a valid address was given
-                    if check_address(src) && check_address(dest) {
-                        memory[dest as usize] *= memory[src as usize];
-                    }
-                }
-                Operation::DIV => {
-                    let src = *line.get(1).unwrap_or_else(|| {
-                        panic!("No SRC for DIV on line {}", memory[PROGRAM_COUNTER_ADDRESS])
-                    });
-                    let dest = *line.get(2).unwrap_or_else(|| {
-                        panic!(
-                            "No DEST for DIV on line {}",
-                            memory[PROGRAM_COUNTER_ADDRESS]
-                        )
-                    });
-
-                    //Double check that a valid address was given
-                    if check_address(src) && check_address(dest) {
-                        memory[dest as usize] /= memory[src as usize];
-                    }
-                }
-                Operation::MOD => {
-                    let src = *line.get(1).unwrap_or_else(|| {
-                        panic!("No SRC for MOD on line {}", memory[PROGRAM_COUNTER_ADDRESS])
-                    });
-                    let dest = *line.get(2).unwrap_or_else(|| {
-                        panic!(
-                            "No DEST for MOD on line {}",
-                            memory[PROGRAM_COUNTER_ADDRESS]
-                        )
-                    });
-
-                    //Double check that a valid address was given
-                    if check_address(src) && check_address(dest) {
-                        memory[dest as usize] %= memory[src as usize];
-                    }
-                }
-                Operation::AND => {
-                    let src = *line.get(1).unwrap_or_else(|| {
-                        panic!("No SRC for AND on line {}", memory[PROGRAM_COUNTER_ADDRESS])
-                    });
-                    let dest = *line.get(2).unwrap_or_else(|| {
-                        panic!(
-                            "No DEST for AND on line {}",
-                            memory[PROGRAM_COUNTER_ADDRESS]
-                        )
-                    });
-
-                    //Double check that a valid address was given
-                    if check_address(src) && check_address(dest) {
-                        memory[dest as usize] &= memory[src as usize];
-                    }
-                }
-                Operation::OR => {
-                    let src = *line.get(1).unwrap_or_else(|| {
-                        panic!("No SRC for OR on line {}", memory[PROGRAM_COUNTER_ADDRESS])
-                    });
-                    let dest = *line.get(2).unwrap_or_else(|| {
-                        panic!("No DEST for OR on line {}", memory[PROGRAM_COUNTER_ADDRESS])
-                    });
-
-                    //Double check that a valid address was given
-                    if check_address(src) && check_address(dest) {
-                        memory[dest as usize] |= memory[src as usize];
-                    }
-                }
-                Operation::XOR => {
-                    let src = *line.get(1).unwrap_or_else(|| {
-                        panic!("No SRC for XOR on line {}", memory[PROGRAM_COUNTER_ADDRESS])
-                    });
-                    let dest = *line.get(2).unwrap_or_else(|| {
-                        panic!(
-                            "No DEST for XOR on line {}",
-                            memory[PROGRAM_COUNTER_ADDRESS]
-                        )
-                    });
-
-                    //Double check that a valid address was given
-                    if check_address(src) && check_address(dest) {
-                        memory[dest as usize] ^= memory[src as usize];
-                    }
-                }
-                Operation::NOT => {
-                    let dest = *line.get(1).unwrap_or_else(|| {
-                        panic!(
-                            "No DEST for INC on line {}",
-                            memory[PROGRAM_COUNTER_ADDRESS]
-                        )
-                    });
-
-                    if check_address(dest) {
-                        memory[dest as usize] = !memory[dest as usize];
-                    }
-                }
-                Operation::SHL => {
-                    let src = *line.get(1).unwrap_or_else(|| {
-                        panic!("No SRC for SHL on line {}", memory[PROGRAM_COUNTER_ADDRESS])
-                    });
-                    let dest = *line.get(2).unwrap_or_else(|| {
-                        panic!(
-                            "No DEST for SHL on line {}",
-                            memory[PROGRAM_COUNTER_ADDRESS]
-                        )
-                    });
-
-                    //Double check that a valid address was given
-                    if check_address(src) && check_address(dest) {
-                        memory[dest as usize] <<= memory[src as usize];
-                    }
-                }
-                Operation::SHR => {
-                    let src = *line.get(1).unwrap_or_else(|| {
-                        panic!("No SRC for SHR on line {}", memory[PROGRAM_COUNTER_ADDRESS])
-                    });
-                    let dest = *line.get(2).unwrap_or_else(|| {
-                        panic!(
-                            "No DEST for SHR on line {}",
-                            memory[PROGRAM_COUNTER_ADDRESS]
-                        )
-                    });
-
-                    //Double check that a valid address was given
-                    if check_address(src) && check_address(dest) {
-                        memory[dest as usize] >>= memory[src as usize];
-                    }
-                }
-                Operation::JMP => {
-                    let address = *line.get(1).unwrap_or_else(|| {
-                        panic!(
-                            "No ADDR for JMP on line {}",
-                            memory[PROGRAM_COUNTER_ADDRESS]
-                        )
-                    });
-                    memory[PROGRAM_COUNTER_ADDRESS] = address;
-                    continue;
-                }
-                Operation::JG => {
-                    let address = *line.get(1).unwrap_or_else(|| {
-                        core_dump(&instructions, &memory);
-                        panic!("No ADDR for JG on line {}", memory[PROGRAM_COUNTER_ADDRESS])
-                    });
-                    let arg1 = *line.get(2).unwrap_or_else(|| {
-                        core_dump(&instructions, &memory);
-                        panic!("No ARG1 for JG on line {}", memory[PROGRAM_COUNTER_ADDRESS]);
-                    });
-                    let arg2 = *line.get(3).unwrap_or_else(|| {
-                        core_dump(&instructions, &memory);
-                        panic!("No ARG2 for JG on line {}", memory[PROGRAM_COUNTER_ADDRESS]);
-                    });
-                    if memory[arg1 as usize] > memory[arg2 as usize] {
-                        memory[PROGRAM_COUNTER_ADDRESS] = address;
-                        continue;
-                    }
-                }
-                Operation::JL => {
-                    let address = *line.get(1).unwrap_or_else(|| {
-                        core_dump(&instructions, &memory);
-                        panic!("No ADDR for JL on line {}", memory[PROGRAM_COUNTER_ADDRESS])
-                    });
-                    let arg1 = *line.get(2).unwrap_or_else(|| {
-                        core_dump(&instructions, &memory);
-                        panic!("No ARG1 for JL on line {}", memory[PROGRAM_COUNTER_ADDRESS]);
-                    });
-                    let arg2 = *line.get(3).unwrap_or_else(|| {
-                        core_dump(&instructions, &memory);
-                        panic!("No ARG2 for JL on line {}", memory[PROGRAM_COUNTER_ADDRESS]);
-                    });
-                    if memory[arg1 as usize] < memory[arg2 as usize] {
-                        memory[PROGRAM_COUNTER_ADDRESS] = address;
-                        continue;
-                    }
-                }
-                Operation::JZ => {
-                    let address = *line.get(1).unwrap_or_else(|| {
-                        core_dump(&instructions, &memory);
-                        panic!("No ADDR for JZ on line {}", memory[PROGRAM_COUNTER_ADDRESS])
-                    });
-                    let arg1 = *line.get(2).unwrap_or_else(|| {
-                        core_dump(&instructions, &memory);
-                        panic!("No ARG1 for JZ on line {}", memory[PROGRAM_COUNTER_ADDRESS]);
-                    });
-                    if memory[arg1 as usize] == 0 {
-                        memory[PROGRAM_COUNTER_ADDRESS] = address;
-                        continue;
-                    }
-                }
-                Operation::JNZ => {
-                    let address = *line.get(1).unwrap_or_else(|| {
-                        core_dump(&instructions, &memory);
-                        panic!("No ADDR for JZ on line {}", memory[PROGRAM_COUNTER_ADDRESS])
-                    });
-                    let arg1 = *line.get(2).unwrap_or_else(|| {
-                        core_dump(&instructions, &memory);
-                        panic!("No ARG1 for JZ on line {}", memory[PROGRAM_COUNTER_ADDRESS]);
-                    });
-                    if memory[arg1 as usize] != 0 {
-                        memory[PROGRAM_COUNTER_ADDRESS] = address;
-                        continue;
-                    }
-                }
-                Operation::CMP => {
-                    let arg1 = *line.get(2).unwrap_or_else(|| {
-                        core_dump(&instructions, &memory);
-                        panic!("No ARG1 for JL on line {}", memory[PROGRAM_COUNTER_ADDRESS]);
-                    });
-                    let arg2 = *line.get(3).unwrap_or_else(|| {
-                        core_dump(&instructions, &memory);
-                        panic!("No ARG2 for JL on line {}", memory[PROGRAM_COUNTER_ADDRESS]);
-                    });
-                    if memory[arg1 as usize] == memory[arg2 as usize] {
-                        memory[CARRY_REGISTER_ADDRESS] = 0x001;
-                    }
-                }
-                Operation::PUSH => {
-                    let src = *line.get(1).unwrap_or_else(|| {
-                        panic!(
-                            "No SRC for PUSH on line {}",
-                            memory[PROGRAM_COUNTER_ADDRESS]
-                        )
-                    });
-
-                    memory[STACK_POINTER_ADDRESS] += 1;
-                    memory[memory[STACK_BASE_ADDRESS] as usize + (memory[STACK_POINTER_ADDRESS] as usize)] =
-                        memory[src as usize];
-                }
-                Operation::POP => {
-                    let dest = *line.get(1).unwrap_or_else(|| {
-                        core_dump(&instructions, &memory);
-                        panic!(
-                            "No DEST for POP on line {}",
-                            memory[PROGRAM_COUNTER_ADDRESS]
-                        )
-                    });
-
-                    memory[dest as usize] =
-                        memory[memory[STACK_BASE_ADDRESS] as usize + (memory[STACK_POINTER_ADDRESS] as usize)];
-                    memory[STACK_POINTER_ADDRESS] -= 1;
+            Fault::DivideByZero => write!(f, "Division by zero"),
+            Fault::StackUnderflow => write!(f, "Error returning, stack is empty"),
+        }
+    }
+}
+
+/// A subroutine entered with CALL. `return_slot` is the stack cell holding
+/// the address CALL will return to.
+#[derive(Clone, Copy, Debug)]
+pub struct Frame {
+    pub call_pc: u16,
+    pub target: u16,
+    pub return_slot: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    /// A write that stores a different value than the cell held.
+    Change,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchAction {
+    Pause,
+    Log,
+}
+
+/// Watches the inclusive address range `start..=end`.
+#[derive(Clone, Copy, Debug)]
+pub struct Watchpoint {
+    pub start: usize,
+    pub end: usize,
+    pub access: Access,
+    pub action: WatchAction,
+}
+
+/// A single access that matched a watchpoint. For reads `old` and `new` are
+/// both the value that was read.
+#[derive(Clone, Copy, Debug)]
+pub struct WatchHit {
+    pub id: usize,
+    pub cycle: u64,
+    pub pc: u16,
+    pub address: usize,
+    pub access: Access,
+    pub action: WatchAction,
+    pub old: u16,
+    pub new: u16,
+}
+
+/// Undo information for one executed instruction: the cycle it ran on and the
+/// previous value of every memory cell it wrote, in write order. The program
+/// counter lives in memory, so PC changes are recorded like any other write.
+struct Record {
+    cycle: u64,
+    writes: Vec<(usize, u16)>,
+    call: Option<CallChange>,
+}
+
+enum CallChange {
+    Entered,
+    Returned(Frame),
+}
+
+pub struct Machine {
+    /// The words of each instruction, as loaded.
+    pub instructions: Vec<Vec<u16>>,
+    pub memory: [u16; MEMORY_SIZE],
+    pub cycle: u64,
+    pub source_map: SourceMap,
+    /// Prints every instruction to stderr before executing it.
+    pub trace: bool,
+    /// `instructions` decoded, see instruction.rs.
+    program: Vec<Instruction>,
+    halted: Option<u16>,
+    fault: Option<Fault>,
+    frames: Vec<Frame>,
+    history: VecDeque<Record>,
+    history_size: usize,
+    current: Vec<(usize, u16)>,
+    call: Option<CallChange>,
+    watchpoints: Vec<(usize, Watchpoint)>,
+    next_watchpoint: usize,
+    watch_log: Vec<WatchHit>,
+    paused: bool,
+    executing: u16,
+}
+
+impl Machine {
+    pub fn new(instructions: Vec<Vec<u16>>) -> Machine {
+        Machine::with_history(instructions, 0)
+    }
+
+    /// Creates a machine that keeps undo records for the last `history_size`
+    /// executed instructions. A size of zero disables recording.
+    pub fn with_history(instructions: Vec<Vec<u16>>, history_size: usize) -> Machine {
+        let mut memory = [0; MEMORY_SIZE];
+        memory[STACK_BASE_ADDRESS] = STACK_BASE as u16;
+
+        let program = instructions
+            .iter()
+            .enumerate()
+            .map(|(pc, line)| Instruction::decode(line, pc as u16, instructions.len()))
+            .collect();
+        Machine {
+            instructions,
+            program,
+            memory,
+            cycle: 0,
+            source_map: SourceMap::default(),
+            trace: false,
+            halted: None,
+            fault: None,
+            frames: Vec::new(),
+            history: VecDeque::new(),
+            history_size,
+            current: Vec::new(),
+            call: None,
+            watchpoints: Vec::new(),
+            next_watchpoint: 0,
+            watch_log: Vec::new(),
+            paused: false,
+            executing: 0,
+        }
+    }
+
+    /// Copies `words` into memory starting at `address`, before running.
+    pub fn load(&mut self, address: usize, words: &[u16]) {
+        self.memory[address..address + words.len()].copy_from_slice(words);
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.memory[PROGRAM_COUNTER_ADDRESS]
+    }
+
+    pub fn fault(&self) -> Option<Fault> {
+        self.fault
+    }
+
+    /// Subroutines currently entered, outermost first.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// The instruction at `pc` with its source location, like
+    /// `0x2 program.x1:12:1 (FUNC1+1): JL [1, 2, 3]`.
+    pub fn describe(&self, pc: u16) -> String {
+        let instruction = match self.instructions.get(pc as usize) {
+            Some(line) if !line.is_empty() => {
+                format!("{} {:?}", describe_opcode(line[0]), &line[1..])
+            }
+            _ => "<end>".to_string(),
+        };
+        match self.source_map.get(pc) {
+            Some(_) => format!("0x{pc:X} {}: {instruction}", self.source_map.describe(pc)),
+            None => format!("0x{pc:X}: {instruction}"),
+        }
+    }
+
+    /// Snapshot of the machine for writing a core dump.
+    pub fn dump(&self) -> CoreDump {
+        CoreDump::new(self)
+    }
+
+    /// Addresses written by the most recently executed instruction.
+    pub fn last_writes(&self) -> Vec<usize> {
+        self.current.iter().map(|(address, _)| *address).collect()
+    }
+
+    /// Cycle of the oldest instruction that can still be undone.
+    pub fn oldest_cycle(&self) -> u64 {
+        self.history
+            .front()
+            .map_or(self.cycle, |record| record.cycle)
+    }
+
+    /// Runs until HLT or until the program counter leaves the program.
+    pub fn run(&mut self) -> State {
+        loop {
+            match self.step() {
+                State::Running => continue,
+                state => return state,
+            }
+        }
+    }
+
+    pub fn step(&mut self) -> State {
+        if let Some(code) = self.halted {
+            return State::Halted(code);
+        }
+        if let Some(fault) = self.fault {
+            return State::Faulted(fault);
+        }
+        let instruction = match self.program.get(self.pc() as usize) {
+            Some(instruction) => *instruction,
+            None => return State::Finished,
+        };
+
+        self.executing = self.pc();
+        self.current.clear();
+        if self.trace {
+            eprintln!("[cycle {}] {}", self.cycle, self.describe(self.executing));
+        }
+        let mut state = self.execute(instruction).unwrap_or_else(|fault| {
+            self.fault = Some(fault);
+            State::Faulted(fault)
+        });
+        if std::mem::take(&mut self.paused) && state == State::Running {
+            state = State::Paused;
+        }
+
+        let call = self.call.take();
+        if self.history_size > 0 {
+            if self.history.len() == self.history_size {
+                self.history.pop_front();
+            }
+            self.history.push_back(Record {
+                cycle: self.cycle,
+                writes: self.current.clone(),
+                call,
+            });
+        }
+        self.cycle += 1;
+        state
+    }
+
+    /// Undoes the most recently executed instruction. Returns false when there
+    /// is no history left to undo.
+    pub fn step_back(&mut self) -> bool {
+        match self.history.pop_back() {
+            Some(record) => {
+                for (address, old) in record.writes.into_iter().rev() {
+                    self.memory[address] = old;
                 }
-                Operation::IMM => {
-                    let immediate = *line.get(1).unwrap_or_else(|| {
-                        core_dump(&instructions, &memory);
-                        panic!("No IMM value on line {}", memory[PROGRAM_COUNTER_ADDRESS])
-                    });
-                    let dest = *line.get(2).unwrap_or_else(|| {
-                        core_dump(&instructions, &memory);
-                        panic!(
-                            "No destination for IMM on line {}",
-                            memory[PROGRAM_COUNTER_ADDRESS]
-                        )
-                    });
-                    if check_address(dest) {
-                        memory[dest as usize] = immediate;
+                match record.call {
+                    Some(CallChange::Entered) => {
+                        self.frames.pop();
                     }
+                    Some(CallChange::Returned(frame)) => self.frames.push(frame),
+                    None => {}
                 }
-                Operation::CALL => {
-                    let address = *line.get(1).unwrap_or_else(|| {
-                        core_dump(&instructions, &memory);
-                        panic!(
-                            "No ADDR for JMP on line {}",
-                            memory[PROGRAM_COUNTER_ADDRESS]
-                        );
-                    });
-                    memory[memory[STACK_BASE_ADDRESS] as usize + (memory[STACK_POINTER_ADDRESS] as usize)] =
-                        memory[PROGRAM_COUNTER_ADDRESS];
-                    memory[STACK_POINTER_ADDRESS] += 1;
-                    memory[PROGRAM_COUNTER_ADDRESS] = address;
-                    continue;
-                }
-                // ATTEMPT TO SUBTRACT WITH OVERFLOW?
-                Operation::RET => {
-                    memory[STACK_POINTER_ADDRESS] = memory[STACK_POINTER_ADDRESS]
-                        .checked_sub(1)
-                        .unwrap_or_else(|| {
-                            core_dump(&instructions, &memory);
-                            panic!("Error returning");
-                        });
-                    memory[PROGRAM_COUNTER_ADDRESS] =
-                        memory[memory[STACK_BASE_ADDRESS] as usize + (memory[STACK_POINTER_ADDRESS] as usize)];
+                self.current.clear();
+                self.cycle = record.cycle;
+                self.halted = None;
+                self.fault = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Steps backward until the instruction that last wrote `address` has been
+    /// undone. Returns the cycle of that write, or None if no recorded
+    /// instruction wrote it (the machine is then at the oldest recorded cycle).
+    pub fn run_back_to_write(&mut self, address: usize) -> Option<u64> {
+        loop {
+            let wrote = self
+                .history
+                .back()?
+                .writes
+                .iter()
+                .any(|(written, _)| *written == address);
+            self.step_back();
+            if wrote {
+                return Some(self.cycle);
+            }
+        }
+    }
+
+    /// Moves the machine to the state it had before `cycle` executed. Earlier
+    /// cycles are reached by undoing, later ones by running forward. Returns
+    /// false if `cycle` is older than the recorded history.
+    pub fn rewind_to(&mut self, cycle: u64) -> bool {
+        if cycle < self.oldest_cycle() {
+            return false;
+        }
+        while self.cycle > cycle {
+            self.step_back();
+        }
+        while self.cycle < cycle {
+            if self.step() != State::Running {
+                break;
+            }
+        }
+        self.cycle == cycle
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        let id = self.next_watchpoint;
+        self.next_watchpoint += 1;
+        self.watchpoints.push((id, watchpoint));
+        id
+    }
+
+    pub fn remove_watchpoint(&mut self, id: usize) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|(watch_id, _)| *watch_id != id);
+        self.watchpoints.len() != count
+    }
+
+    pub fn watchpoints(&self) -> &[(usize, Watchpoint)] {
+        &self.watchpoints
+    }
+
+    /// Returns every watchpoint hit since the last call, oldest first.
+    pub fn take_watch_log(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.watch_log)
+    }
+
+    fn watch(&mut self, address: usize, access: Access, old: u16, new: u16) {
+        for (id, watchpoint) in self.watchpoints.iter() {
+            let matches = match watchpoint.access {
+                Access::Read => access == Access::Read,
+                Access::Write => access == Access::Write,
+                Access::Change => access == Access::Write && old != new,
+            };
+            if matches && (watchpoint.start..=watchpoint.end).contains(&address) {
+                self.paused |= watchpoint.action == WatchAction::Pause;
+                self.watch_log.push(WatchHit {
+                    id: *id,
+                    cycle: self.cycle,
+                    pc: self.executing,
+                    address,
+                    access: watchpoint.access,
+                    action: watchpoint.action,
+                    old,
+                    new,
+                });
+            }
+        }
+    }
+
+    fn read(&mut self, address: usize) -> Result<u16, Fault> {
+        let value = *self
+            .memory
+            .get(address)
+            .ok_or(Fault::InvalidAddress(address))?;
+        if !self.watchpoints.is_empty() {
+            self.watch(address, Access::Read, value, value);
+        }
+        Ok(value)
+    }
+
+    fn write(&mut self, address: usize, value: u16) -> Result<(), Fault> {
+        let old = *self
+            .memory
+            .get(address)
+            .ok_or(Fault::InvalidAddress(address))?;
+        self.current.push((address, old));
+        if !self.watchpoints.is_empty() {
+            self.watch(address, Access::Write, old, value);
+        }
+        self.memory[address] = value;
+        Ok(())
+    }
+
+    /// Words wrap around on overflow, shifting by 16 or more gives 0.
+    fn arithmetic(&mut self, op: Operation, src: usize, dest: usize) -> Result<(), Fault> {
+        let source = self.read(src)?;
+        if source == 0 && matches!(op, Operation::DIV | Operation::MOD) {
+            return Err(Fault::DivideByZero);
+        }
+        let destination = self.read(dest)?;
+        let value = match op {
+            Operation::ADD => destination.wrapping_add(source),
+            Operation::SUB => destination.wrapping_sub(source),
+            Operation::MUL => destination.wrapping_mul(source),
+            Operation::DIV => destination / source,
+            Operation::MOD => destination % source,
+            Operation::AND => destination & source,
+            Operation::OR => destination | source,
+            Operation::XOR => destination ^ source,
+            Operation::SHL => destination.checked_shl(source.into()).unwrap_or(0),
+            Operation::SHR => destination.checked_shr(source.into()).unwrap_or(0),
+            _ => unreachable!("{op:?} is not arithmetic"),
+        };
+        self.write(dest, value)
+    }
+
+    fn unary(&mut self, op: Operation, dest: usize) -> Result<(), Fault> {
+        let value = match op {
+            Operation::INC => self.read(dest)?.wrapping_add(1),
+            Operation::DEC => self.read(dest)?.wrapping_sub(1),
+            Operation::NOT => !self.read(dest)?,
+            _ => unreachable!("{op:?} is not unary"),
+        };
+        self.write(dest, value)
+    }
+
+    fn stack_top(&self) -> usize {
+        self.memory[STACK_BASE_ADDRESS] as usize + self.memory[STACK_POINTER_ADDRESS] as usize
+    }
+
+    fn execute(&mut self, instruction: Instruction) -> Result<State, Fault> {
+        let pc = self.pc();
+        let jump = match instruction {
+            Instruction::Nop => None,
+            Instruction::Move { src, dest } => {
+                let value = self.read(src)?;
+                self.write(dest, value)?;
+                None
+            }
+            Instruction::Immediate { value, dest } => {
+                self.write(dest, value)?;
+                None
+            }
+            Instruction::Arithmetic { op, src, dest } => {
+                self.arithmetic(op, src, dest)?;
+                None
+            }
+            Instruction::Unary { op, dest } => {
+                self.unary(op, dest)?;
+                None
+            }
+            Instruction::Jump { target } => Some(target),
+            Instruction::JumpGreater { target, arg1, arg2 } => {
+                (self.read(arg1)? > self.read(arg2)?).then_some(target)
+            }
+            Instruction::JumpLess { target, arg1, arg2 } => {
+                (self.read(arg1)? < self.read(arg2)?).then_some(target)
+            }
+            Instruction::JumpZero { target, arg } => (self.read(arg)? == 0).then_some(target),
+            Instruction::JumpNotZero { target, arg } => (self.read(arg)? != 0).then_some(target),
+            Instruction::Compare { arg1, arg2 } => {
+                if self.read(arg1)? == self.read(arg2)? {
+                    self.write(CARRY_REGISTER_ADDRESS, 0x001)?;
                 }
-                Operation::HLT => {
-                    let exit_code = *line.get(1).unwrap_or_else(|| {
-                        core_dump(&instructions, &memory);
-                        panic!(
-                            "No EXIT_CODE for JMP on line {}",
-                            memory[PROGRAM_COUNTER_ADDRESS]
-                        );
-                    });
-                    memory[RETURN_REGISTER_ADDRESS] = exit_code;
-                    break;
+                None
+            }
+            Instruction::Push { src } => {
+                self.write(
+                    STACK_POINTER_ADDRESS,
+                    self.memory[STACK_POINTER_ADDRESS].wrapping_add(1),
+                )?;
+                let value = self.read(src)?;
+                self.write(self.stack_top(), value)?;
+                None
+            }
+            Instruction::Pop { dest } => {
+                let value = self.read(self.stack_top())?;
+                self.write(dest, value)?;
+                self.write(
+                    STACK_POINTER_ADDRESS,
+                    self.memory[STACK_POINTER_ADDRESS].wrapping_sub(1),
+                )?;
+                None
+            }
+            Instruction::Call { target } => {
+                let return_slot = self.stack_top();
+                self.write(return_slot, pc)?;
+                self.write(
+                    STACK_POINTER_ADDRESS,
+                    self.memory[STACK_POINTER_ADDRESS].wrapping_add(1),
+                )?;
+                self.frames.push(Frame {
+                    call_pc: pc,
+                    target,
+                    return_slot,
+                });
+                self.call = Some(CallChange::Entered);
+                Some(target)
+            }
+            Instruction::Return => {
+                let stack_pointer = self.memory[STACK_POINTER_ADDRESS]
+                    .checked_sub(1)
+                    .ok_or(Fault::StackUnderflow)?;
+                self.write(STACK_POINTER_ADDRESS, stack_pointer)?;
+                let address = self.read(self.stack_top())?;
+                self.write(PROGRAM_COUNTER_ADDRESS, address)?;
+                if let Some(frame) = self.frames.pop() {
+                    self.call = Some(CallChange::Returned(frame));
                 }
+                None
             }
-        } else {
-            break;
+            Instruction::Halt { code } => {
+                self.write(RETURN_REGISTER_ADDRESS, code)?;
+                self.halted = Some(code);
+                return Ok(State::Halted(code));
+            }
+            Instruction::Invalid(fault) => return Err(fault),
+        };
+
+        match jump {
+            Some(target) => self.write(PROGRAM_COUNTER_ADDRESS, target)?,
+            None => self.write(PROGRAM_COUNTER_ADDRESS, self.pc().wrapping_add(1))?,
         }
+        Ok(State::Running)
+    }
 
-        memory[PROGRAM_COUNTER_ADDRESS] += 1;
+    pub fn core_dump(&self) {
+        print!("{}", self.dump().to_text());
     }
+}
 
-    core_dump(&instructions, &memory);
+/// Whether MOV, IMM and arithmetic use `address`, they ignore the others.
+pub fn check_address(address: u16) -> bool {
+    address & 0b0000_0001_1111 > 0 || address & 0b1111_1100_0000 > 0
 }
 
-fn core_dump(instructions: &Vec<Vec<u16>>, memory: &[u16; 4096]) {
+#[cfg(test)]
+mod tests {
+    use super::{
+        Access, Machine, RETURN_REGISTER_ADDRESS, STACK_BASE, STACK_POINTER_ADDRESS, State,
+        WatchAction, Watchpoint,
+    };
+    use crate::compiler::Layout;
+    use crate::testing::{assemble_program, run_program};
+
+    /// A machine for `program` that keeps `history` undo records.
+    fn recording(program: &str, history: usize) -> Machine {
+        let assembly =
+            assemble_program(program, Layout::default()).unwrap_or_else(|e| panic!("{e}"));
+        Machine::with_history(assembly.bytecode, history)
+    }
+
+    const ROUTINE: &str = "
+        IMM 5 R1
+        DEF LOOP
+        PUSH R1
+        CALL DOUBLE
+        POP R2
+        DEC R1
+        JNZ LOOP R1
+        JMP END
+        DEF DOUBLE
+        ADD R1 R3
+        ADD R1 R3
+        RET
+        DEF END
+    ";
 
-    println!("\nINTERPRETER DUMP:\n");
-    if let Some(line) = instructions.get(memory[PROGRAM_COUNTER_ADDRESS] as usize) {
-        println!(
-            "Current Instruction: {:?} {:?}",
-            Operation::from_u16(line[0]),
-            line[1..].to_vec()
+    #[test]
+    fn arithmetic_wraps() {
+        let machine = run_program(
+            "
+            IMM -1 R1
+            IMM 1 R2
+            ADD R2 R1
+            SUB R2 R3
+            IMM -1 R4
+            INC R4
+            DEC R5
+            IMM 0x100 R6
+            MUL R6 R6
+            IMM 1 R7
+            IMM 16 R8
+            SHL R8 R7
+        ",
         );
+        assert_eq!(machine.memory[1..=7], [0, 1, 0xFFFF, 0, 0xFFFF, 0, 0]);
     }
-    println!(
-        "Program Counter: [0x{:X}]\nReturn Register: [0x{:X}]\nStack Pointer:[0x{:X}]\nCarry Register: [0x{:X}]\nStack Base: 0x{:X}\n\n",
-        memory[RETURN_REGISTER_ADDRESS],
-        memory[PROGRAM_COUNTER_ADDRESS],
-        memory[STACK_POINTER_ADDRESS],
-        memory[CARRY_REGISTER_ADDRESS],
-        memory[STACK_BASE_ADDRESS],
-    );
-    println!("Arithmetic Registers:\n{:?}\n", memory[0..0x10].to_vec());
-    println!("Reserved Registers:\n{:?}\n", memory[0x011..0x020].to_vec());
-    println!("\nDUMP END\n");
-}
 
-fn check_address(address: u16) -> bool {
-    address & 0b0000_0001_1111 > 0 || address & 0b1111_1100_0000 > 0
-}
\ No newline at end of file
+    #[test]
+    fn stack_pointer_wraps() {
+        let machine = run_program(
+            "
+            IMM 7 R1
+            IMM -1 SP
+            PUSH R1
+            POP R2
+            MOV SP RET
+        ",
+        );
+        assert_eq!(machine.memory[STACK_BASE], 7);
+        assert_eq!(machine.memory[2], 7);
+        assert_eq!(machine.memory[STACK_POINTER_ADDRESS], 0xFFFF);
+        assert_eq!(machine.memory[RETURN_REGISTER_ADDRESS], 0xFFFF);
+    }
+
+    #[test]
+    fn stepping_back_restores_the_machine() {
+        let mut machine = recording(ROUTINE, 1000);
+        let mut states = Vec::new();
+        loop {
+            states.push((machine.cycle, machine.memory, machine.frames().len()));
+            if machine.step() != State::Running {
+                break;
+            }
+        }
+        assert_eq!((machine.memory[1], machine.memory[3]), (0, 30));
+        // the last step found the end of the program without executing
+        states.pop();
+        while let Some((cycle, memory, frames)) = states.pop() {
+            assert!(machine.step_back());
+            assert_eq!(machine.cycle, cycle);
+            assert_eq!(machine.memory, memory);
+            assert_eq!(machine.frames().len(), frames);
+        }
+        assert!(!machine.step_back());
+        assert_eq!(machine.pc(), 0);
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut machine = recording(ROUTINE, 3);
+        for _ in 0..10 {
+            machine.step();
+        }
+        assert_eq!(machine.oldest_cycle(), 7);
+        assert!(!machine.rewind_to(6));
+        assert!(machine.step_back());
+        assert!(machine.step_back());
+        assert!(machine.step_back());
+        assert!(!machine.step_back());
+        assert_eq!(machine.cycle, 7);
+
+        let mut unrecorded = recording("IMM 1 R1", 0);
+        unrecorded.step();
+        assert!(!unrecorded.step_back());
+        assert_eq!(unrecorded.memory[1], 1);
+    }
+
+    #[test]
+    fn running_back_to_a_write() {
+        let mut machine = recording(
+            "
+            IMM 1 R1
+            IMM 2 R2
+            IMM 3 R1
+            IMM 4 R3
+        ",
+            10,
+        );
+        assert_eq!(machine.run(), State::Finished);
+        assert_eq!(machine.run_back_to_write(1), Some(2));
+        assert_eq!((machine.cycle, machine.pc(), machine.memory[1]), (2, 2, 1));
+        assert_eq!(machine.memory[3], 0);
+        assert_eq!(machine.run_back_to_write(1), Some(0));
+        assert_eq!(
+            (machine.cycle, machine.memory[1], machine.memory[2]),
+            (0, 0, 0)
+        );
+        assert_eq!(machine.run_back_to_write(1), None);
+
+        assert!(machine.rewind_to(3));
+        assert_eq!(machine.memory[1..=3], [3, 2, 0]);
+        assert!(machine.rewind_to(1));
+        assert_eq!(machine.memory[1..=3], [1, 0, 0]);
+    }
+
+    const WATCHED: &str = "
+        IMM 1 R1
+        MOV R1 R2
+        IMM 1 R1
+        IMM 7 R3
+        IMM 9 R4
+    ";
+
+    /// (cycle, address, old, new) of every hit of a watchpoint on
+    /// `start..=end`, logging instead of pausing.
+    fn hits(start: usize, end: usize, access: Access) -> Vec<(u64, usize, u16, u16)> {
+        let mut machine = recording(WATCHED, 0);
+        machine.add_watchpoint(Watchpoint {
+            start,
+            end,
+            access,
+            action: WatchAction::Log,
+        });
+        assert_eq!(machine.run(), State::Finished);
+        let log = machine.take_watch_log();
+        assert!(log.iter().all(|hit| hit.access == access));
+        log.iter()
+            .map(|hit| (hit.cycle, hit.address, hit.old, hit.new))
+            .collect()
+    }
+
+    #[test]
+    fn watchpoints_fire_on_their_access() {
+        assert_eq!(hits(1, 1, Access::Read), [(1, 1, 1, 1)]);
+        assert_eq!(hits(1, 1, Access::Write), [(0, 1, 0, 1), (2, 1, 1, 1)]);
+        // writing the value a cell already holds is no change
+        assert_eq!(hits(1, 1, Access::Change), [(0, 1, 0, 1)]);
+        assert_eq!(hits(2, 3, Access::Write), [(1, 2, 0, 1), (3, 3, 0, 7)]);
+        assert_eq!(hits(5, 6, Access::Write), []);
+    }
+
+    #[test]
+    fn pausing_and_logging() {
+        let mut machine = recording(WATCHED, 0);
+        let pause = machine.add_watchpoint(Watchpoint {
+            start: 2,
+            end: 3,
+            access: Access::Write,
+            action: WatchAction::Pause,
+        });
+        machine.add_watchpoint(Watchpoint {
+            start: 4,
+            end: 4,
+            access: Access::Write,
+            action: WatchAction::Log,
+        });
+        // execution stops after the instruction that hit the watchpoint
+        assert_eq!(machine.run(), State::Paused);
+        assert_eq!((machine.cycle, machine.pc(), machine.memory[2]), (2, 2, 1));
+        assert_eq!(machine.run(), State::Paused);
+        assert_eq!((machine.cycle, machine.memory[3]), (4, 7));
+        assert_eq!(machine.run(), State::Finished);
+        assert_eq!(machine.memory[4], 9);
+        let actions: Vec<(usize, WatchAction)> = machine
+            .take_watch_log()
+            .iter()
+            .map(|hit| (hit.address, hit.action))
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                (2, WatchAction::Pause),
+                (3, WatchAction::Pause),
+                (4, WatchAction::Log)
+            ]
+        );
+
+        assert!(machine.remove_watchpoint(pause));
+        assert!(!machine.remove_watchpoint(pause));
+        assert_eq!(machine.watchpoints().len(), 1);
+    }
+}
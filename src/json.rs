@@ -0,0 +1,349 @@
+use crate::coredump::json_string;
+use std::fmt;
+
+/// A JSON value. Objects keep their fields in order.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+static NULL: Json = Json::Null;
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser { text, position: 0 };
+        let value = parser.value()?;
+        parser.whitespace();
+        match parser.position == text.len() {
+            true => Ok(value),
+            false => Err(format!("Unexpected text at {}", parser.position)),
+        }
+    }
+
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    /// Field `key` of an object, Null if there is none.
+    pub fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(name, _)| name == key)
+                .map_or(&NULL, |(_, value)| value),
+            _ => &NULL,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        match self {
+            Json::Number(number) if *number >= 0.0 && number.fract() == 0.0 => {
+                Some(*number as usize)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> &[Json] {
+        match self {
+            Json::Array(values) => values,
+            _ => &[],
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(string: &str) -> Json {
+        Json::String(string.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(string: String) -> Json {
+        Json::String(string)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(number: usize) -> Json {
+        Json::Number(number as f64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Json {
+        Json::Bool(value)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{value}"),
+            Json::Number(number) if number.fract() == 0.0 && number.abs() < 1e15 => {
+                write!(f, "{}", *number as i64)
+            }
+            Json::Number(number) => write!(f, "{number}"),
+            Json::String(string) => write!(f, "{}", json_string(string)),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (index, (key, value)) in fields.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{value}", json_string(key))?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn rest(&self) -> &str {
+        &self.text[self.position..]
+    }
+
+    fn whitespace(&mut self) {
+        let rest = self.rest();
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), String> {
+        self.whitespace();
+        match self.rest().starts_with(token) {
+            true => {
+                self.position += token.len();
+                Ok(())
+            }
+            false => Err(format!("Expected {token} at {}", self.position)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.whitespace();
+        let rest = self.rest();
+        for (word, value) in [
+            ("null", Json::Null),
+            ("true", Json::Bool(true)),
+            ("false", Json::Bool(false)),
+        ] {
+            if rest.starts_with(word) {
+                self.position += word.len();
+                return Ok(value);
+            }
+        }
+        match rest.chars().next() {
+            Some('"') => self.string().map(Json::String),
+            Some('[') => {
+                self.position += 1;
+                let mut values = Vec::new();
+                self.whitespace();
+                if self.rest().starts_with(']') {
+                    self.position += 1;
+                    return Ok(Json::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    self.whitespace();
+                    if self.rest().starts_with(']') {
+                        self.position += 1;
+                        return Ok(Json::Array(values));
+                    }
+                    self.expect(",")?;
+                }
+            }
+            Some('{') => {
+                self.position += 1;
+                let mut fields = Vec::new();
+                self.whitespace();
+                if self.rest().starts_with('}') {
+                    self.position += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.whitespace();
+                    let key = self.string()?;
+                    self.expect(":")?;
+                    fields.push((key, self.value()?));
+                    self.whitespace();
+                    if self.rest().starts_with('}') {
+                        self.position += 1;
+                        return Ok(Json::Object(fields));
+                    }
+                    self.expect(",")?;
+                }
+            }
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+                    .unwrap_or(rest.len());
+                let number = rest[..end]
+                    .parse()
+                    .map_err(|_| format!("Invalid number at {}", self.position))?;
+                self.position += end;
+                Ok(Json::Number(number))
+            }
+            _ => Err(format!("Unexpected value at {}", self.position)),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect("\"")?;
+        let mut string = String::new();
+        let mut characters = self.rest().char_indices();
+        while let Some((index, character)) = characters.next() {
+            match character {
+                '"' => {
+                    self.position += index + 1;
+                    return Ok(string);
+                }
+                '\\' => {
+                    let escaped = match characters.next().map(|(_, c)| c) {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('u') => {
+                            let high = hex4(&mut characters).ok_or("Invalid \\u escape")?;
+                            // a surrogate pair is written as two escapes, \uD83D\uDE00
+                            let code = match high {
+                                0xD800..=0xDBFF => {
+                                    let escape: String =
+                                        characters.by_ref().take(2).map(|(_, c)| c).collect();
+                                    let low = hex4(&mut characters)
+                                        .filter(|_| escape == "\\u")
+                                        .ok_or("Invalid \\u escape")?;
+                                    0x10000
+                                        + ((high - 0xD800) << 10)
+                                        + (low.wrapping_sub(0xDC00) & 0x3FF)
+                                }
+                                _ => high,
+                            };
+                            char::from_u32(code).unwrap_or('\u{FFFD}')
+                        }
+                        Some(other) => other,
+                        None => break,
+                    };
+                    string.push(escaped);
+                }
+                _ => string.push(character),
+            }
+        }
+        Err("Unterminated string".to_string())
+    }
+}
+
+/// Value of the next four hexadecimal digits.
+fn hex4(characters: &mut std::str::CharIndices) -> Option<u32> {
+    let digits: String = characters.take(4).map(|(_, c)| c).collect();
+    match digits.len() == 4 && digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        true => u32::from_str_radix(&digits, 16).ok(),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Json;
+
+    #[test]
+    fn parses_values() {
+        let json = Json::parse(r#" {"a": [1, -2.5, 1e3, true, false, null], "b": {}, "c": []} "#);
+        let expected = Json::object(vec![
+            (
+                "a",
+                Json::Array(vec![
+                    Json::Number(1.0),
+                    Json::Number(-2.5),
+                    Json::Number(1000.0),
+                    Json::Bool(true),
+                    Json::Bool(false),
+                    Json::Null,
+                ]),
+            ),
+            ("b", Json::Object(Vec::new())),
+            ("c", Json::Array(Vec::new())),
+        ]);
+        assert_eq!(json, Ok(expected));
+    }
+
+    #[test]
+    fn string_escapes() {
+        let parse = |text: &str| Json::parse(text).map(|json| json.as_str().map(String::from));
+        assert_eq!(
+            parse(r#""a\"\\\/\n\t\r\b\f""#),
+            Ok(Some("a\"\\/\n\t\r\u{8}\u{c}".to_string()))
+        );
+        assert_eq!(parse(r#""é€""#), Ok(Some("é€".to_string())));
+        assert_eq!(parse(r#""😀 \u00e9""#), Ok(Some("😀 é".to_string())));
+        assert_eq!(parse(r#""\uD83D\uDE00""#), Ok(Some("😀".to_string())));
+        assert!(parse(r#""\u12""#).is_err());
+        assert!(parse(r#""\uD83D x""#).is_err());
+
+        let text = "quote \" backslash \\ line\n bell \u{7}";
+        let written = Json::from(text).to_string();
+        assert_eq!(written, r#""quote \" backslash \\ line\n bell \u0007""#);
+        assert_eq!(Json::parse(&written), Ok(Json::from(text)));
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(Json::parse("42").unwrap().as_usize(), Some(42));
+        assert_eq!(Json::parse("-1").unwrap().as_usize(), None);
+        assert_eq!(Json::parse("1.5").unwrap().as_usize(), None);
+        assert_eq!(Json::Number(3.0).to_string(), "3");
+        assert_eq!(Json::Number(-0.25).to_string(), "-0.25");
+        assert_eq!(Json::parse("1.2.3"), Err("Invalid number at 0".to_string()));
+    }
+
+    #[test]
+    fn malformed_input() {
+        for (text, error) in [
+            ("", "Unexpected value at 0"),
+            ("[1, 2", "Expected , at 5"),
+            ("[1 2]", "Expected , at 3"),
+            (r#"{"a" 1}"#, "Expected : at 5"),
+            (r#"{a: 1}"#, "Expected \" at 1"),
+            (r#""open"#, "Unterminated string"),
+            ("nul", "Unexpected value at 0"),
+            ("{} x", "Unexpected text at 3"),
+        ] {
+            assert_eq!(Json::parse(text), Err(error.to_string()), "{text}");
+        }
+    }
+}
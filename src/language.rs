@@ -0,0 +1,571 @@
+use crate::compiler::Diagnostic;
+use crate::expr::{OPERATORS, apply, precedence};
+use crate::operation::{WORD_BITS, fit_word, literal_value};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::rc::Rc;
+
+/*
+HIGH-LEVEL LANGUAGE
+
+eightbit compile program.x1h [-o program.x1]
+eightbit program.x1h
+
+var total = 0;                 global variable, its value must be a constant
+
+fn add(a, b) {                 function with parameters
+    return a + b;
+}
+
+fn main() {                    the program starts here
+    var i = 1;                 local variable, known until the end of the block
+    while i <= 10 {
+        total = add(total, i);
+        i = i + 1;
+    }
+    if total == 55 {
+        return 1;
+    } else if total > 55 {
+        return 2;
+    }
+    return 0;
+}
+
+Values are unsigned 16 bit words, like the machine's. Expressions have the
+operators of assembler expressions (see expr.rs) with the same precedence,
+comparisons and ! give 1 or 0 and && and || skip their right side when the
+left one decides. `-` in front of a constant makes it negative (two's
+complement), there are no negative values at run time. Numbers are written
+like assembler literals: 42, 0x2A, 0b101010, 0o52, '*'.
+
+`break` and `continue` apply to the innermost loop, a call can be used as a
+statement of its own. A function that ends without `return`, or returns
+without a value, returns 0. The program calls main and finishes when main
+returns, its value is left in the RET register. codegen.rs describes the
+code generated for it.
+*/
+
+pub struct Program {
+    pub globals: Vec<Global>,
+    pub functions: Vec<Function>,
+}
+
+pub struct Global {
+    pub name: String,
+    pub value: u16,
+    pub line: usize,
+}
+
+pub struct Function {
+    pub name: String,
+    /// The first `params` variables are the parameters.
+    pub params: usize,
+    pub variables: Vec<Variable>,
+    pub body: Vec<Statement>,
+    pub line: usize,
+}
+
+/// A parameter or local variable. `weight` counts its uses, those inside
+/// loops count more, and `reads` holds the position of every read, see
+/// Expr::Call.
+pub struct Variable {
+    pub name: String,
+    pub weight: usize,
+    pub reads: Vec<usize>,
+}
+
+pub struct Statement {
+    pub line: usize,
+    pub kind: StatementKind,
+}
+
+pub enum StatementKind {
+    Assign(Place, Expr),
+    If(Expr, Vec<Statement>, Vec<Statement>),
+    /// `span` holds the positions inside the loop.
+    While {
+        condition: Expr,
+        body: Vec<Statement>,
+        span: Range<usize>,
+    },
+    Return(Option<Expr>),
+    Break,
+    Continue,
+    Expr(Expr),
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub enum Place {
+    Local(usize),
+    Global(String),
+}
+
+pub enum Expr {
+    Number(u16),
+    /// A read of a variable of the function, at a position.
+    Local(usize, usize),
+    Global(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    /// Reads and calls are numbered in the order they run within a function,
+    /// `at` comes after the positions of the arguments.
+    Call {
+        name: String,
+        args: Vec<Expr>,
+        at: usize,
+    },
+}
+
+impl Expr {
+    /// Whether the expression reads `place`.
+    pub fn reads(&self, place: &Place) -> bool {
+        match self {
+            Expr::Number(_) => false,
+            Expr::Local(variable, _) => *place == Place::Local(*variable),
+            Expr::Global(name) => *place == Place::Global(name.clone()),
+            Expr::Unary(_, operand) => operand.reads(place),
+            Expr::Binary(_, left, right) => left.reads(place) || right.reads(place),
+            Expr::Call { args, .. } => args.iter().any(|arg| arg.reads(place)),
+        }
+    }
+
+    pub fn calls(&self) -> bool {
+        match self {
+            Expr::Number(_) | Expr::Local(..) | Expr::Global(_) => false,
+            Expr::Unary(_, operand) => operand.calls(),
+            Expr::Binary(_, left, right) => left.calls() || right.calls(),
+            Expr::Call { .. } => true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(u16),
+    Name(String),
+    Symbol(&'static str),
+}
+
+const SYMBOLS: [&str; 7] = ["=", "(", ")", "{", "}", ",", ";"];
+
+const KEYWORDS: [&str; 8] = [
+    "var", "fn", "if", "else", "while", "return", "break", "continue",
+];
+
+/// Parses `text`, read from `file`.
+pub fn parse(text: &str, file: Rc<str>) -> Result<Program, Diagnostic> {
+    let tokens = lex(text, &file)?;
+    let end = text.split('\n').count();
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+        end,
+        file,
+        variables: Vec::new(),
+        scopes: Vec::new(),
+        counter: 0,
+        loops: 0,
+    };
+    parser.program()
+}
+
+fn lex(text: &str, file: &Rc<str>) -> Result<Vec<(Token, usize)>, Diagnostic> {
+    let mut tokens = Vec::new();
+    for (index, line) in text.split('\n').enumerate() {
+        let number = index + 1;
+        let error = |message: String| Diagnostic::at(file.clone(), number, message);
+        let mut rest = line;
+        while let Some(character) = rest.chars().next() {
+            let length = if character.is_ascii_whitespace() {
+                1
+            } else if rest.starts_with("//") {
+                break;
+            } else if character.is_ascii_alphanumeric() || character == '_' || character == '\'' {
+                let length = match character {
+                    '\'' => {
+                        closing_quote(rest)
+                            .ok_or_else(|| error("Unterminated character literal".to_string()))?
+                            + 1
+                    }
+                    _ => rest
+                        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                        .unwrap_or(rest.len()),
+                };
+                let word = &rest[..length];
+                tokens.push((
+                    match literal_value(word).map_err(&error)? {
+                        Some(value) => Token::Number(fit_word(value, WORD_BITS).map_err(&error)?),
+                        None => Token::Name(word.to_string()),
+                    },
+                    number,
+                ));
+                length
+            } else if let Some(symbol) = OPERATORS
+                .iter()
+                .chain(SYMBOLS.iter())
+                .find(|symbol| rest.starts_with(**symbol))
+            {
+                tokens.push((Token::Symbol(symbol), number));
+                symbol.len()
+            } else {
+                return Err(error(format!("Unexpected character {character}")));
+            };
+            rest = &rest[length..];
+        }
+    }
+    Ok(tokens)
+}
+
+/// Index of the quote closing the character literal at the start of `text`.
+fn closing_quote(text: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (index, character) in text.char_indices().skip(1) {
+        match character {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '\'' => return Some(index),
+            _ => {}
+        }
+    }
+    None
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    /// Line reported for errors at the end of the file.
+    end: usize,
+    file: Rc<str>,
+    // the function being parsed
+    variables: Vec<Variable>,
+    scopes: Vec<HashMap<String, usize>>,
+    counter: usize,
+    loops: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .map_or(self.end, |(_, line)| *line)
+    }
+
+    fn error(&self, message: String) -> Diagnostic {
+        Diagnostic::at(self.file.clone(), self.line(), message)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.position += 1;
+        token
+    }
+
+    /// Consumes `symbol` if it comes next.
+    fn accept(&mut self, symbol: &str) -> bool {
+        let found = match self.peek() {
+            Some(Token::Symbol(next)) => *next == symbol,
+            Some(Token::Name(name)) => name == symbol,
+            _ => false,
+        };
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), Diagnostic> {
+        match self.accept(symbol) {
+            true => Ok(()),
+            false => Err(self.error(format!("Expected {symbol}, found {}", self.found()))),
+        }
+    }
+
+    fn found(&self) -> String {
+        match self.peek() {
+            Some(Token::Number(value)) => value.to_string(),
+            Some(Token::Name(name)) => name.clone(),
+            Some(Token::Symbol(symbol)) => symbol.to_string(),
+            None => "the end of the file".to_string(),
+        }
+    }
+
+    fn name(&mut self) -> Result<String, Diagnostic> {
+        match self.peek() {
+            Some(Token::Name(name)) if !KEYWORDS.contains(&name.as_str()) => {
+                let name = name.clone();
+                self.position += 1;
+                Ok(name)
+            }
+            _ => Err(self.error(format!("Expected a name, found {}", self.found()))),
+        }
+    }
+
+    fn program(&mut self) -> Result<Program, Diagnostic> {
+        let mut program = Program {
+            globals: Vec::new(),
+            functions: Vec::new(),
+        };
+        let mut names: HashMap<String, usize> = HashMap::new();
+        while self.peek().is_some() {
+            let line = self.line();
+            let global = self.accept("var");
+            if !global && !self.accept("fn") {
+                return Err(self.error(format!("Expected var or fn, found {}", self.found())));
+            }
+            let name = self.name()?;
+            if let Some(previous) = names.insert(name.clone(), line) {
+                return Err(self.error(format!("{name} is already defined on line {previous}")));
+            }
+            if global {
+                let value = match self.accept("=") {
+                    true => match self.expression(0)? {
+                        Expr::Number(value) => value,
+                        _ => {
+                            return Err(
+                                self.error(format!("The value of {name} must be a constant"))
+                            );
+                        }
+                    },
+                    false => 0,
+                };
+                self.expect(";")?;
+                program.globals.push(Global { name, value, line });
+            } else {
+                program.functions.push(self.function(name, line)?);
+            }
+        }
+        Ok(program)
+    }
+
+    fn function(&mut self, name: String, line: usize) -> Result<Function, Diagnostic> {
+        self.variables = Vec::new();
+        self.scopes = vec![HashMap::new()];
+        self.counter = 0;
+        self.expect("(")?;
+        while !self.accept(")") {
+            if !self.variables.is_empty() {
+                self.expect(",")?;
+            }
+            let param = self.name()?;
+            self.declare(param)?;
+        }
+        let params = self.variables.len();
+        let body = self.block()?;
+        Ok(Function {
+            name,
+            params,
+            variables: std::mem::take(&mut self.variables),
+            body,
+            line,
+        })
+    }
+
+    fn declare(&mut self, name: String) -> Result<usize, Diagnostic> {
+        let scope = self.scopes.last_mut().expect("no scope");
+        if scope.contains_key(&name) {
+            return Err(self.error(format!("{name} is already defined in this block")));
+        }
+        scope.insert(name.clone(), self.variables.len());
+        self.variables.push(Variable {
+            name,
+            weight: 0,
+            reads: Vec::new(),
+        });
+        Ok(self.variables.len() - 1)
+    }
+
+    /// Where `name` is stored, counting it as a use.
+    fn lookup(&mut self, name: &str) -> Place {
+        let variable = self
+            .scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).copied());
+        match variable {
+            Some(variable) => {
+                self.variables[variable].weight += 8usize.pow(self.loops.min(4) as u32);
+                Place::Local(variable)
+            }
+            None => Place::Global(name.to_string()),
+        }
+    }
+
+    fn block(&mut self) -> Result<Vec<Statement>, Diagnostic> {
+        self.expect("{")?;
+        self.scopes.push(HashMap::new());
+        let mut statements = Vec::new();
+        while !self.accept("}") {
+            if self.peek().is_none() {
+                return Err(self.error("Expected }, found the end of the file".to_string()));
+            }
+            statements.push(self.statement()?);
+        }
+        self.scopes.pop();
+        Ok(statements)
+    }
+
+    fn statement(&mut self) -> Result<Statement, Diagnostic> {
+        let line = self.line();
+        let kind = if self.accept("var") {
+            let name = self.name()?;
+            let value = match self.accept("=") {
+                true => self.expression(0)?,
+                false => Expr::Number(0),
+            };
+            self.expect(";")?;
+            let variable = self.declare(name)?;
+            self.lookup(&self.variables[variable].name.clone());
+            StatementKind::Assign(Place::Local(variable), value)
+        } else if self.accept("if") {
+            self.conditional()?
+        } else if self.accept("while") {
+            let start = self.counter;
+            self.loops += 1;
+            let condition = self.expression(0)?;
+            let body = self.block()?;
+            self.loops -= 1;
+            StatementKind::While {
+                condition,
+                body,
+                span: start..self.counter,
+            }
+        } else if self.accept("return") {
+            let value = match self.accept(";") {
+                true => None,
+                false => {
+                    let value = self.expression(0)?;
+                    self.expect(";")?;
+                    Some(value)
+                }
+            };
+            StatementKind::Return(value)
+        } else if let Some(keyword) = ["break", "continue"]
+            .into_iter()
+            .find(|keyword| self.accept(keyword))
+        {
+            if self.loops == 0 {
+                return Err(self.error(format!("{keyword} outside a loop")));
+            }
+            self.expect(";")?;
+            match keyword {
+                "break" => StatementKind::Break,
+                _ => StatementKind::Continue,
+            }
+        } else if let (Some(Token::Name(name)), Some((Token::Symbol("="), _))) =
+            (self.peek().cloned(), self.tokens.get(self.position + 1))
+        {
+            self.position += 2;
+            let value = self.expression(0)?;
+            self.expect(";")?;
+            StatementKind::Assign(self.lookup(&name), value)
+        } else {
+            let value = self.expression(0)?;
+            self.expect(";")?;
+            StatementKind::Expr(value)
+        };
+        Ok(Statement { line, kind })
+    }
+
+    /// The rest of an if statement, after `if`.
+    fn conditional(&mut self) -> Result<StatementKind, Diagnostic> {
+        let condition = self.expression(0)?;
+        let then = self.block()?;
+        let otherwise = if !self.accept("else") {
+            Vec::new()
+        } else if self.peek() == Some(&Token::Name("if".to_string())) {
+            let line = self.line();
+            self.position += 1;
+            vec![Statement {
+                line,
+                kind: self.conditional()?,
+            }]
+        } else {
+            self.block()?
+        };
+        Ok(StatementKind::If(condition, then, otherwise))
+    }
+
+    /// Parses binary operators binding tighter than `min`.
+    fn expression(&mut self, min: usize) -> Result<Expr, Diagnostic> {
+        let mut left = self.unary()?;
+        while let Some(Token::Symbol(operator)) = self.peek().cloned() {
+            if !OPERATORS.contains(&operator)
+                || operator == "~"
+                || operator == "!"
+                || precedence(operator) <= min
+            {
+                break;
+            }
+            self.position += 1;
+            let right = self.expression(precedence(operator))?;
+            left = match (left, right) {
+                (Expr::Number(left), Expr::Number(right)) => {
+                    let value = apply(operator, left as i64, right as i64)
+                        .map_err(|message| self.error(message))?;
+                    Expr::Number(value as u16)
+                }
+                (left, right) => Expr::Binary(operator, Box::new(left), Box::new(right)),
+            };
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, Diagnostic> {
+        let line = self.line();
+        match self.next() {
+            Some(Token::Symbol("-")) => match self.unary()? {
+                Expr::Number(value) => Ok(Expr::Number(value.wrapping_neg())),
+                _ => Err(Diagnostic::at(
+                    self.file.clone(),
+                    line,
+                    "Only constants can be negated, values are unsigned".to_string(),
+                )),
+            },
+            Some(Token::Symbol("+")) => self.unary(),
+            Some(Token::Symbol(operator @ ("~" | "!"))) => Ok(match self.unary()? {
+                Expr::Number(value) if operator == "~" => Expr::Number(!value),
+                Expr::Number(value) => Expr::Number((value == 0) as u16),
+                operand => Expr::Unary(operator, Box::new(operand)),
+            }),
+            Some(Token::Symbol("(")) => {
+                let value = self.expression(0)?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            Some(Token::Number(value)) => Ok(Expr::Number(value)),
+            Some(Token::Name(name)) if !KEYWORDS.contains(&name.as_str()) => {
+                if !self.accept("(") {
+                    return Ok(match self.lookup(&name) {
+                        Place::Local(variable) => {
+                            self.variables[variable].reads.push(self.counter);
+                            self.counter += 1;
+                            Expr::Local(variable, self.counter - 1)
+                        }
+                        Place::Global(name) => Expr::Global(name),
+                    });
+                }
+                let mut args = Vec::new();
+                while !self.accept(")") {
+                    if !args.is_empty() {
+                        self.expect(",")?;
+                    }
+                    args.push(self.expression(0)?);
+                }
+                self.counter += 1;
+                Ok(Expr::Call {
+                    name,
+                    args,
+                    at: self.counter - 1,
+                })
+            }
+            _ => {
+                self.position -= 1;
+                Err(self.error(format!("Expected a value, found {}", self.found())))
+            }
+        }
+    }
+}
@@ -0,0 +1,25 @@
+pub mod codegen;
+pub mod compiler;
+pub mod conditional;
+pub mod coredump;
+pub mod data;
+pub mod debugger;
+pub mod expr;
+pub mod formatter;
+pub mod hexdump;
+pub mod include;
+pub mod instruction;
+pub mod interpreter;
+pub mod json;
+pub mod language;
+pub mod lint;
+pub mod listing;
+pub mod lsp;
+pub mod macros;
+pub mod object;
+pub mod operation;
+pub mod peephole;
+pub mod reachability;
+pub mod sourcemap;
+#[cfg(test)]
+mod testing;
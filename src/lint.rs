@@ -0,0 +1,494 @@
+use crate::compiler::{Diagnostic, Line, builtin, qualify, read_file, split_line};
+use crate::coredump::json_string;
+use crate::include::resolve_readable;
+use crate::interpreter::{RETURN_REGISTER_ADDRESS, register_name};
+use crate::macros::expand_macros;
+use crate::operation::{Operation, WORD_BITS, parse_literal};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+/*
+LINTER
+
+eightbit lint program.x1 [--json]
+eightbit lint --rules
+
+Checks a program, with its includes and macros expanded, against the rules
+below and prints one finding per line, or a JSON array with --json. Exits
+with status 1 if there are findings.
+
+// lint:allow RULE ...     on a line of its own turns RULE off for the file
+IMM 1 RET // lint:allow    after code turns every rule off for that line
+
+Without a rule name every rule is turned off. Definitions inside .if blocks
+may repeat, a name is usually defined once per branch.
+*/
+
+pub const RULES: [(&str, &str); 6] = [
+    (
+        "redefinition",
+        "a DEF name defined twice or shadowing a built-in name",
+    ),
+    ("unused-def", "a DEF constant or label that is never used"),
+    (
+        "reserved-register",
+        "MOV or IMM writing a reserved register (0x010-0x01F)",
+    ),
+    ("ret-outside-routine", "RET in code that is never called"),
+    ("missing-hlt", "a program that does not end with HLT"),
+    (
+        "stack-balance",
+        "a RET after more PUSH than POP in its routine, or the opposite",
+    ),
+];
+
+pub struct Finding {
+    pub file: Rc<str>,
+    pub line: usize,
+    pub rule: &'static str,
+    pub message: String,
+}
+
+/// Lints `program`, read from `path`, and every file it includes.
+pub fn lint_file(program: String, path: &Path) -> Result<Vec<Finding>, Diagnostic> {
+    // every branch is checked, with the files included inside .if blocks
+    let lines = resolve_readable(read_file(program.clone(), path, &[])?);
+    let lines = expand_macros(lines)?;
+    let mut allowed = Allowed::default();
+    let mut files = HashSet::new();
+    let main = path.display().to_string();
+    for line in lines.iter() {
+        if files.insert(line.file.clone()) {
+            let text = if *line.file == *main {
+                program.clone()
+            } else {
+                fs::read_to_string(&*line.file).unwrap_or_default()
+            };
+            allowed.read(&line.file, &text);
+        }
+    }
+    let mut findings = lint(&lines);
+    findings.retain(|finding| !allowed.allows(finding));
+    Ok(findings)
+}
+
+/// Rules turned off by `lint:allow` comments, per file and per line. An
+/// empty list stands for every rule.
+#[derive(Default)]
+struct Allowed {
+    files: HashMap<Rc<str>, Vec<String>>,
+    lines: HashMap<(Rc<str>, usize), Vec<String>>,
+}
+
+impl Allowed {
+    fn read(&mut self, file: &Rc<str>, text: &str) {
+        for (index, source) in text.split('\n').enumerate() {
+            let (words, Some(comment)) = split_line(source) else {
+                continue;
+            };
+            let Some(start) = comment.find("lint:allow") else {
+                continue;
+            };
+            let rules: Vec<String> = comment[start + "lint:allow".len()..]
+                .split(|c: char| c.is_ascii_whitespace() || c == ',')
+                .filter(|rule| !rule.is_empty())
+                .map(String::from)
+                .collect();
+            if words.is_empty() {
+                self.files.entry(file.clone()).or_default().extend(rules);
+            } else {
+                self.lines.insert((file.clone(), index + 1), rules);
+            }
+        }
+    }
+
+    fn allows(&self, finding: &Finding) -> bool {
+        let covers =
+            |rules: &Vec<String>| rules.is_empty() || rules.iter().any(|rule| rule == finding.rule);
+        self.files.get(&finding.file).is_some_and(covers)
+            || self
+                .lines
+                .get(&(finding.file.clone(), finding.line))
+                .is_some_and(covers)
+    }
+}
+
+/// A DEF name, where it was defined and whether that was inside an .if block.
+struct Definition<'a> {
+    line: &'a Line,
+    conditional: bool,
+}
+
+/// Checks `lines`, which have their includes and macros expanded.
+pub fn lint(lines: &[Line]) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let mut finding = |line: &Line, rule: &'static str, message: String| {
+        findings.push(Finding {
+            file: line.file.clone(),
+            line: line.line,
+            rule,
+            message,
+        })
+    };
+
+    let mut definitions: Vec<(String, Definition)> = Vec::new();
+    let mut values: HashMap<String, u16> = HashMap::new();
+    let mut used: HashSet<String> = HashSet::new();
+    let mut exported: HashSet<String> = HashSet::new();
+    let mut called: HashSet<String> = HashSet::new();
+    let mut returns: Vec<(&Line, String)> = Vec::new();
+    let mut last: Option<(&Line, Operation)> = None;
+    let mut halts = false;
+    let mut scope = String::new();
+    let mut depth = 0;
+    // values pushed by the current routine, and at the labels jumped to
+    let mut stack = 0i32;
+    let mut jumped: HashMap<String, i32> = HashMap::new();
+
+    for line in lines.iter() {
+        let Some(first) = line.words.first() else {
+            continue;
+        };
+        let mut uses = |words: &[String], scope: &str| {
+            for word in words {
+                for name in names(word) {
+                    used.insert(qualify(name, scope));
+                }
+            }
+        };
+        match first.to_ascii_lowercase().as_str() {
+            ".if" | ".ifdef" | ".ifndef" => {
+                depth += 1;
+                uses(&line.words[1..], &scope);
+                continue;
+            }
+            ".endif" => {
+                depth -= 1;
+                continue;
+            }
+            _ if first.starts_with('.') => {
+                uses(&line.words[1..], &scope);
+                continue;
+            }
+            _ => {}
+        }
+        match first.as_str() {
+            "EXPORT" => {
+                exported.extend(line.words[1..].iter().cloned());
+                continue;
+            }
+            "EXTERN" => continue,
+            _ => {}
+        }
+        let Some(op) = Operation::from_name(first) else {
+            uses(&line.words[1..], &scope);
+            continue;
+        };
+        let operand = |index: usize| line.words.get(index).map(String::as_str);
+
+        if op == Operation::DEF {
+            let Some(name) = operand(1) else {
+                continue;
+            };
+            if builtin(name, false).is_some() {
+                finding(
+                    line,
+                    "redefinition",
+                    format!(
+                        "DEF {name} shadows the built-in name {}",
+                        name.to_uppercase()
+                    ),
+                );
+            }
+            let label = line.words.len() == 2;
+            if label && !name.starts_with('.') && line.expansion.is_empty() {
+                scope = name.to_string();
+                stack = 0;
+            }
+            let name = qualify(name, &scope);
+            if label && let Some(pushed) = jumped.get(&name) {
+                stack = *pushed;
+            }
+            let conditional = depth > 0;
+            if let Some((_, previous)) = definitions.iter().find(|(other, _)| *other == name)
+                && !(conditional && previous.conditional)
+            {
+                finding(
+                    line,
+                    "redefinition",
+                    format!(
+                        "{name} is already defined at {}:{}",
+                        previous.line.file, previous.line.line
+                    ),
+                );
+            }
+            if let [value] = &line.words[2..]
+                && let Ok(Some(value)) = parse_literal(value, WORD_BITS)
+            {
+                values.insert(name.clone(), value);
+            }
+            uses(&line.words[2..], &scope);
+            definitions.push((name, Definition { line, conditional }));
+            continue;
+        }
+
+        // the opcode too, which may be a DEF shadowing a mnemonic
+        uses(&line.words, &scope);
+        match op {
+            Operation::MOV | Operation::IMM => {
+                let dest = operand(2).and_then(|word| {
+                    parse_literal(word, WORD_BITS)
+                        .ok()
+                        .flatten()
+                        .or_else(|| builtin(word, true))
+                        .or_else(|| values.get(&qualify(word, &scope)).copied())
+                });
+                if let Some(dest) = dest
+                    && (RETURN_REGISTER_ADDRESS..0x020).contains(&(dest as usize))
+                {
+                    let register = register_name(dest as usize)
+                        .map_or(String::new(), |name| format!(" {name}"));
+                    finding(
+                        line,
+                        "reserved-register",
+                        format!("{op:?} writes the reserved register{register} (0x{dest:03X})"),
+                    );
+                }
+            }
+            Operation::CALL => {
+                called.extend(operand(1).map(|name| qualify(name, &scope)));
+            }
+            Operation::PUSH => stack += 1,
+            Operation::POP => stack -= 1,
+            Operation::RET => {
+                returns.push((line, scope.clone()));
+                if stack > 0 {
+                    finding(
+                        line,
+                        "stack-balance",
+                        format!("RET after {stack} more PUSH than POP"),
+                    );
+                } else if stack < 0 {
+                    finding(
+                        line,
+                        "stack-balance",
+                        format!("RET after {} more POP than PUSH", -stack),
+                    );
+                }
+            }
+            Operation::HLT => halts = true,
+            _ => {}
+        }
+        if op.relative().is_some()
+            && op != Operation::CALL
+            && let Some(target) = operand(1)
+        {
+            jumped.entry(qualify(target, &scope)).or_insert(stack);
+        }
+        last = Some((line, op));
+    }
+
+    for (name, definition) in definitions.iter() {
+        if !used.contains(name) && !exported.contains(name) {
+            finding(
+                definition.line,
+                "unused-def",
+                format!("{name} is never used"),
+            );
+        }
+    }
+    for (line, scope) in returns {
+        if scope.is_empty() {
+            finding(
+                line,
+                "ret-outside-routine",
+                "RET outside any routine".to_string(),
+            );
+        } else if !called.contains(&scope) && !exported.contains(&scope) {
+            finding(
+                line,
+                "ret-outside-routine",
+                format!("RET in {scope}, which is never called"),
+            );
+        }
+    }
+    // objects are libraries without a program to end
+    if exported.is_empty()
+        && let Some((line, op)) = last
+    {
+        if !halts {
+            finding(line, "missing-hlt", "The program has no HLT".to_string());
+        } else if !matches!(
+            op,
+            Operation::HLT | Operation::JMP | Operation::JR | Operation::RET
+        ) {
+            finding(
+                line,
+                "missing-hlt",
+                "The program runs past its last instruction instead of ending with HLT".to_string(),
+            );
+        }
+    }
+
+    findings.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+    findings
+}
+
+/// Names used by `word`, which may be an expression. Strings and character
+/// literals have none.
+fn names(word: &str) -> Vec<&str> {
+    if word.starts_with(['"', '\'']) {
+        return Vec::new();
+    }
+    word.split(|c: char| !(c.is_ascii_alphanumeric() || "_.@".contains(c)))
+        .filter(|name| name.starts_with(|c: char| c.is_ascii_alphabetic() || "_.@".contains(c)))
+        .collect()
+}
+
+pub fn to_text(findings: &[Finding]) -> String {
+    let mut text = String::new();
+    for finding in findings {
+        let _ = writeln!(
+            text,
+            "{}:{}: {} [{}]",
+            finding.file, finding.line, finding.message, finding.rule
+        );
+    }
+    text
+}
+
+pub fn to_json(findings: &[Finding]) -> String {
+    let findings: Vec<String> = findings
+        .iter()
+        .map(|finding| {
+            format!(
+                "  {{\"file\": {}, \"line\": {}, \"rule\": {}, \"message\": {}}}",
+                json_string(&finding.file),
+                finding.line,
+                json_string(finding.rule),
+                json_string(&finding.message)
+            )
+        })
+        .collect();
+    if findings.is_empty() {
+        "[]\n".to_string()
+    } else {
+        format!("[\n{}\n]\n", findings.join(",\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lint_file, to_json, to_text};
+    use std::path::Path;
+
+    /// (line, rule) of every finding in `program`.
+    fn findings(program: &str) -> Vec<(usize, &'static str)> {
+        let findings = lint_file(program.to_string(), Path::new("test.x1")).unwrap();
+        findings
+            .iter()
+            .map(|finding| (finding.line, finding.rule))
+            .collect()
+    }
+
+    #[test]
+    fn redefinition() {
+        assert_eq!(
+            findings("DEF A 1\nDEF A 2\nIMM A R1\nHLT 0"),
+            [(2, "redefinition")]
+        );
+        assert_eq!(
+            findings("DEF PUSH 1\nIMM PUSH R1\nHLT 0"),
+            [(1, "redefinition")]
+        );
+        // once per branch of an .if
+        let branches = ".ifdef FAST\nDEF A 1\n.else\nDEF A 2\n.endif\nIMM A R1\nHLT 0";
+        assert_eq!(findings(branches), []);
+    }
+
+    #[test]
+    fn unused_def() {
+        assert_eq!(findings("DEF A 1\nIMM 1 R1\nHLT 0"), [(1, "unused-def")]);
+        assert_eq!(findings("DEF A 1\nIMM A+1 R1\nHLT 0"), []);
+    }
+
+    #[test]
+    fn reserved_register() {
+        assert_eq!(
+            findings("IMM 1 SP\nMOV R1 0x010\nHLT 0"),
+            [(1, "reserved-register"), (2, "reserved-register")]
+        );
+        assert_eq!(findings("IMM 1 R15\nMOV R1 0x020\nHLT 0"), []);
+    }
+
+    #[test]
+    fn ret_outside_routine() {
+        assert_eq!(
+            findings("RET\nJMP F\nDEF F\nRET\nHLT 0"),
+            [(1, "ret-outside-routine"), (4, "ret-outside-routine")]
+        );
+        assert_eq!(findings("CALL F\nHLT 0\nDEF F\nRET"), []);
+    }
+
+    #[test]
+    fn missing_hlt() {
+        assert_eq!(findings("IMM 1 R1"), [(1, "missing-hlt")]);
+        assert_eq!(findings("HLT 0\nIMM 1 R1"), [(2, "missing-hlt")]);
+        assert_eq!(findings("IMM 1 R1\nHLT 0"), []);
+        // a library has no program to end
+        assert_eq!(findings("EXPORT F\nDEF F\nRET"), []);
+    }
+
+    #[test]
+    fn stack_balance() {
+        let program = "CALL F\nCALL G\nHLT 0\nDEF F\nPUSH R1\nRET\nDEF G\nPOP R1\nRET";
+        assert_eq!(
+            findings(program),
+            [(6, "stack-balance"), (9, "stack-balance")]
+        );
+        let program =
+            "CALL F\nHLT 0\nDEF F\nPUSH R1\nJZ .done R1\nPOP R1\nRET\nDEF .done\nPOP R2\nRET";
+        assert_eq!(findings(program), []);
+    }
+
+    #[test]
+    fn allowed_rules() {
+        let program = "DEF A 1\nIMM 1 SP // lint:allow\nHLT 0";
+        assert_eq!(findings(program), [(1, "unused-def")]);
+        let program = "// lint:allow unused-def\nDEF A 1\nIMM 1 SP\nHLT 0";
+        assert_eq!(findings(program), [(3, "reserved-register")]);
+        let program = "DEF A 1 // lint:allow missing-hlt\nHLT 0";
+        assert_eq!(findings(program), [(1, "unused-def")]);
+    }
+
+    #[test]
+    fn json_output() {
+        let findings = lint_file("IMM 1 R1".to_string(), Path::new("test.x1")).unwrap();
+        assert_eq!(
+            to_json(&findings),
+            "[\n  {\"file\": \"test.x1\", \"line\": 1, \"rule\": \"missing-hlt\", \
+             \"message\": \"The program has no HLT\"}\n]\n"
+        );
+        assert_eq!(to_json(&[]), "[]\n");
+        assert_eq!(
+            to_text(&findings),
+            "test.x1:1: The program has no HLT [missing-hlt]\n"
+        );
+    }
+
+    #[test]
+    fn shadowed_mnemonics_are_used() {
+        let program = "
+            // lint:allow redefinition
+            DEF INC 0x025
+            IMM 1 R1
+            INC R1
+            HLT 0
+        ";
+        let findings = lint_file(program.to_string(), Path::new("test.x1")).unwrap();
+        assert!(findings.is_empty(), "{}", to_text(&findings));
+    }
+}
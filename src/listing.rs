@@ -0,0 +1,116 @@
+use crate::compiler::Assembly;
+use std::fmt::Write as _;
+use std::rc::Rc;
+
+/*
+LISTING
+
+program.x1:12            0002  0032 0001 0002 0003      JL FUNC1 R2 R3    ; FUNC1=0001
+
+One row per source line: where it came from, the address it was assigned
+(an instruction index, or a memory address marked with `d` for data), the
+words it encodes to, the line itself and the values of the names it uses.
+The symbol table with every DEF name and label follows at the end.
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymbolKind {
+    Label,
+    Data,
+    Constant,
+}
+
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub value: Vec<u16>,
+}
+
+/// Assembler output for one source line.
+pub struct Entry {
+    pub file: Rc<str>,
+    pub line: usize,
+    pub text: String,
+    pub address: Option<u16>,
+    pub data: bool,
+    pub words: Vec<u16>,
+    pub resolved: Vec<(String, Vec<u16>)>,
+}
+
+pub fn listing(assembly: &Assembly) -> String {
+    let mut text = format!("{:<24} {:<5} {:<24} SOURCE\n", "LOCATION", "ADDR", "WORDS");
+    for entry in &assembly.listing {
+        let location = format!("{}:{}", entry.file, entry.line);
+        let address = match (entry.address, entry.data) {
+            (Some(address), false) => format!("{address:04X}"),
+            (Some(address), true) => format!("{address:04X}d"),
+            (None, _) => String::new(),
+        };
+        let _ = write!(
+            text,
+            "{location:<24} {address:<5} {:<24} {}",
+            hex(&entry.words),
+            entry.text
+        );
+        if !entry.resolved.is_empty() {
+            let resolved: Vec<String> = entry
+                .resolved
+                .iter()
+                .map(|(name, value)| format!("{name}={}", hex(value)))
+                .collect();
+            let _ = write!(text, "    ; {}", resolved.join(" "));
+        }
+        text.push('\n');
+    }
+
+    let _ = writeln!(text, "\n{:<24} {:<9} VALUE", "SYMBOL", "KIND");
+    let mut symbols: Vec<&Symbol> = assembly.symbols.iter().collect();
+    symbols.sort_by(|a, b| a.name.cmp(&b.name));
+    for symbol in symbols {
+        let kind = match symbol.kind {
+            SymbolKind::Label => "label",
+            SymbolKind::Data => "data",
+            SymbolKind::Constant => "constant",
+        };
+        let _ = writeln!(text, "{:<24} {kind:<9} {}", symbol.name, hex(&symbol.value));
+    }
+    text
+}
+
+fn hex(words: &[u16]) -> String {
+    let words: Vec<String> = words.iter().map(|word| format!("{word:04X}")).collect();
+    words.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::listing;
+    use crate::compiler::Layout;
+    use crate::testing::assemble_program;
+
+    #[test]
+    fn lists_lines_and_symbols() {
+        let program = "DEF LIMIT 3
+DEF LOOP
+    INC R1
+    JL LOOP R1 LIMIT // again
+DEF TABLE
+    .word 1 2";
+        let assembly = assemble_program(program, Layout::default()).unwrap();
+        let expected = "\
+LOCATION                 ADDR  WORDS                    SOURCE
+test.x1:1                      0003                     DEF LIMIT 3
+test.x1:2                0000                           DEF LOOP
+test.x1:3                0000  0025 0001                INC R1
+test.x1:4                0001  0032 0000 0001 0003      JL LOOP R1 LIMIT    ; LOOP=0000 LIMIT=0003
+test.x1:5                0040d                          DEF TABLE
+test.x1:6                0040d 0001 0002                .word 1 2
+
+SYMBOL                   KIND      VALUE
+LIMIT                    constant  0003
+LOOP                     label     0000
+TABLE                    data      0040
+";
+        assert_eq!(listing(&assembly), expected);
+    }
+}
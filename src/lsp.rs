@@ -0,0 +1,939 @@
+use crate::compiler::{Diagnostic, Layout, Severity, assemble, qualify, read_file, split_line};
+use crate::interpreter::register_name;
+use crate::json::Json;
+use crate::listing::{Symbol, SymbolKind};
+use crate::operation::{Operation, WORD_BITS, parse_literal};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+/*
+LANGUAGE SERVER
+
+eightbit lsp
+
+Speaks the Language Server Protocol over stdin and stdout. Every open
+document is assembled when it is opened or changed and the errors and
+warnings are published as diagnostics, problems in included files are shown
+on the first line. The server also answers
+
+textDocument/definition    where a label or DEF name is defined
+textDocument/references    every use of a label or DEF name
+textDocument/hover         the value of a name, the address of a register, the
+                           value of a number or the signature, opcode and
+                           description of a mnemonic
+textDocument/completion    mnemonics, registers, labels and DEF names, local
+                           labels also as .NAME inside their scope
+
+Documents are synchronized in full. Included files are read from disk.
+*/
+
+/// Runs the server on stdin and stdout until the client exits.
+pub fn run() {
+    let code =
+        serve(&mut io::stdin().lock(), &mut io::stdout()).expect("Error talking to the client.");
+    std::process::exit(code);
+}
+
+/// Answers the messages read from `input` on `output`. Returns the exit
+/// status: 0 if the client shut the server down before exiting.
+pub fn serve(input: &mut impl BufRead, output: &mut impl Write) -> io::Result<i32> {
+    let mut server = Server::default();
+    while let Some(message) = read_message(input)? {
+        let replies = match Json::parse(&message) {
+            Ok(message) => server.handle(&message),
+            Err(error) => vec![failure(Json::Null, -32700, error)],
+        };
+        for reply in replies {
+            write_message(output, &reply)?;
+        }
+        if server.exited {
+            return Ok(if server.shutdown { 0 } else { 1 });
+        }
+    }
+    Ok(1)
+}
+
+/// Longest message body the server reads, longer ones are an error.
+const MAX_MESSAGE_LENGTH: usize = 16 << 20;
+
+/// Body of the next `Content-Length` framed message, None at the end of
+/// `input`.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() && length.is_some() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':')
+            && name.eq_ignore_ascii_case("Content-Length")
+        {
+            length = value.trim().parse().ok();
+        }
+    }
+    let length = length.unwrap_or_default();
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Message of {length} bytes, the limit is {MAX_MESSAGE_LENGTH}"),
+        ));
+    }
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+}
+
+fn write_message(output: &mut impl Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    output.flush()
+}
+
+fn failure(id: Json, code: i32, message: String) -> Json {
+    Json::object(vec![
+        ("jsonrpc", "2.0".into()),
+        ("id", id),
+        (
+            "error",
+            Json::object(vec![
+                ("code", Json::Number(code as f64)),
+                ("message", message.into()),
+            ]),
+        ),
+    ])
+}
+
+/// A name written in a document or in a file it includes. Lines and columns
+/// count from 0, columns in UTF-16 code units like the protocol does.
+struct Occurrence {
+    path: PathBuf,
+    line: usize,
+    start: usize,
+    end: usize,
+    /// Name with local labels qualified by their scope.
+    name: String,
+    definition: bool,
+    label: bool,
+}
+
+struct Document {
+    path: PathBuf,
+    text: String,
+    occurrences: Vec<Occurrence>,
+    /// Symbols of the last assembly, empty if it failed.
+    symbols: Vec<Symbol>,
+}
+
+impl Document {
+    fn open(uri: &str, text: String) -> Document {
+        let path = uri_to_path(uri);
+        let mut occurrences = Vec::new();
+        scan(
+            &text,
+            &path,
+            &mut String::new(),
+            &mut occurrences,
+            &mut Vec::new(),
+        );
+        Document {
+            path,
+            text,
+            occurrences,
+            symbols: Vec::new(),
+        }
+    }
+
+    /// The name under the cursor.
+    fn at(&self, line: usize, character: usize) -> Option<&Occurrence> {
+        self.occurrences.iter().find(|occurrence| {
+            occurrence.path == self.path
+                && occurrence.line == line
+                && (occurrence.start..=occurrence.end).contains(&character)
+        })
+    }
+
+    fn definition(&self, name: &str) -> Option<&Occurrence> {
+        self.occurrences
+            .iter()
+            .find(|occurrence| occurrence.definition && occurrence.name == name)
+    }
+
+    /// Global label the cursor line belongs to.
+    fn scope(&self, line: usize) -> String {
+        self.occurrences
+            .iter()
+            .rfind(|occurrence| {
+                occurrence.path == self.path
+                    && occurrence.line <= line
+                    && occurrence.definition
+                    && occurrence.label
+                    && !occurrence.name.contains('.')
+            })
+            .map(|occurrence| occurrence.name.clone())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Default)]
+struct Server {
+    documents: HashMap<String, Document>,
+    shutdown: bool,
+    exited: bool,
+}
+
+impl Server {
+    /// Replies to `message`: a response for a request and diagnostics for
+    /// changed documents.
+    fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let Some(method) = message.get("method").as_str() else {
+            return Vec::new();
+        };
+        let params = message.get("params");
+        let uri = params
+            .get("textDocument")
+            .get("uri")
+            .as_str()
+            .unwrap_or_default();
+        let id = message.get("id").clone();
+        if id == Json::Null {
+            return match method {
+                "exit" => {
+                    self.exited = true;
+                    Vec::new()
+                }
+                "textDocument/didOpen" => {
+                    let text = params.get("textDocument").get("text").as_str();
+                    self.update(uri, text.unwrap_or_default().to_string())
+                }
+                "textDocument/didChange" => {
+                    let changes = params.get("contentChanges").as_array();
+                    match changes
+                        .last()
+                        .and_then(|change| change.get("text").as_str())
+                    {
+                        Some(text) => self.update(uri, text.to_string()),
+                        None => Vec::new(),
+                    }
+                }
+                "textDocument/didClose" => {
+                    self.documents.remove(uri);
+                    vec![publish(uri, Vec::new())]
+                }
+                _ => Vec::new(),
+            };
+        }
+
+        let result = match method {
+            _ if self.shutdown => {
+                return vec![failure(id, -32600, "The server is shut down".to_string())];
+            }
+            "initialize" => capabilities(),
+            "shutdown" => {
+                self.shutdown = true;
+                Json::Null
+            }
+            "textDocument/definition" => self.definition(params),
+            "textDocument/references" => self.references(params),
+            "textDocument/hover" => self.hover(params),
+            "textDocument/completion" => self.completion(params),
+            _ => return vec![failure(id, -32601, format!("Unknown method {method}"))],
+        };
+        vec![Json::object(vec![
+            ("jsonrpc", "2.0".into()),
+            ("id", id),
+            ("result", result),
+        ])]
+    }
+
+    /// Stores the new text of a document and assembles it.
+    fn update(&mut self, uri: &str, text: String) -> Vec<Json> {
+        let mut document = Document::open(uri, text);
+        let (symbols, diagnostics) = check(&document.text, &document.path);
+        document.symbols = symbols;
+        self.documents.insert(uri.to_string(), document);
+        vec![publish(uri, diagnostics)]
+    }
+
+    /// The open document of a request and the line and character of the
+    /// cursor.
+    fn position(&self, params: &Json) -> Option<(&Document, usize, usize)> {
+        let uri = params.get("textDocument").get("uri").as_str()?;
+        let position = params.get("position");
+        Some((
+            self.documents.get(uri)?,
+            position.get("line").as_usize()?,
+            position.get("character").as_usize()?,
+        ))
+    }
+
+    fn definition(&self, params: &Json) -> Json {
+        self.position(params)
+            .and_then(|(document, line, character)| {
+                let name = &document.at(line, character)?.name;
+                Some(location(document.definition(name)?))
+            })
+            .unwrap_or(Json::Null)
+    }
+
+    fn references(&self, params: &Json) -> Json {
+        let declaration = *params.get("context").get("includeDeclaration") == Json::Bool(true);
+        let Some((document, line, character)) = self.position(params) else {
+            return Json::Null;
+        };
+        let Some(name) = document
+            .at(line, character)
+            .map(|occurrence| &occurrence.name)
+        else {
+            return Json::Array(Vec::new());
+        };
+        Json::Array(
+            document
+                .occurrences
+                .iter()
+                .filter(|occurrence| {
+                    occurrence.name == *name && (declaration || !occurrence.definition)
+                })
+                .map(location)
+                .collect(),
+        )
+    }
+
+    fn hover(&self, params: &Json) -> Json {
+        let Some((document, line, character)) = self.position(params) else {
+            return Json::Null;
+        };
+        let source = document.text.split('\n').nth(line).unwrap_or_default();
+        let Some((start, word)) = word_at(source, byte_index(source, character)) else {
+            return Json::Null;
+        };
+        let first = source.len() - source.trim_start().len() == start;
+        let name = document
+            .at(line, character)
+            .and_then(|occurrence| document.definition(&occurrence.name));
+
+        let text = if let Some(definition) = name {
+            let symbol = document
+                .symbols
+                .iter()
+                .find(|symbol| symbol.name == definition.name);
+            describe_name(&definition.name, definition.label, symbol)
+        } else if let Some(op) = Operation::from_name(word).filter(|_| first) {
+            describe_operation(op)
+        } else if let Some(address) = crate::interpreter::register_address(word) {
+            format!("Register {} at 0x{address:03X}", word.to_uppercase())
+        } else if let Some(op) = Operation::from_name(word) {
+            describe_operation(op)
+        } else if let Ok(Some(value)) = parse_literal(word, WORD_BITS) {
+            format!("{value} = 0x{value:03X}")
+        } else {
+            return Json::Null;
+        };
+        let end = start + word.len();
+        Json::object(vec![
+            (
+                "contents",
+                Json::object(vec![("kind", "markdown".into()), ("value", text.into())]),
+            ),
+            (
+                "range",
+                range(line, utf16(&source[..start]), utf16(&source[..end])),
+            ),
+        ])
+    }
+
+    fn completion(&self, params: &Json) -> Json {
+        let item = |label: String, kind: usize, detail: String| {
+            Json::object(vec![
+                ("label", label.into()),
+                ("kind", kind.into()),
+                ("detail", detail.into()),
+            ])
+        };
+        let mut items = Vec::new();
+        for op in Operation::ALL {
+            items.push(item(op.name(), 14, signature(op)));
+        }
+        for address in 0..0x020 {
+            if let Some(name) = register_name(address) {
+                items.push(item(name, 6, format!("Register 0x{address:03X}")));
+            }
+        }
+        if let Some((document, line, _)) = self.position(params) {
+            let scope = document.scope(line);
+            let mut seen = HashSet::new();
+            for definition in document
+                .occurrences
+                .iter()
+                .filter(|occurrence| occurrence.definition)
+            {
+                if !seen.insert(&definition.name) {
+                    continue;
+                }
+                let (kind, detail) = match definition.label {
+                    true => (3, "Label"),
+                    false => (21, "Constant"),
+                };
+                items.push(item(definition.name.clone(), kind, detail.to_string()));
+                if let Some(local) = definition.name.strip_prefix(&scope)
+                    && !scope.is_empty()
+                    && local.starts_with('.')
+                {
+                    items.push(item(local.to_string(), kind, detail.to_string()));
+                }
+            }
+        }
+        Json::Array(items)
+    }
+}
+
+fn capabilities() -> Json {
+    Json::object(vec![
+        (
+            "capabilities",
+            Json::object(vec![
+                ("textDocumentSync", 1.into()),
+                ("definitionProvider", true.into()),
+                ("referencesProvider", true.into()),
+                ("hoverProvider", true.into()),
+                (
+                    "completionProvider",
+                    Json::object(vec![("triggerCharacters", Json::Array(vec![".".into()]))]),
+                ),
+            ]),
+        ),
+        (
+            "serverInfo",
+            Json::object(vec![
+                ("name", "eightbit".into()),
+                ("version", env!("CARGO_PKG_VERSION").into()),
+            ]),
+        ),
+    ])
+}
+
+fn publish(uri: &str, diagnostics: Vec<Json>) -> Json {
+    Json::object(vec![
+        ("jsonrpc", "2.0".into()),
+        ("method", "textDocument/publishDiagnostics".into()),
+        (
+            "params",
+            Json::object(vec![
+                ("uri", uri.into()),
+                ("diagnostics", Json::Array(diagnostics)),
+            ]),
+        ),
+    ])
+}
+
+/// Assembles `text`, read from `path`, and returns its symbols and the
+/// diagnostics of the assembler. Documents using EXTERN are objects.
+fn check(text: &str, path: &Path) -> (Vec<Symbol>, Vec<Json>) {
+    let object = text.split('\n').any(|line| {
+        split_line(line)
+            .0
+            .first()
+            .is_some_and(|word| word == "EXTERN")
+    });
+    let layout = Layout {
+        object,
+        ..Layout::default()
+    };
+    let file = path.display().to_string();
+    let diagnostic = |diagnostic: &Diagnostic| {
+        let mut message = diagnostic.message.clone();
+        for name in diagnostic.expansion.iter().rev() {
+            message.push_str(&format!(", in expansion of {name}"));
+        }
+        let line = match *diagnostic.file == *file {
+            true => diagnostic.line.saturating_sub(1),
+            false => {
+                message = format!("{}:{}: {message}", diagnostic.file, diagnostic.line);
+                0
+            }
+        };
+        let length = utf16(text.split('\n').nth(line).unwrap_or_default());
+        let severity = match diagnostic.severity {
+            Severity::Error => 1,
+            Severity::Warning => 2,
+        };
+        Json::object(vec![
+            ("range", range(line, 0, length)),
+            ("severity", severity.into()),
+            ("source", "eightbit".into()),
+            ("message", message.into()),
+        ])
+    };
+    match read_file(text.to_string(), path, &[]).and_then(|lines| assemble(lines, layout)) {
+        Ok(assembly) => (
+            assembly.symbols,
+            assembly.warnings.iter().map(diagnostic).collect(),
+        ),
+        Err(error) => (Vec::new(), vec![diagnostic(&error)]),
+    }
+}
+
+/// Records the names written in `text`, read from `path`, and in the files
+/// it includes. `scope` is the global label local labels belong to and
+/// `stack` holds the files being scanned, to stop include cycles.
+fn scan(
+    text: &str,
+    path: &Path,
+    scope: &mut String,
+    occurrences: &mut Vec<Occurrence>,
+    stack: &mut Vec<PathBuf>,
+) {
+    stack.push(path.to_path_buf());
+    for (number, source) in text.split('\n').enumerate() {
+        let (words, comment) = split_line(source);
+        let Some(first) = words.first() else {
+            continue;
+        };
+        if first == "INCLUDE" {
+            let name = words[1..].join(" ");
+            let included = path
+                .parent()
+                .unwrap_or(Path::new("."))
+                .join(name.trim_matches('"'));
+            if !stack.contains(&included)
+                && let Ok(text) = fs::read_to_string(&included)
+            {
+                scan(&text, &included, scope, occurrences, stack);
+            }
+            continue;
+        }
+        let defines = Operation::from_name(first) == Some(Operation::DEF) && words.len() > 1;
+        let label = defines && words.len() == 2;
+        if label && !words[1].starts_with('.') {
+            *scope = words[1].clone();
+        }
+        let code = &source[..source.len() - comment.map_or(0, str::len)];
+        for (index, (start, name)) in identifiers(code).into_iter().enumerate() {
+            if index == 0 && (name.starts_with('.') || Operation::from_name(name).is_some()) {
+                continue;
+            }
+            let definition = defines && index == 1;
+            occurrences.push(Occurrence {
+                path: path.to_path_buf(),
+                line: number,
+                start: utf16(&source[..start]),
+                end: utf16(&source[..start + name.len()]),
+                name: qualify(name, scope),
+                definition,
+                label: definition && label,
+            });
+        }
+    }
+    stack.pop();
+}
+
+fn is_name(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_.@".contains(c)
+}
+
+/// Names in `code`, with their byte offsets. Text inside quotes and numbers
+/// are skipped.
+fn identifiers(code: &str) -> Vec<(usize, &str)> {
+    let mut found = Vec::new();
+    let mut quote = None;
+    let mut escaped = false;
+    let mut start = None;
+    for (index, character) in code.char_indices().chain([(code.len(), ' ')]) {
+        if let Some(open) = quote {
+            if escaped {
+                escaped = false;
+            } else if character == '\\' {
+                escaped = true;
+            } else if character == open {
+                quote = None;
+            }
+            continue;
+        }
+        if is_name(character) {
+            start.get_or_insert(index);
+            continue;
+        }
+        if let Some(begin) = start.take()
+            && !code[begin..].starts_with(|c: char| c.is_ascii_digit())
+        {
+            found.push((begin, &code[begin..index]));
+        }
+        if matches!(character, '\'' | '"') {
+            quote = Some(character);
+        }
+    }
+    found
+}
+
+/// The word around byte `index` of `line`, with its byte offset.
+fn word_at(line: &str, index: usize) -> Option<(usize, &str)> {
+    let start = line[..index]
+        .rfind(|c: char| !is_name(c))
+        .map_or(0, |start| start + 1);
+    let end = line[index..]
+        .find(|c: char| !is_name(c))
+        .map_or(line.len(), |end| index + end);
+    (start < end).then(|| (start, &line[start..end]))
+}
+
+fn utf16(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/// Byte offset of the UTF-16 column `character` in `line`.
+fn byte_index(line: &str, character: usize) -> usize {
+    let mut units = 0;
+    for (index, c) in line.char_indices() {
+        if units >= character {
+            return index;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+fn range(line: usize, start: usize, end: usize) -> Json {
+    let position = |character: usize| {
+        Json::object(vec![("line", line.into()), ("character", character.into())])
+    };
+    Json::object(vec![("start", position(start)), ("end", position(end))])
+}
+
+fn location(occurrence: &Occurrence) -> Json {
+    Json::object(vec![
+        ("uri", path_to_uri(&occurrence.path).into()),
+        (
+            "range",
+            range(occurrence.line, occurrence.start, occurrence.end),
+        ),
+    ])
+}
+
+/// `ADD SRC DEST`
+fn signature(op: Operation) -> String {
+    let mut words = vec![op.name()];
+    words.extend(op.signature().iter().map(|(name, _)| name.to_string()));
+    words.join(" ")
+}
+
+fn describe_operation(op: Operation) -> String {
+    format!(
+        "```\n{}\n```\nOpcode 0x{:03X}. {}",
+        signature(op),
+        op.opcode(),
+        op.description()
+    )
+}
+
+fn describe_name(name: &str, label: bool, symbol: Option<&Symbol>) -> String {
+    let kind = match symbol.map(|symbol| symbol.kind) {
+        Some(SymbolKind::Label) => "label",
+        Some(SymbolKind::Data) => "data label",
+        Some(SymbolKind::Constant) => "constant",
+        None if label => "label",
+        None => "constant",
+    };
+    let mut text = format!("`{name}` {kind}");
+    if let Some(symbol) = symbol {
+        let words: Vec<String> = symbol
+            .value
+            .iter()
+            .map(|word| format!("0x{word:03X}"))
+            .collect();
+        text.push_str(&format!(" = {}", words.join(" ")));
+        if let [value] = symbol.value.as_slice() {
+            text.push_str(&format!(" ({value})"));
+        }
+    }
+    text
+}
+
+/// Path of a `file://` URI.
+fn uri_to_path(uri: &str) -> PathBuf {
+    let Some(path) = uri.strip_prefix("file://") else {
+        return PathBuf::from(uri);
+    };
+    let mut bytes = Vec::new();
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = tail
+            .get(..2)
+            .filter(|_| byte == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(escaped) => {
+                bytes.push(escaped);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let mut uri = "file://".to_string();
+    for byte in path.display().to_string().bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            uri.push(byte as char);
+        } else {
+            uri.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    uri
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "file:///tmp/eightbit%20lsp/main.x1";
+
+    const PROGRAM: &str = "DEF SIZE 0x10
+DEF MAIN
+    IMM SIZE R1
+    CALL LOOP
+    HLT 0
+DEF LOOP
+DEF .again
+    DEC R1
+    JNZ .again R1
+    RET";
+
+    /// Runs a session of `messages` and returns the messages of the server.
+    fn session(messages: &[&str]) -> (i32, Vec<Json>) {
+        let mut input = Vec::new();
+        for message in messages {
+            write!(input, "Content-Length: {}\r\n\r\n{message}", message.len()).unwrap();
+        }
+        let mut output = Vec::new();
+        let code = serve(&mut input.as_slice(), &mut output).unwrap();
+        let mut replies = Vec::new();
+        let mut reader = output.as_slice();
+        while let Some(body) = read_message(&mut reader).unwrap() {
+            replies.push(Json::parse(&body).unwrap());
+        }
+        (code, replies)
+    }
+
+    fn open(text: &str) -> String {
+        format!(
+            r#"{{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{{"textDocument":{{"uri":"{URI}","languageId":"x1","version":1,"text":{}}}}}}}"#,
+            Json::from(text)
+        )
+    }
+
+    fn request(id: usize, method: &str, line: usize, character: usize) -> String {
+        format!(
+            r#"{{"jsonrpc":"2.0","id":{id},"method":"{method}","params":{{"textDocument":{{"uri":"{URI}"}},"position":{{"line":{line},"character":{character}}},"context":{{"includeDeclaration":true}}}}}}"#
+        )
+    }
+
+    /// Result of the request with `id` in `replies`.
+    fn result(replies: &[Json], id: usize) -> &Json {
+        replies
+            .iter()
+            .find(|reply| *reply.get("id") == Json::from(id))
+            .map(|reply| reply.get("result"))
+            .unwrap()
+    }
+
+    fn lines(locations: &Json) -> Vec<usize> {
+        locations
+            .as_array()
+            .iter()
+            .map(|location| {
+                location
+                    .get("range")
+                    .get("start")
+                    .get("line")
+                    .as_usize()
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn initialize_and_shutdown() {
+        let (code, replies) = session(&[
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"capabilities":{}}}"#,
+            r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#,
+            r#"{"jsonrpc":"2.0","id":2,"method":"workspace/symbol","params":{}}"#,
+            r#"{"jsonrpc":"2.0","id":3,"method":"shutdown"}"#,
+            r#"{"jsonrpc":"2.0","method":"exit"}"#,
+        ]);
+        assert_eq!(code, 0);
+        assert_eq!(replies.len(), 3);
+        let capabilities = result(&replies, 1).get("capabilities");
+        assert_eq!(*capabilities.get("textDocumentSync"), Json::from(1));
+        assert_eq!(*capabilities.get("hoverProvider"), Json::Bool(true));
+        assert_eq!(*replies[1].get("error").get("code"), Json::Number(-32601.0));
+        assert_eq!(*result(&replies, 3), Json::Null);
+    }
+
+    #[test]
+    fn oversized_messages_are_rejected() {
+        let oversized = format!("Content-Length: {}\r\n\r\n{{}}", MAX_MESSAGE_LENGTH + 1);
+        let error = serve(&mut oversized.as_bytes(), &mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let message = "Content-Length: 2\r\n\r\n{}";
+        assert_eq!(
+            read_message(&mut message.as_bytes()).unwrap(),
+            Some("{}".to_string())
+        );
+    }
+
+    #[test]
+    fn exit_without_shutdown() {
+        let (code, replies) = session(&[r#"{"jsonrpc":"2.0","method":"exit"}"#]);
+        assert_eq!(code, 1);
+        assert!(replies.is_empty());
+    }
+
+    #[test]
+    fn publishes_diagnostics() {
+        let change = format!(
+            r#"{{"jsonrpc":"2.0","method":"textDocument/didChange","params":{{"textDocument":{{"uri":"{URI}","version":2}},"contentChanges":[{{"text":"IMM 1 R1\nHLT 0"}}]}}}}"#
+        );
+        let (_, replies) = session(&[&open("IMM 1 R1\nJMP NOWHERE\nHLT 0"), &change]);
+        assert_eq!(replies.len(), 2);
+        let params = replies[0].get("params");
+        assert_eq!(params.get("uri").as_str(), Some(URI));
+        let diagnostic = &params.get("diagnostics").as_array()[0];
+        assert_eq!(
+            diagnostic.get("message").as_str(),
+            Some("Undefined symbol NOWHERE")
+        );
+        assert_eq!(*diagnostic.get("severity"), Json::from(1));
+        assert_eq!(
+            diagnostic.get("range").to_string(),
+            r#"{"start":{"line":1,"character":0},"end":{"line":1,"character":11}}"#
+        );
+        let diagnostics = replies[1].get("params").get("diagnostics");
+        assert_eq!(*diagnostics, Json::Array(Vec::new()));
+    }
+
+    #[test]
+    fn publishes_warnings() {
+        let (_, replies) = session(&[&open("HLT 0\nDEF UNUSED\nNOP\nHLT 0")]);
+        let diagnostics = replies[0].get("params").get("diagnostics").as_array();
+        assert!(!diagnostics.is_empty());
+        assert!(
+            diagnostics
+                .iter()
+                .all(|diagnostic| *diagnostic.get("severity") == Json::from(2))
+        );
+    }
+
+    #[test]
+    fn goes_to_definitions() {
+        let (_, replies) = session(&[
+            &open(PROGRAM),
+            &request(1, "textDocument/definition", 2, 9),
+            &request(2, "textDocument/definition", 8, 9),
+            &request(3, "textDocument/definition", 3, 1),
+        ]);
+        let size = result(&replies, 1);
+        assert_eq!(size.get("uri").as_str(), Some(URI));
+        assert_eq!(
+            size.get("range").to_string(),
+            r#"{"start":{"line":0,"character":4},"end":{"line":0,"character":8}}"#
+        );
+        assert_eq!(lines(&Json::Array(vec![result(&replies, 2).clone()])), [6]);
+        assert_eq!(*result(&replies, 3), Json::Null);
+    }
+
+    #[test]
+    fn finds_references() {
+        let without = request(2, "textDocument/references", 5, 5).replace(
+            "\"includeDeclaration\":true",
+            "\"includeDeclaration\":false",
+        );
+        let (_, replies) = session(&[
+            &open(PROGRAM),
+            &request(1, "textDocument/references", 5, 5),
+            &without,
+            &request(3, "textDocument/references", 6, 6),
+        ]);
+        assert_eq!(lines(result(&replies, 1)), [3, 5]);
+        assert_eq!(lines(result(&replies, 2)), [3]);
+        assert_eq!(lines(result(&replies, 3)), [6, 8]);
+    }
+
+    #[test]
+    fn hovers() {
+        let program = "DEF SIZE 0x10\nIMM SIZE R1\nADD R1 R2\nIMM 0x2A R3\nHLT 0";
+        let (_, replies) = session(&[
+            &open(program),
+            &request(1, "textDocument/hover", 2, 1),
+            &request(2, "textDocument/hover", 1, 6),
+            &request(3, "textDocument/hover", 2, 5),
+            &request(4, "textDocument/hover", 3, 6),
+            &request(5, "textDocument/hover", 4, 5),
+        ]);
+        let text = |id| {
+            result(&replies, id)
+                .get("contents")
+                .get("value")
+                .as_str()
+                .unwrap()
+                .to_string()
+        };
+        assert!(text(1).contains("ADD SRC DEST"));
+        assert!(text(1).contains("0x023"));
+        assert!(text(1).contains(Operation::ADD.description()));
+        assert_eq!(text(2), "`SIZE` constant = 0x010 (16)");
+        assert_eq!(text(3), "Register R1 at 0x001");
+        assert_eq!(text(4), "42 = 0x02A");
+        assert_eq!(
+            result(&replies, 2).get("range").to_string(),
+            r#"{"start":{"line":1,"character":4},"end":{"line":1,"character":8}}"#
+        );
+        assert_eq!(text(5), "0 = 0x000");
+    }
+
+    #[test]
+    fn completes() {
+        let (_, replies) = session(&[&open(PROGRAM), &request(1, "textDocument/completion", 8, 4)]);
+        let labels: Vec<&str> = result(&replies, 1)
+            .as_array()
+            .iter()
+            .map(|item| item.get("label").as_str().unwrap())
+            .collect();
+        for label in [
+            "ADD",
+            "CALLR",
+            "R15",
+            "SP",
+            "PC",
+            "SIZE",
+            "MAIN",
+            "LOOP.again",
+            ".again",
+        ] {
+            assert!(labels.contains(&label), "{label} missing");
+        }
+    }
+
+    #[test]
+    fn converts_uris() {
+        let path = uri_to_path(URI);
+        assert_eq!(path, Path::new("/tmp/eightbit lsp/main.x1"));
+        assert_eq!(path_to_uri(&path), URI);
+    }
+
+    #[test]
+    fn parse_error() {
+        let (_, replies) = session(&["{\"jsonrpc\":"]);
+        assert_eq!(*replies[0].get("error").get("code"), Json::Number(-32700.0));
+    }
+}
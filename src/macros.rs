@@ -0,0 +1,217 @@
+use crate::compiler::{Diagnostic, Line};
+use crate::expr::{is_expression, rename};
+use std::collections::HashMap;
+
+/*
+MACROS
+
+MACRO NAME ARG1 ARG2 ...
+    BODY
+ENDM
+
+A line starting with NAME and followed by one word per ARG is replaced with
+BODY, with every ARG word substituted by the matching word of the call, also
+where it is a name in an expression like (ARG1 + 1). Labels defined inside
+BODY (DEF LABEL) are renamed for every expansion, in expressions as well, so a
+macro can be used more than once. Macros may call other macros, but not
+themselves.
+*/
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Line>,
+}
+
+pub fn expand_macros(lines: Vec<Line>) -> Result<Vec<Line>, Diagnostic> {
+    let mut macros: HashMap<String, Macro> = HashMap::new();
+    let mut program = Vec::new();
+
+    let mut lines = lines.into_iter();
+    while let Some(line) = lines.next() {
+        match line.words.first().map(String::as_str) {
+            Some("MACRO") => {
+                let name = line
+                    .words
+                    .get(1)
+                    .ok_or_else(|| Diagnostic::error(&line, "MACRO without name".to_string()))?
+                    .clone();
+                if macros.contains_key(&name) {
+                    return Err(Diagnostic::error(
+                        &line,
+                        format!("Macro {name} is already defined"),
+                    ));
+                }
+
+                let mut body = Vec::new();
+                loop {
+                    let body_line = lines.next().ok_or_else(|| {
+                        Diagnostic::error(&line, format!("MACRO {name} without ENDM"))
+                    })?;
+                    match body_line.words.first().map(String::as_str) {
+                        Some("ENDM") => break,
+                        Some("MACRO") => {
+                            return Err(Diagnostic::error(
+                                &body_line,
+                                format!("MACRO inside the definition of {name}"),
+                            ));
+                        }
+                        _ => body.push(body_line),
+                    }
+                }
+                macros.insert(
+                    name,
+                    Macro {
+                        params: line.words[2..].to_vec(),
+                        body,
+                    },
+                );
+            }
+            Some("ENDM") => {
+                return Err(Diagnostic::error(&line, "ENDM without MACRO".to_string()));
+            }
+            _ => program.push(line),
+        }
+    }
+
+    let mut expanded = Vec::new();
+    let mut expansions = 0;
+    for line in program {
+        expand(line, &macros, &mut expansions, &mut expanded)?;
+    }
+    Ok(expanded)
+}
+
+fn expand(
+    line: Line,
+    macros: &HashMap<String, Macro>,
+    expansions: &mut usize,
+    expanded: &mut Vec<Line>,
+) -> Result<(), Diagnostic> {
+    let Some((name, definition)) = line
+        .words
+        .first()
+        .and_then(|word| macros.get_key_value(word))
+    else {
+        expanded.push(line);
+        return Ok(());
+    };
+
+    if line.expansion.contains(name) {
+        return Err(Diagnostic::error(
+            &line,
+            format!("Macro {name} expands itself"),
+        ));
+    }
+    let args = &line.words[1..];
+    if args.len() != definition.params.len() {
+        return Err(Diagnostic::error(
+            &line,
+            format!(
+                "Macro {name} takes {} arguments, {} given",
+                definition.params.len(),
+                args.len()
+            ),
+        ));
+    }
+
+    *expansions += 1;
+    let mut substitutions: HashMap<&str, String> = definition
+        .params
+        .iter()
+        .map(String::as_str)
+        .zip(args.iter().cloned())
+        .collect();
+    for body_line in definition.body.iter() {
+        if let [def, label] = body_line.words.as_slice()
+            && def.eq_ignore_ascii_case("DEF")
+        {
+            substitutions.insert(label, format!("{label}@{expansions}"));
+        }
+    }
+
+    let mut expansion = line.expansion.clone();
+    expansion.push(name.clone());
+    for body_line in definition.body.iter() {
+        let words = body_line
+            .words
+            .iter()
+            .map(|word| match substitutions.get(word.as_str()) {
+                Some(substitution) => substitution.clone(),
+                None if is_expression(word) => {
+                    rename(word, |name| substitutions.get(name).cloned())
+                }
+                None => word.clone(),
+            })
+            .collect();
+        expand(
+            Line {
+                file: line.file.clone(),
+                line: line.line,
+                column: line.column,
+                words,
+                expansion: expansion.clone(),
+                includes: body_line.includes.clone(),
+            },
+            macros,
+            expansions,
+            expanded,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::Layout;
+    use crate::testing::{assemble_program, registers};
+
+    /// R1 to R3 after running `program`.
+    fn run(program: &str) -> Result<[u16; 3], String> {
+        registers(assemble_program(program, Layout::default()))
+    }
+
+    #[test]
+    fn parameters_in_expressions() {
+        let program = "
+            MACRO LOAD VAL REG
+                IMM (VAL + 1) REG
+            ENDM
+            LOAD 4 R1
+            LOAD (2 * 3) R2
+            LOAD 'A' R3
+        ";
+        assert_eq!(run(program), Ok([5, 7, 66]));
+    }
+
+    #[test]
+    fn local_labels_in_expressions() {
+        let program = "
+            MACRO SKIP REG
+                JMP (L + 0)
+                IMM 9 REG
+            DEF L
+            ENDM
+            SKIP R1
+            SKIP R2
+            IMM 1 R3
+        ";
+        assert_eq!(run(program), Ok([0, 0, 1]));
+    }
+
+    #[test]
+    fn arguments_are_checked() {
+        let program = "
+            MACRO LOAD VAL REG
+                IMM VAL REG
+            ENDM
+            LOAD 1
+        ";
+        let error = run(program).unwrap_err();
+        assert!(
+            error.contains("Macro LOAD takes 2 arguments, 1 given"),
+            "{error}"
+        );
+        let error = run("MACRO SELF\nSELF\nENDM\nSELF").unwrap_err();
+        assert!(error.contains("Macro SELF expands itself"), "{error}");
+    }
+}
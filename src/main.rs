@@ -1,39 +1,283 @@
 use std::env;
 use std::fs;
 use std::fs::read_to_string;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
 
-pub mod compiler;
-pub mod operation;
-pub mod interpreter;
+use eightbit::{
+    codegen, compiler, coredump, debugger, formatter, hexdump, interpreter, lint, listing, lsp,
+    object, operation, peephole,
+};
 
-use compiler::compile;
-use interpreter::run_raw;
-use operation::format_radix;
+use compiler::{Assembly, Layout, assemble, read_file};
+use interpreter::{MEMORY_SIZE, Machine, PROGRAM_MEMORY_START, State};
+use operation::{format_radix, parse_number};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
+    // every subcommand accepts --listing PATH
+    let listing = option(&args, "--listing");
+    // and --pic for position-independent code
+    let layout = Layout {
+        pic: args.iter().any(|arg| arg == "--pic"),
+        ..Layout::default()
+    };
+    // and -D NAME=VALUE definitions for conditional assembly
+    let defines = defines(&args);
+    // and -O for the peephole optimizer
+    let optimize = args.iter().any(|arg| arg == "-O");
+    // and --strip to drop unreachable instructions
+    let strip = args.iter().any(|arg| arg == "--strip");
+
+    match args.get(1).map(String::as_str) {
+        // eightbit debug program.x1 [--history N]
+        Some("debug") => {
+            let history = option(&args, "--history")
+                .map(|size| size.parse().expect("History size is not a number."))
+                .unwrap_or(debugger::DEFAULT_HISTORY);
+            debugger::debug(boot(
+                load(args.get(2), listing, layout, &defines, optimize, strip),
+                history,
+            ));
+        }
+        // eightbit hexdump program.x1 [START [END]]
+        Some("hexdump") => {
+            let mut machine = boot(
+                load(args.get(2), listing, layout, &defines, optimize, strip),
+                0,
+            );
+            machine.run();
+            let bound = |index: usize| {
+                args.get(index).map(|word| {
+                    parse_number(word).unwrap_or_else(|| panic!("Invalid address {word}")) as usize
+                })
+            };
+            let range = bound(3).unwrap_or(0)..=bound(4).unwrap_or(MEMORY_SIZE - 1);
+            print!(
+                "{}",
+                hexdump::hexdump(&machine.memory, range, &[], io::stdout().is_terminal())
+            );
+        }
+        // eightbit fmt program.x1 ... [--check]
+        Some("fmt") => {
+            let check = args.iter().any(|arg| arg == "--check");
+            let paths = args[2..].iter().filter(|arg| !arg.starts_with('-'));
+            let unformatted = formatter::format_files(paths.map(Path::new), check)
+                .expect("Error formatting files.");
+            if check && !unformatted.is_empty() {
+                for path in unformatted {
+                    println!("{}", path.display());
+                }
+                std::process::exit(1);
+            }
+        }
+        // eightbit lint program.x1 [--json] or eightbit lint --rules
+        Some("lint") => {
+            if args.iter().any(|arg| arg == "--rules") {
+                for (rule, description) in lint::RULES {
+                    println!("{rule:<20} {description}");
+                }
+                return;
+            }
+            let path = args.get(2).expect("No file given to lint.");
+            let program = read_to_string(path).expect("Error reading file.");
+            let findings =
+                lint::lint_file(program, Path::new(path)).unwrap_or_else(|error| panic!("{error}"));
+            if args.iter().any(|arg| arg == "--json") {
+                print!("{}", lint::to_json(&findings));
+            } else {
+                print!("{}", lint::to_text(&findings));
+            }
+            if !findings.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        // eightbit compile program.x1h [-o program.x1]
+        Some("compile") => {
+            let path = args.get(2).expect("No file given to compile.");
+            let program = read_to_string(path).expect("Error reading file.");
+            let assembly = codegen::assembly(&program, Path::new(path))
+                .unwrap_or_else(|error| panic!("{error}"));
+            let output = option(&args, "-o")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| Path::new(path).with_extension("x1"));
+            fs::write(output, assembly).expect("Error writing assembly.");
+        }
+        // eightbit lsp
+        Some("lsp") => lsp::run(),
+        // eightbit object program.x1 [-o program.x1o] [--pic] [-D NAME=VALUE] [-O]
+        Some("object") => {
+            let path = args.get(2).expect("No file given to assemble.");
+            let program = read_to_string(path).expect("Error reading file.");
+            let (mut object, warnings) =
+                object::assemble_object(program, Path::new(path), layout.pic, &defines)
+                    .unwrap_or_else(|error| panic!("{error}"));
+            for warning in warnings.iter() {
+                eprintln!("{warning}");
+            }
+            if optimize {
+                peephole::optimize(&mut object);
+            }
+            let output = option(&args, "-o")
+                .map(String::from)
+                .unwrap_or_else(|| format!("{path}o"));
+            fs::write(&output, object.to_text()).expect("Error writing object.");
+        }
+        // eightbit link a.x1o b.x1o ... [-o linked.x1o] [--entry NAME]
+        Some("link") => {
+            let mut objects = Vec::new();
+            let mut words = args[2..].iter();
+            while let Some(word) = words.next() {
+                match word.as_str() {
+                    // options followed by a value
+                    "-o" | "--entry" | "--listing" | "-D" => {
+                        words.next();
+                        continue;
+                    }
+                    flag if flag.starts_with('-') => continue,
+                    _ => {}
+                }
+                let text = read_to_string(word).expect("Error reading object.");
+                let object =
+                    object::Object::parse(&text).unwrap_or_else(|error| panic!("{word}: {error}"));
+                objects.push((word.clone(), object));
+            }
+            let linked = object::link(&objects, option(&args, "--entry"))
+                .unwrap_or_else(|error| panic!("{error}"));
+            let output = option(&args, "-o").unwrap_or("linked.x1o");
+            fs::write(output, linked.to_text()).expect("Error writing object.");
+        }
+        // eightbit program.x1 (or .x1h) [--core-dump PATH] [--trace] [--pic] [-D NAME=VALUE] [-O] [--strip]
+        _ => run(
+            load(args.get(1), listing, layout, &defines, optimize, strip),
+            option(&args, "--core-dump"),
+            args.iter().any(|arg| arg == "--trace"),
+        ),
+    }
+}
 
-    let path = args
-        .get(1)
+fn run(assembly: Assembly, core_file: Option<&str>, trace: bool) {
+    let mut machine = boot(assembly, 0);
+    machine.trace = trace;
+    let state = machine.run();
+    machine.core_dump();
+
+    if let State::Faulted(fault) = state {
+        let path = core_file.unwrap_or(coredump::DEFAULT_CORE_FILE);
+        machine
+            .dump()
+            .write(path)
+            .expect("Error writing core dump.");
+        panic!(
+            "{fault} at {}, core dumped to {path}",
+            machine.source_map.describe(machine.pc())
+        );
+    }
+    if let Some(path) = core_file {
+        machine
+            .dump()
+            .write(path)
+            .expect("Error writing core dump.");
+    }
+}
+
+/// Creates a machine for `assembly` with its data loaded into memory.
+fn boot(assembly: Assembly, history_size: usize) -> Machine {
+    let mut machine = Machine::with_history(assembly.bytecode, history_size);
+    machine.source_map = assembly.source_map;
+    machine.load(PROGRAM_MEMORY_START, &assembly.data);
+    machine
+}
+
+fn load(
+    path: Option<&String>,
+    listing: Option<&str>,
+    layout: Layout,
+    defines: &[String],
+    optimize: bool,
+    strip: bool,
+) -> Assembly {
+    let path = path
         .unwrap_or_else(|| panic!("No file given to debug."))
         .clone();
-    if !path.ends_with(".x1") {
+    if path.ends_with(".x1o") {
+        let text = read_to_string(&path).expect("Error reading file.");
+        return object::Object::parse(&text)
+            .and_then(object::Object::into_assembly)
+            .unwrap_or_else(|error| panic!("{path}: {error}"));
+    }
+    if !path.ends_with(".x1") && !path.ends_with(".x1h") {
         panic!("File is not an x1 program!");
     };
 
-    let program = read_to_string(path).expect("Error reading file.");
-    let bytecode = compile(program);
+    let program = read_to_string(&path).expect("Error reading file.");
+    // programs in the high-level language are translated to assembly first
+    let lines = if path.ends_with(".x1h") {
+        codegen::translate(&program, Path::new(&path))
+    } else {
+        read_file(program, Path::new(&path), defines)
+    }
+    .unwrap_or_else(|error| panic!("{error}"));
+    let mut assembly = assemble(lines.clone(), layout).unwrap_or_else(|error| panic!("{error}"));
+    for warning in assembly.warnings.iter() {
+        eprintln!("{warning}");
+    }
+    if (optimize || strip)
+        && let Err(error) = peephole::shrink(
+            &mut assembly,
+            lines,
+            Path::new(&path),
+            layout.pic,
+            strip,
+            optimize,
+        )
+    {
+        eprintln!("{error}, the program is left as written");
+    }
 
-    let mut content_string = String::new();
-    for line in bytecode.iter() {
-        let contents = line
+    let hex = |words: &[u16]| {
+        words
             .iter()
             .map(|x| "0x".to_string() + &format_radix(*x as u32, 16))
             .collect::<Vec<String>>()
-            .join(" ");
-        content_string.push_str(&format!("{}\n", contents));
+            .join(" ")
+    };
+    let mut content_string = String::new();
+    for line in assembly.bytecode.iter() {
+        content_string.push_str(&format!("{}\n", hex(line)));
+    }
+    if !assembly.data.is_empty() {
+        content_string.push_str(&format!(
+            ".data 0x{:X} {}\n",
+            PROGRAM_MEMORY_START,
+            hex(&assembly.data)
+        ));
     }
     fs::write("compiled.txt", &content_string).unwrap();
+    if let Some(listing_path) = listing {
+        fs::write(listing_path, listing::listing(&assembly)).expect("Error writing listing.");
+    }
+
+    assembly
+}
 
-    run_raw(bytecode);
+fn option<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|index| args.get(index + 1))
+        .map(String::as_str)
+}
+
+/// Every `-D NAME=VALUE` or `-DNAME=VALUE` argument.
+fn defines(args: &[String]) -> Vec<String> {
+    let mut defines = Vec::new();
+    let mut words = args.iter();
+    while let Some(word) = words.next() {
+        match word.strip_prefix("-D") {
+            Some("") => defines.extend(words.next().cloned()),
+            Some(define) => defines.push(define.to_string()),
+            None => {}
+        }
+    }
+    defines
 }
@@ -0,0 +1,618 @@
+use crate::compiler::{Assembly, Diagnostic, Layout, Line, assemble, read_file};
+use crate::interpreter::{PROGRAM_MEMORY_END, PROGRAM_MEMORY_START};
+use crate::listing::{Symbol, SymbolKind};
+use crate::operation::{OperandKind, Operation, parse_number};
+use crate::sourcemap::{Location, SourceMap};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::rc::Rc;
+
+/*
+OBJECTS AND LINKING
+
+EXPORT NAME ...     makes labels and constants of this file visible to other objects
+EXTERN NAME ...     declares a symbol exported by another object
+
+`eightbit object lib.x1` assembles lib.x1 into the object lib.x1o. Code of an
+object starts at instruction 0 and its data at PROGRAM_MEMORY_START, the
+relocation records list every word holding a code or data address so the
+linker can move them with the object. EXTERN symbols may only be used as
+instruction operands, the linker computes the offsets of relative jumps and
+calls to them.
+
+`eightbit link main.x1o lib.x1o [-o app.x1o] [--entry START]` places the
+objects one after another, resolves every EXTERN against the exported
+symbols and, with --entry, starts the program with a jump to that symbol.
+The result is an object again and can be run like a program.
+
+Object files are text, one record per line:
+
+X1OBJ
+export NAME code|data|abs VALUE
+code WORD ...                                   one line per instruction
+source LINE COLUMN LABEL|- LABEL_ADDRESS FILE   location of the code line before
+data WORD ...
+reloc code INSTRUCTION WORD code|data
+reloc data INDEX code|data
+ref INSTRUCTION WORD NAME                       operand naming an EXTERN symbol
+*/
+
+const MAGIC: &str = "X1OBJ";
+
+/// What an address is relative to. Absolute values never move.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Section {
+    Code,
+    Data,
+    Absolute,
+}
+
+/// A word of an object: (instruction, word) in the code or an index into the
+/// data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Place {
+    Code(usize, usize),
+    Data(usize),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Relocation {
+    pub place: Place,
+    pub section: Section,
+}
+
+#[derive(Default)]
+pub struct Object {
+    pub code: Vec<Vec<u16>>,
+    pub data: Vec<u16>,
+    pub source_map: SourceMap,
+    pub exports: Vec<(String, Section, u16)>,
+    pub relocations: Vec<Relocation>,
+    /// Operands naming an EXTERN symbol, as (instruction, word, name).
+    pub references: Vec<(usize, usize, String)>,
+}
+
+/// Assembles `program`, read from `path`, into an object, position-independent
+/// if `pic` is set, with the command line `defines`. Returns the object and the warnings of the assembler.
+pub fn assemble_object(
+    program: String,
+    path: &Path,
+    pic: bool,
+    defines: &[String],
+) -> Result<(Object, Vec<Diagnostic>), Diagnostic> {
+    assemble_lines(read_file(program, path, defines)?, path, pic)
+}
+
+/// Assembles `lines`, read from `path`, into an object, see assemble_object.
+pub fn assemble_lines(
+    lines: Vec<Line>,
+    path: &Path,
+    pic: bool,
+) -> Result<(Object, Vec<Diagnostic>), Diagnostic> {
+    let layout = Layout {
+        object: true,
+        pic,
+        ..Layout::default()
+    };
+    // Words holding an address move with the base of their section.
+    let code_moved = assemble(lines.clone(), Layout { code: 1, ..layout })?;
+    let data_moved = assemble(lines.clone(), Layout { data: 1, ..layout })?;
+    let assembly = assemble(lines, layout)?;
+    let file: Rc<str> = Rc::from(path.display().to_string());
+
+    let mut relocations = Vec::new();
+    for (index, row) in assembly.bytecode.iter().enumerate() {
+        for (word, value) in row.iter().enumerate() {
+            let section = section(
+                *value,
+                code_moved.bytecode[index][word],
+                data_moved.bytecode[index][word],
+            );
+            let location = &assembly.source_map.locations[index];
+            match section {
+                Some(Section::Absolute) => {}
+                Some(section) => relocations.push(Relocation {
+                    place: Place::Code(index, word),
+                    section,
+                }),
+                None => return Err(unrelocatable(location.file.clone(), location.line)),
+            }
+        }
+    }
+    for (index, value) in assembly.data.iter().enumerate() {
+        match section(*value, code_moved.data[index], data_moved.data[index]) {
+            Some(Section::Absolute) => {}
+            Some(section) => relocations.push(Relocation {
+                place: Place::Data(index),
+                section,
+            }),
+            None => {
+                let (file, line) = assembly
+                    .data_entry((PROGRAM_MEMORY_START + index) as u16)
+                    .map_or((file, 0), |entry| (entry.file.clone(), entry.line));
+                return Err(unrelocatable(file, line));
+            }
+        }
+    }
+
+    let mut exports = Vec::new();
+    for name in assembly.exports.iter() {
+        let value = |assembly: &Assembly| {
+            assembly
+                .symbols
+                .iter()
+                .find(|symbol| &symbol.name == name)
+                .map(|symbol| symbol.value.clone())
+                .unwrap_or_default()
+        };
+        let (value, code, data) = match (
+            value(&assembly).as_slice(),
+            value(&code_moved).as_slice(),
+            value(&data_moved).as_slice(),
+        ) {
+            ([value], [code], [data]) => (*value, *code, *data),
+            _ => {
+                return Err(Diagnostic::at(
+                    file,
+                    0,
+                    format!("EXPORT {name} is not a single word"),
+                ));
+            }
+        };
+        let section = section(value, code, data).unwrap_or(Section::Absolute);
+        exports.push((name.clone(), section, value));
+    }
+
+    let object = Object {
+        code: assembly.bytecode,
+        data: assembly.data,
+        source_map: assembly.source_map,
+        exports,
+        relocations,
+        references: assembly.references,
+    };
+    Ok((object, assembly.warnings))
+}
+
+/// Section of a word given its value when assembled normally, with the code
+/// moved by one and with the data moved by one. None if it moves in some
+/// other way, like the difference or product of addresses.
+fn section(value: u16, code_moved: u16, data_moved: u16) -> Option<Section> {
+    match (
+        code_moved.wrapping_sub(value),
+        data_moved.wrapping_sub(value),
+    ) {
+        (0, 0) => Some(Section::Absolute),
+        (1, 0) => Some(Section::Code),
+        (0, 1) => Some(Section::Data),
+        _ => None,
+    }
+}
+
+fn unrelocatable(file: Rc<str>, line: usize) -> Diagnostic {
+    Diagnostic::at(
+        file,
+        line,
+        "Value cannot be relocated, it is neither a constant nor a single address plus a \
+         constant"
+            .to_string(),
+    )
+}
+
+/// `value` moved with the base of its section, None if it no longer fits in
+/// a word.
+fn relocate(value: u16, section: Section, code_base: u16, data_base: u16) -> Option<u16> {
+    match section {
+        Section::Code => value.checked_add(code_base),
+        Section::Data => value.checked_add(data_base),
+        Section::Absolute => Some(value),
+    }
+}
+
+/// Links `objects`, given with their file names, into one object.
+pub fn link(objects: &[(String, Object)], entry: Option<&str>) -> Result<Object, String> {
+    let mut bases = Vec::new();
+    let (mut code, mut data) = (usize::from(entry.is_some()), 0);
+    for (_, object) in objects {
+        bases.push((code as u16, data as u16));
+        code += object.code.len();
+        data += object.data.len();
+    }
+    if PROGRAM_MEMORY_START + data > PROGRAM_MEMORY_END + 1 {
+        return Err("Linked data does not fit in program memory".to_string());
+    }
+
+    let mut symbols: HashMap<&str, (Section, u16, &str)> = HashMap::new();
+    for ((file, object), &(code_base, data_base)) in objects.iter().zip(&bases) {
+        for (name, section, value) in object.exports.iter() {
+            let value = relocate(*value, *section, code_base, data_base).ok_or_else(|| {
+                format!("Symbol {name} of {file} does not fit in a word once linked")
+            })?;
+            if let Some((_, _, other)) = symbols.insert(name, (*section, value, file)) {
+                return Err(format!("Symbol {name} is exported by {other} and {file}"));
+            }
+        }
+    }
+
+    let mut linked = Object::default();
+    if let Some(entry) = entry {
+        match symbols.get(entry) {
+            Some((Section::Code, address, _)) => {
+                // the jump is at address 0, so its offset is the address
+                linked.code.push(vec![Operation::JR.opcode(), *address]);
+                linked.source_map.locations.push(Location {
+                    file: Rc::from("<entry>"),
+                    line: 0,
+                    column: 0,
+                    label: None,
+                });
+            }
+            Some(_) => return Err(format!("Entry symbol {entry} is not a code label")),
+            None => return Err(format!("Entry symbol {entry} is not exported")),
+        }
+    }
+
+    for ((file, object), &(code_base, data_base)) in objects.iter().zip(&bases) {
+        let mut code = object.code.clone();
+        let mut data = object.data.clone();
+        let moved = |place: Place| match place {
+            Place::Code(index, word) => Place::Code(index + code_base as usize, word),
+            Place::Data(index) => Place::Data(index + data_base as usize),
+        };
+        for relocation in object.relocations.iter() {
+            let word = match relocation.place {
+                Place::Code(index, word) => &mut code[index][word],
+                Place::Data(index) => &mut data[index],
+            };
+            *word = relocate(*word, relocation.section, code_base, data_base).ok_or_else(|| {
+                format!("A relocated word of {file} does not fit in a word once linked")
+            })?;
+            linked.relocations.push(Relocation {
+                place: moved(relocation.place),
+                section: relocation.section,
+            });
+        }
+        for (index, word, name) in object.references.iter() {
+            let (section, value, _) = symbols
+                .get(name.as_str())
+                .ok_or_else(|| format!("Undefined symbol {name}, used by {file}"))?;
+            let relative = Operation::try_from_u16(code[*index][0])
+                .is_some_and(|op| op.signature()[*word - 1].1 == OperandKind::Relative);
+            if relative {
+                if *section != Section::Code {
+                    return Err(format!(
+                        "{file} jumps relative to {name}, which is not a code label"
+                    ));
+                }
+                code[*index][*word] = value.wrapping_sub(code_base + *index as u16);
+            } else {
+                code[*index][*word] = *value;
+            }
+            if *section != Section::Absolute && !relative {
+                linked.relocations.push(Relocation {
+                    place: moved(Place::Code(*index, *word)),
+                    section: *section,
+                });
+            }
+        }
+        linked
+            .source_map
+            .locations
+            .extend(object.source_map.locations.iter().map(|location| {
+                Location {
+                    label: location
+                        .label
+                        .as_ref()
+                        .map(|(name, address)| (name.clone(), address + code_base)),
+                    ..location.clone()
+                }
+            }));
+        linked.code.extend(code);
+        linked.data.extend(data);
+    }
+
+    let mut exports: Vec<(String, Section, u16)> = symbols
+        .into_iter()
+        .map(|(name, (section, value, _))| (name.to_string(), section, value))
+        .collect();
+    exports.sort_by(|a, b| a.0.cmp(&b.0));
+    linked.exports = exports;
+    Ok(linked)
+}
+
+impl Object {
+    /// Converts a fully linked object into a program that can be run.
+    pub fn into_assembly(self) -> Result<Assembly, String> {
+        if let Some((_, _, name)) = self.references.first() {
+            return Err(format!(
+                "Undefined symbol {name}, link the object that exports it"
+            ));
+        }
+        let symbols = self
+            .exports
+            .iter()
+            .map(|(name, section, value)| Symbol {
+                name: name.clone(),
+                kind: match section {
+                    Section::Code => SymbolKind::Label,
+                    Section::Data => SymbolKind::Data,
+                    Section::Absolute => SymbolKind::Constant,
+                },
+                value: vec![*value],
+            })
+            .collect();
+        Ok(Assembly {
+            reachable: vec![true; self.code.len()],
+            bytecode: self.code,
+            data: self.data,
+            warnings: Vec::new(),
+            listing: Vec::new(),
+            symbols,
+            source_map: self.source_map,
+            exports: self.exports.into_iter().map(|(name, _, _)| name).collect(),
+            references: Vec::new(),
+        })
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{MAGIC}\n");
+        for (name, section, value) in self.exports.iter() {
+            let _ = writeln!(text, "export {name} {} 0x{value:X}", section_name(*section));
+        }
+        for (index, row) in self.code.iter().enumerate() {
+            let _ = writeln!(text, "code {}", hex(row));
+            if let Some(location) = self.source_map.get(index as u16) {
+                let (label, address) = match &location.label {
+                    Some((label, address)) => (label.as_str(), *address),
+                    None => ("-", 0),
+                };
+                let _ = writeln!(
+                    text,
+                    "source {} {} {label} 0x{address:X} {}",
+                    location.line, location.column, location.file
+                );
+            }
+        }
+        if !self.data.is_empty() {
+            let _ = writeln!(text, "data {}", hex(&self.data));
+        }
+        for relocation in self.relocations.iter() {
+            let section = section_name(relocation.section);
+            let _ = match relocation.place {
+                Place::Code(index, word) => {
+                    writeln!(text, "reloc code {index} {word} {section}")
+                }
+                Place::Data(index) => writeln!(text, "reloc data {index} {section}"),
+            };
+        }
+        for (index, word, name) in self.references.iter() {
+            let _ = writeln!(text, "ref {index} {word} {name}");
+        }
+        text
+    }
+
+    pub fn parse(text: &str) -> Result<Object, String> {
+        let mut lines = text.lines().enumerate();
+        if lines.next().map(|(_, line)| line.trim()) != Some(MAGIC) {
+            return Err("Not an x1 object file".to_string());
+        }
+
+        let mut object = Object::default();
+        for (position, line) in lines {
+            let error = |message: &str| format!("Line {}: {message}", position + 1);
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let number = |word: Option<&&str>| {
+                word.and_then(|word| parse_number(word))
+                    .and_then(|value| u16::try_from(value).ok())
+                    .ok_or_else(|| error("Invalid number"))
+            };
+            let index = |word: Option<&&str>| number(word).map(usize::from);
+            let section = |word: Option<&&str>| match word.copied() {
+                Some("code") => Ok(Section::Code),
+                Some("data") => Ok(Section::Data),
+                Some("abs") => Ok(Section::Absolute),
+                _ => Err(error("Invalid section")),
+            };
+
+            match words.first().copied() {
+                None => {}
+                Some("export") => {
+                    let name = words.get(1).ok_or_else(|| error("Missing name"))?;
+                    let value = number(words.get(3))?;
+                    object
+                        .exports
+                        .push((name.to_string(), section(words.get(2))?, value));
+                }
+                Some("code") => object.code.push(
+                    (1..words.len())
+                        .map(|word| number(words.get(word)))
+                        .collect::<Result<_, _>>()?,
+                ),
+                Some("source") => {
+                    let label = match words.get(3).copied() {
+                        Some("-") | None => None,
+                        Some(label) => Some((label.to_string(), number(words.get(4))?)),
+                    };
+                    let file = line.splitn(6, char::is_whitespace).nth(5).unwrap_or("");
+                    object.source_map.locations.push(Location {
+                        file: Rc::from(file),
+                        line: index(words.get(1))?,
+                        column: index(words.get(2))?,
+                        label,
+                    });
+                }
+                Some("data") => {
+                    for word in 1..words.len() {
+                        object.data.push(number(words.get(word))?);
+                    }
+                }
+                Some("reloc") => {
+                    let relocation = match words.get(1).copied() {
+                        Some("code") => Relocation {
+                            place: Place::Code(index(words.get(2))?, index(words.get(3))?),
+                            section: section(words.get(4))?,
+                        },
+                        Some("data") => Relocation {
+                            place: Place::Data(index(words.get(2))?),
+                            section: section(words.get(3))?,
+                        },
+                        _ => return Err(error("Invalid relocation")),
+                    };
+                    object.relocations.push(relocation);
+                }
+                Some("ref") => {
+                    let name = words.get(3).ok_or_else(|| error("Missing name"))?;
+                    object.references.push((
+                        index(words.get(1))?,
+                        index(words.get(2))?,
+                        name.to_string(),
+                    ));
+                }
+                Some(record) => return Err(error(&format!("Unknown record {record}"))),
+            }
+        }
+
+        // only operands hold addresses, the signature also keeps the linker
+        // from looking up a word before the first operand
+        let code_word = |index: usize, word: usize| {
+            object.code.get(index).is_some_and(|row| {
+                let operands = row
+                    .first()
+                    .and_then(|opcode| Operation::try_from_u16(*opcode))
+                    .map_or(0, |op| op.signature().len());
+                (1..=operands).contains(&word) && word < row.len()
+            })
+        };
+        let valid = object
+            .relocations
+            .iter()
+            .all(|relocation| match relocation.place {
+                Place::Code(index, word) => code_word(index, word),
+                Place::Data(index) => index < object.data.len(),
+            })
+            && object
+                .references
+                .iter()
+                .all(|(index, word, _)| code_word(*index, *word));
+        if !valid {
+            return Err("Relocation outside of the object".to_string());
+        }
+        Ok(object)
+    }
+}
+
+fn section_name(section: Section) -> &'static str {
+    match section {
+        Section::Code => "code",
+        Section::Data => "data",
+        Section::Absolute => "abs",
+    }
+}
+
+fn hex(words: &[u16]) -> String {
+    let words: Vec<String> = words.iter().map(|word| format!("0x{word:X}")).collect();
+    words.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Object, link};
+    use crate::interpreter::State;
+    use crate::operation::Operation;
+    use crate::testing::{boot, object};
+
+    fn assemble(program: &str, pic: bool) -> Object {
+        // through the file format, as eightbit link reads them
+        Object::parse(&object(program, pic).to_text()).unwrap_or_else(|error| panic!("{error}"))
+    }
+
+    const MAIN: &str = "
+        EXTERN DOUBLE TABLE
+        EXPORT START
+        DEF SEVEN
+        .word 7
+        DEF START
+        IMM 3 R1
+        CALL DOUBLE
+        MOV TABLE R3
+        MOV SEVEN R4
+        HLT 0
+    ";
+
+    const LIB: &str = "
+        EXPORT DOUBLE TABLE
+        DEF TABLE
+        .word 0x11 0x22
+        DEF DOUBLE
+        ADD R1 R1
+        JMP DONE
+        IMM 0xFF R1
+        DEF DONE
+        MOV TABLE R2
+        RET
+    ";
+
+    /// R1 to R4 after linking `objects` and running them from START.
+    fn run(objects: Vec<(&str, Object)>) -> [u16; 4] {
+        let objects: Vec<(String, Object)> = objects
+            .into_iter()
+            .map(|(file, object)| (file.to_string(), object))
+            .collect();
+        let linked = link(&objects, Some("START")).unwrap_or_else(|error| panic!("{error}"));
+        let assembly = linked
+            .into_assembly()
+            .unwrap_or_else(|error| panic!("{error}"));
+        let mut machine = boot(assembly.bytecode, &assembly.data);
+        assert_eq!(machine.run(), State::Halted(0));
+        [1, 2, 3, 4].map(|register| machine.memory[register])
+    }
+
+    #[test]
+    fn links_and_relocates() {
+        for pic in [false, true] {
+            let objects = vec![
+                ("main.x1o", assemble(MAIN, pic)),
+                ("lib.x1o", assemble(LIB, pic)),
+            ];
+            assert_eq!(run(objects), [6, 0x11, 0x11, 7]);
+            // the library first moves the main program instead
+            let objects = vec![
+                ("lib.x1o", assemble(LIB, pic)),
+                ("main.x1o", assemble(MAIN, pic)),
+            ];
+            assert_eq!(run(objects), [6, 0x11, 0x11, 7]);
+        }
+    }
+
+    #[test]
+    fn link_errors() {
+        let main = || ("main.x1o".to_string(), assemble(MAIN, false));
+        let lib = || ("lib.x1o".to_string(), assemble(LIB, false));
+        let error = link(&[main()], Some("START")).err().unwrap();
+        assert!(error.starts_with("Undefined symbol"), "{error}");
+        assert!(error.ends_with("used by main.x1o"), "{error}");
+        let error = link(&[main(), lib(), lib()], None).err().unwrap();
+        assert_eq!(error, "Symbol DOUBLE is exported by lib.x1o and lib.x1o");
+        let error = link(&[main(), lib()], Some("TABLE")).err().unwrap();
+        assert_eq!(error, "Entry symbol TABLE is not a code label");
+        let jump = Operation::JMP.opcode();
+        let operand = Object::parse(&format!("X1OBJ\ncode 0x{jump:X} 0x0\nref 0 0 FOO"));
+        assert_eq!(operand.err().unwrap(), "Relocation outside of the object");
+        let far = Object::parse(&format!(
+            "X1OBJ\ncode 0x{jump:X} 0xFFFF\nreloc code 0 1 code"
+        ));
+        let far = (
+            "far.x1o".to_string(),
+            far.unwrap_or_else(|error| panic!("{error}")),
+        );
+        let error = link(&[lib(), far], None).err().unwrap();
+        assert_eq!(
+            error,
+            "A relocated word of far.x1o does not fit in a word once linked"
+        );
+
+        let unlinked = assemble(MAIN, false).into_assembly().err().unwrap();
+        assert!(unlinked.starts_with("Undefined symbol"), "{unlinked}");
+    }
+}
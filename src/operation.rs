@@ -1,17 +1,19 @@
 /*
 
-instructions start with 0x0[1<=N<=3]N (BYTE && 0010_0000 > 0)
+instructions start with 0x0[1<=N<=3]N (BYTE && 0010_0000 > 0), the relative
+jumps continue up to 0x041
 registers start with 0x00N (BYTE && 0000_1111 > 0)
 
 */
 
 //INSTRUCTIONS WITHOUT ARGS FOR EASY PARSING
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u16)]
 pub enum Operation {
     //BASIC
-    NOP, // 0x020 / 32 -> NOP -> NO OPERATION
-    DEF, // 0x021 / 33 -> DEF NAME ARGS -> DEFINES A NAME TO REPRESENT A COLLECTION OF BYTES
-    MOV, // 0x022 / 34 -> MOV SRC DEST -> MOVES SOURCE TO DESTINATION
+    NOP = 0x020, // 0x020 / 32 -> NOP -> NO OPERATION
+    DEF,         // 0x021 / 33 -> DEF NAME ARGS -> DEFINES A NAME TO REPRESENT A COLLECTION OF BYTES
+    MOV,         // 0x022 / 34 -> MOV SRC DEST -> MOVES SOURCE TO DESTINATION
 
     //ARITHMETIC
     ADD, // 0x023 / 35 -> ADD SRC DEST -> ADDS SOURCE TO DESTINATION
@@ -26,7 +28,7 @@ pub enum Operation {
     AND, // 0x02A / 42 -> AND SRC DEST -> DEST = SRC && DEST
     OR,  // 0x02B / 43 -> OR SRC DEST -> DEST = SRC || DEST
     XOR, // 0x02C / 44 -> XOR SRC DEST -> DEST = SRC ^ DEST
-    NOT, // 0x02D / 45 -> NOT SRC DEST -> DEST = !DEST
+    NOT, // 0x02D / 45 -> NOT DEST -> DEST = !DEST
     SHL, // 0x02E / 46 -> SHL ARG TARGET -> SHIFTS LEFT TARGET BY ARG
     SHR, // 0x02F / 47 -> SHR ARG TARGET -> SHIFTS RIGHT TARGET BY ARG
 
@@ -47,11 +49,180 @@ pub enum Operation {
     CALL, // 0x039 / 57 -> CALL NAME -> CALLS SUBROUTINE "NAME"
     RET,  // 0x03A / 58 -> RET -> RETURNS TO PARENT ROUTINE (HALTS IN ERROR, POPS ADDRESS OFF STACK)
     HLT,  // 0x03B / 59 -> HLT -> HALTS PROGRAM PROCESSING (SAFELY?)
+
+    //RELATIVE FLOW CONTROL (OFFSET IS ADDED TO THE ADDRESS OF THE INSTRUCTION ITSELF)
+    JR,    // 0x03C / 60 -> JR OFFSET -> JUMPS BY OFFSET
+    JGR,   // 0x03D / 61 -> JGR OFFSET ARG1 ARG2 -> JUMPS BY OFFSET IF ARG1 IS GREATER THAN ARG2
+    JLR,   // 0x03E / 62 -> JLR OFFSET ARG1 ARG2 -> JUMPS BY OFFSET IF ARG1 IS LESS THAN ARG2
+    JZR,   // 0x03F / 63 -> JZR OFFSET ARG -> JUMPS BY OFFSET IF ARG IS EQUAL TO ZERO
+    JNZR,  // 0x040 / 64 -> JNZR OFFSET ARG -> JUMPS BY OFFSET IF ARG IS NOT EQUAL TO ZERO
+    CALLR, // 0x041 / 65 -> CALLR OFFSET -> CALLS THE SUBROUTINE OFFSET AWAY
+}
+
+/// Kind of an instruction operand, checked by the assembler.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperandKind {
+    /// Register or memory address that is read.
+    Source,
+    /// Register or memory address that is written.
+    Dest,
+    /// Value used as is.
+    Immediate,
+    /// Instruction address.
+    Label,
+    /// Instruction address, encoded as an offset from the instruction itself.
+    Relative,
 }
 
 impl Operation {
+    pub const ALL: [Operation; 34] = [
+        Self::NOP,
+        Self::DEF,
+        Self::MOV,
+        Self::ADD,
+        Self::SUB,
+        Self::INC,
+        Self::DEC,
+        Self::MUL,
+        Self::DIV,
+        Self::MOD,
+        Self::AND,
+        Self::OR,
+        Self::XOR,
+        Self::NOT,
+        Self::SHL,
+        Self::SHR,
+        Self::JMP,
+        Self::JG,
+        Self::JL,
+        Self::JZ,
+        Self::JNZ,
+        Self::CMP,
+        Self::PUSH,
+        Self::POP,
+        Self::IMM,
+        Self::CALL,
+        Self::RET,
+        Self::HLT,
+        Self::JR,
+        Self::JGR,
+        Self::JLR,
+        Self::JZR,
+        Self::JNZR,
+        Self::CALLR,
+    ];
+
+    pub fn opcode(self) -> u16 {
+        self as u16
+    }
+
+    pub fn name(self) -> String {
+        format!("{self:?}")
+    }
+
+    /// Looks up an operation by mnemonic, ignoring case.
+    pub fn from_name(name: &str) -> Option<Operation> {
+        Operation::ALL
+            .into_iter()
+            .find(|op| op.name().eq_ignore_ascii_case(name))
+    }
+
+    /// Names and kinds of the operands the operation takes, in order.
+    pub fn signature(self) -> &'static [(&'static str, OperandKind)] {
+        use OperandKind::*;
+        match self {
+            Self::NOP | Self::DEF | Self::RET => &[],
+            Self::MOV
+            | Self::ADD
+            | Self::SUB
+            | Self::MUL
+            | Self::DIV
+            | Self::MOD
+            | Self::AND
+            | Self::OR
+            | Self::XOR
+            | Self::SHL
+            | Self::SHR => &[("SRC", Source), ("DEST", Dest)],
+            Self::INC | Self::DEC | Self::NOT | Self::POP => &[("DEST", Dest)],
+            Self::JMP | Self::CALL => &[("ADDR", Label)],
+            Self::JG | Self::JL => &[("ADDR", Label), ("ARG1", Source), ("ARG2", Source)],
+            Self::JZ | Self::JNZ => &[("ADDR", Label), ("ARG1", Source)],
+            Self::CMP => &[("ARG1", Source), ("ARG2", Source)],
+            Self::PUSH => &[("SRC", Source)],
+            Self::IMM => &[("IMM", Immediate), ("DEST", Dest)],
+            Self::HLT => &[("EXIT_CODE", Immediate)],
+            Self::JR | Self::CALLR => &[("OFFSET", Relative)],
+            Self::JGR | Self::JLR => &[("OFFSET", Relative), ("ARG1", Source), ("ARG2", Source)],
+            Self::JZR | Self::JNZR => &[("OFFSET", Relative), ("ARG1", Source)],
+        }
+    }
+
+    /// What the operation does, for documentation.
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::NOP => "Does nothing.",
+            Self::DEF => "Defines a label (DEF NAME) or a constant (DEF NAME VALUE).",
+            Self::MOV => "Copies SRC to DEST.",
+            Self::ADD => "Adds SRC to DEST.",
+            Self::SUB => "Subtracts SRC from DEST.",
+            Self::INC => "Adds 1 to DEST.",
+            Self::DEC => "Subtracts 1 from DEST.",
+            Self::MUL => "Multiplies DEST by SRC.",
+            Self::DIV => "Divides DEST by SRC, rounding down.",
+            Self::MOD => "Sets DEST to the remainder of DEST divided by SRC.",
+            Self::AND => "Sets DEST to the bitwise and of SRC and DEST.",
+            Self::OR => "Sets DEST to the bitwise or of SRC and DEST.",
+            Self::XOR => "Sets DEST to the bitwise xor of SRC and DEST.",
+            Self::NOT => "Inverts every bit of DEST.",
+            Self::SHL => "Shifts DEST left by SRC bits.",
+            Self::SHR => "Shifts DEST right by SRC bits.",
+            Self::JMP => "Jumps to ADDR.",
+            Self::JG => "Jumps to ADDR if ARG1 is greater than ARG2.",
+            Self::JL => "Jumps to ADDR if ARG1 is less than ARG2.",
+            Self::JZ => "Jumps to ADDR if ARG1 is zero.",
+            Self::JNZ => "Jumps to ADDR if ARG1 is not zero.",
+            Self::CMP => "Sets FLAGS to 1 if ARG1 and ARG2 are equal.",
+            Self::PUSH => "Pushes SRC onto the stack.",
+            Self::POP => "Pops the top of the stack into DEST.",
+            Self::IMM => "Sets DEST to the value IMM.",
+            Self::CALL => "Pushes the return address and jumps to ADDR.",
+            Self::RET => "Returns to the instruction after the last CALL.",
+            Self::HLT => "Stops the program with EXIT_CODE, which is also stored in RET.",
+            Self::JR => "Jumps OFFSET instructions away.",
+            Self::JGR => "Jumps OFFSET instructions away if ARG1 is greater than ARG2.",
+            Self::JLR => "Jumps OFFSET instructions away if ARG1 is less than ARG2.",
+            Self::JZR => "Jumps OFFSET instructions away if ARG1 is zero.",
+            Self::JNZR => "Jumps OFFSET instructions away if ARG1 is not zero.",
+            Self::CALLR => "Pushes the return address and jumps OFFSET instructions away.",
+        }
+    }
+
+    /// The relative form of an absolute jump or call.
+    pub fn relative(self) -> Option<Operation> {
+        match self {
+            Self::JMP => Some(Self::JR),
+            Self::JG => Some(Self::JGR),
+            Self::JL => Some(Self::JLR),
+            Self::JZ => Some(Self::JZR),
+            Self::JNZ => Some(Self::JNZR),
+            Self::CALL => Some(Self::CALLR),
+            _ => None,
+        }
+    }
+
+    pub fn is_relative(self) -> bool {
+        matches!(
+            self,
+            Self::JR | Self::JGR | Self::JLR | Self::JZR | Self::JNZR | Self::CALLR
+        )
+    }
+
     pub fn from_u16(num: u16) -> Operation {
-        match num {
+        Operation::try_from_u16(num).unwrap_or_else(|| panic!("Unknown Operation. {:#X}", num))
+    }
+
+    pub fn try_from_u16(num: u16) -> Option<Operation> {
+        Some(match num {
             0x020 => Self::NOP,
             0x021 => Self::DEF,
             0x022 => Self::MOV,
@@ -86,23 +257,126 @@ impl Operation {
             0x03A => Self::RET,
             0x03B => Self::HLT,
 
-            _ => panic!("Unknown Operation. {:#X}", num),
-        }
+            0x03C => Self::JR,
+            0x03D => Self::JGR,
+            0x03E => Self::JLR,
+            0x03F => Self::JZR,
+            0x040 => Self::JNZR,
+            0x041 => Self::CALLR,
+
+            _ => return None,
+        })
     }
 }
 
-pub fn parse_hex(str: &str) -> Option<u16> {
-    if str.starts_with("0x") {
-        Some(
-            u16::from_str_radix(
-                str.strip_prefix("0x")
-                    .unwrap_or_else(|| panic!("Error stripping hexidecimal prefix for {str}")),
-                16,
-            )
-            .unwrap_or_else(|_| panic!("Failure to parse hexidecimal for {str}")),
-        )
+/// Width of a machine word, literals must fit in it.
+pub const WORD_BITS: u32 = 16;
+
+/*
+LITERALS
+
+42        -> decimal
+0x2A      -> hexadecimal
+0b101010  -> binary
+0o52      -> octal
+'*'       -> character, escapes: \n \t \r \0 \\ \' \" \xNN
+-42       -> negative, stored as two's complement
+
+Digits may be separated with '_'.
+*/
+
+/// Parses a numeric or character literal for a word of `bits` bits. Returns
+/// Ok(None) if `word` is not a literal at all (a name), and an error if it
+/// looks like one but is malformed or does not fit.
+pub fn parse_literal(word: &str, bits: u32) -> Result<Option<u16>, String> {
+    match literal_value(word)? {
+        Some(value) => fit_word(value, bits).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Converts `value` to a word of `bits` bits, negative values become two's
+/// complement.
+pub fn fit_word(value: i64, bits: u32) -> Result<u16, String> {
+    let min = -(1i64 << (bits - 1));
+    let max = (1i64 << bits) - 1;
+    if value < min || value > max {
+        return Err(format!(
+            "{value} does not fit in a {bits} bit word ({min}..={max})"
+        ));
+    }
+    Ok((value & max) as u16)
+}
+
+/// Value of a literal without range checks, see parse_literal.
+pub fn literal_value(word: &str) -> Result<Option<i64>, String> {
+    let (negative, digits) = match word.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, word),
+    };
+    let value: i64 = if let Some(character) = digits.strip_prefix('\'') {
+        let character = character
+            .strip_suffix('\'')
+            .ok_or_else(|| format!("Unterminated character literal {word}"))?;
+        match parse_escapes(character)?.as_slice() {
+            [value] => *value as i64,
+            _ => return Err(format!("Character literal {word} must hold one character")),
+        }
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        let (radix, number) = match digits.get(..2) {
+            Some("0x") => (16, &digits[2..]),
+            Some("0b") => (2, &digits[2..]),
+            Some("0o") => (8, &digits[2..]),
+            _ => (10, digits),
+        };
+        let number = number.replace('_', "");
+        i64::from_str_radix(&number, radix).map_err(|_| format!("Invalid literal {word}"))?
+    } else if negative {
+        return Err(format!("Invalid literal {word}"));
     } else {
-        None
+        return Ok(None);
+    };
+
+    Ok(Some(if negative { -value } else { value }))
+}
+
+/// Resolves backslash escapes in the contents of a character or string
+/// literal, returning one word per character.
+pub fn parse_escapes(text: &str) -> Result<Vec<u16>, String> {
+    let mut words = Vec::new();
+    let mut chars = text.chars();
+    while let Some(character) = chars.next() {
+        if character != '\\' {
+            let word = u16::try_from(u32::from(character))
+                .map_err(|_| format!("Character {character:?} does not fit in a word"))?;
+            words.push(word);
+            continue;
+        }
+        let escaped = match chars.next() {
+            Some('n') => '\n' as u16,
+            Some('t') => '\t' as u16,
+            Some('r') => '\r' as u16,
+            Some('0') => 0,
+            Some('\\') => '\\' as u16,
+            Some('\'') => '\'' as u16,
+            Some('"') => '"' as u16,
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                u16::from_str_radix(&hex, 16).map_err(|_| format!("Invalid escape \\x{hex}"))?
+            }
+            Some(other) => return Err(format!("Unknown escape \\{other}")),
+            None => return Err("Unterminated escape".to_string()),
+        };
+        words.push(escaped);
+    }
+    Ok(words)
+}
+
+/// Parses a user supplied number, either `0x` prefixed hexadecimal or decimal.
+pub fn parse_number(word: &str) -> Option<u64> {
+    match word.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => word.parse().ok(),
     }
 }
 
@@ -111,7 +385,7 @@ pub fn format_radix(mut x: u32, radix: u32) -> String {
 
     loop {
         let m = x % radix;
-        x = x / radix;
+        x /= radix;
 
         // will panic if you use a bad radix (< 2 or > 36).
         result.push(std::char::from_digit(m, radix).unwrap());
@@ -121,3 +395,58 @@ pub fn format_radix(mut x: u32, radix: u32) -> String {
     }
     result.into_iter().rev().collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{WORD_BITS, parse_escapes, parse_literal};
+
+    #[test]
+    fn literals() {
+        let literal = |word| parse_literal(word, WORD_BITS);
+        assert_eq!(literal("42"), Ok(Some(42)));
+        assert_eq!(literal("0x2A"), Ok(Some(42)));
+        assert_eq!(literal("0b10_1010"), Ok(Some(42)));
+        assert_eq!(literal("0o52"), Ok(Some(42)));
+        assert_eq!(literal("'*'"), Ok(Some(42)));
+        assert_eq!(literal("-1"), Ok(Some(0xFFFF)));
+        assert_eq!(literal("-'a'"), Ok(Some(0xFF9F)));
+        assert_eq!(literal("LOOP"), Ok(None));
+        assert!(literal("0x2G").is_err());
+        assert!(literal("-LOOP").is_err());
+        assert!(literal("'ab'").is_err());
+        assert!(literal("'a").is_err());
+    }
+
+    #[test]
+    fn literal_ranges() {
+        assert_eq!(parse_literal("0xFFFF", WORD_BITS), Ok(Some(0xFFFF)));
+        assert_eq!(parse_literal("-32768", WORD_BITS), Ok(Some(0x8000)));
+        assert_eq!(
+            parse_literal("0x10000", WORD_BITS),
+            Err("65536 does not fit in a 16 bit word (-32768..=65535)".to_string())
+        );
+        assert!(parse_literal("-32769", WORD_BITS).is_err());
+        assert_eq!(parse_literal("0xFF", 8), Ok(Some(0xFF)));
+        assert_eq!(parse_literal("-128", 8), Ok(Some(0x80)));
+        assert!(parse_literal("0x100", 8).is_err());
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            parse_escapes(r#"a\n\t\r\0\\\'\"\x41"#),
+            Ok(vec![0x61, 0x0A, 0x09, 0x0D, 0, 0x5C, 0x27, 0x22, 0x41])
+        );
+        assert_eq!(parse_escapes("é€"), Ok(vec![0xE9, 0x20AC]));
+        assert_eq!(
+            parse_escapes("🙂"),
+            Err("Character '🙂' does not fit in a word".to_string())
+        );
+        assert_eq!(parse_escapes("\\q"), Err("Unknown escape \\q".to_string()));
+        assert_eq!(
+            parse_escapes("\\xZZ"),
+            Err("Invalid escape \\xZZ".to_string())
+        );
+        assert_eq!(parse_escapes("\\"), Err("Unterminated escape".to_string()));
+    }
+}
@@ -0,0 +1,606 @@
+use crate::compiler::{Assembly, Diagnostic, Line};
+use crate::interpreter::{PROGRAM_MEMORY_START, check_address};
+use crate::listing::SymbolKind;
+use crate::object::{Object, Place, Relocation, Section, assemble_lines};
+use crate::operation::Operation;
+use std::collections::HashSet;
+use std::path::Path;
+
+/*
+PEEPHOLE OPTIMIZER
+
+MOV X X                  -> removed
+JMP NEXT                 -> removed, also conditional jumps to the next instruction
+IMM A R / ADD R R        -> IMM 2*A R
+IMM A R / ADD R X        -> IMM A R                    if A is 0
+IMM B X / IMM A R / ADD R X
+                         -> IMM A R / IMM A+B X
+CALL F / RET             -> JMP F
+JMP L ... L: JMP M       -> JMP M ... L: JMP M         for every jump and call
+
+Enabled with -O, runs on the assembled object until nothing changes. An
+instruction some jump or call goes to is never merged with the one before it.
+Every word holding a code address is moved with the instructions, values
+computed from several code addresses, like the length of a function, are not.
+The listing and source map follow the optimized program, removed instructions
+are listed without an address.
+*/
+
+/// Optimizes the code of `object` in place. Returns where every instruction
+/// went, None for the removed ones.
+pub fn optimize(object: &mut Object) -> Vec<Option<usize>> {
+    let mut moved: Vec<Option<usize>> = (0..object.code.len()).map(Some).collect();
+    loop {
+        let threaded = thread_jumps(object);
+        let removed = rewrite(object);
+        if !threaded && !removed.iter().any(|removed| *removed) {
+            return moved;
+        }
+        moved = then(&moved, &remove(object, &removed));
+    }
+}
+
+/// Removes the instructions of `assembly` that can't be reached if `strip` is
+/// set (see reachability.rs) and optimizes it if `optimize` is set. `lines`,
+/// read from `path`, are assembled again as an object for the code addresses
+/// it records, an error leaves `assembly` as it is.
+pub fn shrink(
+    assembly: &mut Assembly,
+    lines: Vec<Line>,
+    path: &Path,
+    pic: bool,
+    strip: bool,
+    optimize: bool,
+) -> Result<(), Diagnostic> {
+    let (mut object, _) = assemble_lines(lines, path, pic)?;
+    let mut moved: Vec<Option<usize>> = (0..object.code.len()).map(Some).collect();
+    if strip {
+        let unreachable: Vec<bool> = assembly.reachable.iter().map(|reached| !reached).collect();
+        moved = remove(&mut object, &unreachable);
+    }
+    if optimize {
+        moved = then(&moved, &self::optimize(&mut object));
+    }
+    replace_program(assembly, object, &moved);
+    Ok(())
+}
+
+/// Where instructions went after being moved by `first` and then `second`.
+fn then(first: &[Option<usize>], second: &[Option<usize>]) -> Vec<Option<usize>> {
+    first
+        .iter()
+        .map(|position| position.and_then(|position| second[position]))
+        .collect()
+}
+
+/// Replaces the program of `assembly` with `object`, whose instructions went
+/// where `moved` says, and moves the listing, the code labels and the
+/// reachability along.
+fn replace_program(assembly: &mut Assembly, object: Object, moved: &[Option<usize>]) {
+    // an address of a removed instruction moves to the one after it
+    let address = |value: u16| {
+        moved
+            .iter()
+            .skip(value as usize)
+            .find_map(|position| *position)
+            .unwrap_or(object.code.len()) as u16
+    };
+    let labels: Vec<(&str, u16)> = assembly
+        .symbols
+        .iter()
+        .filter(|symbol| symbol.kind == SymbolKind::Label)
+        .filter_map(|symbol| Some((symbol.name.as_str(), *symbol.value.first()?)))
+        .collect();
+    // a local label is written without its scope
+    let label = |word: &str, value: &[u16]| {
+        labels.iter().any(|(name, address)| {
+            (*name == word || (word.starts_with('.') && name.ends_with(word)))
+                && value == [*address]
+        })
+    };
+
+    for entry in assembly.listing.iter_mut() {
+        match entry.address {
+            Some(start) if entry.data => {
+                let start = start as usize - PROGRAM_MEMORY_START;
+                entry.words = object.data[start..start + entry.words.len()].to_vec();
+            }
+            Some(index) if !entry.words.is_empty() => match moved[index as usize] {
+                Some(position) => {
+                    entry.address = Some(position as u16);
+                    entry.words = object.code[position].clone();
+                }
+                None => {
+                    entry.address = None;
+                    entry.words.clear();
+                }
+            },
+            Some(label) => entry.address = Some(address(label)),
+            None => {}
+        }
+        for (word, value) in entry.resolved.iter_mut() {
+            if label(word, value) {
+                *value = vec![address(value[0])];
+            }
+        }
+    }
+    for symbol in assembly.symbols.iter_mut() {
+        if symbol.kind == SymbolKind::Label {
+            symbol.value = symbol.value.iter().map(|value| address(*value)).collect();
+        }
+    }
+
+    let mut reachable = vec![false; object.code.len()];
+    for (reached, position) in assembly.reachable.iter().zip(moved) {
+        if let Some(position) = position {
+            reachable[*position] = *reached;
+        }
+    }
+    assembly.reachable = reachable;
+    assembly.bytecode = object.code;
+    assembly.data = object.data;
+    assembly.source_map = object.source_map;
+}
+
+/// Whether `op` goes to the address in its first operand.
+fn is_jump(op: Operation) -> bool {
+    op.is_relative() || op.relative().is_some()
+}
+
+fn operation(row: &[u16]) -> Option<Operation> {
+    let op = Operation::try_from_u16(*row.first()?)?;
+    (row.len() == op.signature().len() + 1).then_some(op)
+}
+
+fn is_reference(object: &Object, index: usize, word: usize) -> bool {
+    object
+        .references
+        .iter()
+        .any(|(instruction, operand, _)| (*instruction, *operand) == (index, word))
+}
+
+fn is_relocated(object: &Object, index: usize, word: usize) -> bool {
+    object
+        .relocations
+        .iter()
+        .any(|relocation| relocation.place == Place::Code(index, word))
+}
+
+/// Instruction the jump or call at `index` goes to, None for other
+/// instructions and for jumps to EXTERN symbols.
+fn target(object: &Object, index: usize) -> Option<usize> {
+    let row = &object.code[index];
+    let op = operation(row).filter(|op| is_jump(*op))?;
+    if is_reference(object, index, 1) {
+        return None;
+    }
+    Some(if op.is_relative() {
+        (index as u16).wrapping_add(row[1]) as usize
+    } else {
+        row[1] as usize
+    })
+}
+
+fn set_target(object: &mut Object, index: usize, target: usize) {
+    let row = &mut object.code[index];
+    if Operation::from_u16(row[0]).is_relative() {
+        row[1] = (target as u16).wrapping_sub(index as u16);
+    } else {
+        row[1] = target as u16;
+        if !is_relocated(object, index, 1) {
+            object.relocations.push(Relocation {
+                place: Place::Code(index, 1),
+                section: Section::Code,
+            });
+        }
+    }
+}
+
+/// Whether the instruction at `index` is an unconditional jump.
+fn is_goto(object: &Object, index: usize) -> bool {
+    matches!(
+        object.code.get(index).and_then(|row| operation(row)),
+        Some(Operation::JMP | Operation::JR)
+    )
+}
+
+/// Points jumps and calls to a jump at the end of the chain instead.
+fn thread_jumps(object: &mut Object) -> bool {
+    let mut changed = false;
+    for index in 0..object.code.len() {
+        let Some(first) = target(object, index) else {
+            continue;
+        };
+        let mut last = first;
+        let mut seen = HashSet::from([index]);
+        while is_goto(object, last) && seen.insert(last) {
+            match target(object, last) {
+                Some(next) => last = next,
+                None => break,
+            }
+        }
+        // a chain ending in a loop of jumps keeps its first target
+        if last != first && !is_goto(object, last) {
+            set_target(object, index, last);
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Instructions reached other than by falling through from the one before:
+/// targets of jumps and calls and every code address held anywhere.
+fn entries(object: &Object) -> HashSet<usize> {
+    let mut entries: HashSet<usize> = (0..object.code.len())
+        .filter_map(|index| target(object, index))
+        .collect();
+    for relocation in object.relocations.iter() {
+        if relocation.section == Section::Code {
+            entries.insert(value(object, relocation.place) as usize);
+        }
+    }
+    for (_, section, value) in object.exports.iter() {
+        if *section == Section::Code {
+            entries.insert(*value as usize);
+        }
+    }
+    entries
+}
+
+fn value(object: &Object, place: Place) -> u16 {
+    match place {
+        Place::Code(index, word) => object.code[index][word],
+        Place::Data(index) => object.data[index],
+    }
+}
+
+/// Applies every rewrite that doesn't overlap with an earlier one. Returns
+/// the instructions to remove.
+fn rewrite(object: &mut Object) -> Vec<bool> {
+    let entries = entries(object);
+    let mut removed = vec![false; object.code.len()];
+    let mut index = 0;
+    while index < object.code.len() {
+        let row = object.code[index].clone();
+        let next = object.code.get(index + 1).cloned().unwrap_or_default();
+        // the next instruction is only reached from this one
+        let joined = !entries.contains(&(index + 1));
+        let fixed = |index: usize, word: usize| {
+            !is_reference(object, index, word) && !is_relocated(object, index, word)
+        };
+        match (operation(&row), operation(&next)) {
+            (Some(Operation::MOV), _) if row[1] == row[2] && !is_reference(object, index, 1) => {
+                removed[index] = true;
+            }
+            (Some(Operation::CALL | Operation::CALLR), Some(Operation::RET)) if joined => {
+                let jump = match Operation::from_u16(row[0]) {
+                    Operation::CALL => Operation::JMP,
+                    _ => Operation::JR,
+                };
+                object.code[index][0] = jump.opcode();
+                removed[index + 1] = true;
+                index += 1;
+            }
+            (Some(op), _)
+                if is_jump(op)
+                    && !matches!(op, Operation::CALL | Operation::CALLR)
+                    && target(object, index) == Some(index + 1) =>
+            {
+                removed[index] = true;
+            }
+            (Some(Operation::IMM), Some(Operation::ADD))
+                if joined
+                    && row[2] == next[1]
+                    && check_address(row[2])
+                    && check_address(next[2])
+                    && fixed(index, 1) =>
+            {
+                let (immediate, register, dest) = (row[1], row[2], next[2]);
+                if dest == register {
+                    if let Some(double) = immediate.checked_add(immediate) {
+                        object.code[index][1] = double;
+                        removed[index + 1] = true;
+                    }
+                } else if immediate == 0 {
+                    removed[index + 1] = true;
+                } else if fixed(index + 1, 1)
+                    && let Some((previous, value)) = before(object, index, &entries, &removed, dest)
+                    && let Some(sum) = value.checked_add(immediate)
+                {
+                    removed[previous] = true;
+                    object.code[index + 1] = vec![Operation::IMM.opcode(), sum, dest];
+                }
+                index += 1;
+            }
+            _ => {}
+        }
+        index += 1;
+    }
+    removed
+}
+
+/// The instruction right before `index` if it is an `IMM VALUE DEST` that is
+/// not removed yet and `index` can only be reached from it, with VALUE.
+fn before(
+    object: &Object,
+    index: usize,
+    entries: &HashSet<usize>,
+    removed: &[bool],
+    dest: u16,
+) -> Option<(usize, u16)> {
+    let previous = index.checked_sub(1)?;
+    let row = &object.code[previous];
+    let fixed = !is_reference(object, previous, 1) && !is_relocated(object, previous, 1);
+    (operation(row) == Some(Operation::IMM)
+        && row[2] == dest
+        && fixed
+        && !removed[previous]
+        && !entries.contains(&index))
+    .then_some((previous, row[1]))
+}
+
+/// Drops the `removed` instructions and moves every code address, jump and
+/// record to the new positions. An address of a removed instruction moves to
+/// the one after it. Returns where every instruction went, None for the
+/// removed ones.
+pub fn remove(object: &mut Object, removed: &[bool]) -> Vec<Option<usize>> {
+    let length = object.code.len();
+    let mut moved = Vec::with_capacity(length + 1);
+    let mut position = 0;
+    for removed in removed.iter().chain([&false]) {
+        moved.push(position);
+        position += usize::from(!removed);
+    }
+    let address = |value: u16| match moved.get(value as usize) {
+        Some(position) => *position as u16,
+        None => value,
+    };
+
+    // jumps are moved last, relative ones depend on their own position
+    let targets: Vec<Option<usize>> = (0..length).map(|index| target(object, index)).collect();
+    for relocation in object.relocations.iter() {
+        match *relocation {
+            Relocation {
+                section: Section::Code,
+                place: Place::Code(index, word),
+            } if word != 1 || targets[index].is_none() => {
+                object.code[index][word] = address(object.code[index][word]);
+            }
+            Relocation {
+                section: Section::Code,
+                place: Place::Data(index),
+            } => object.data[index] = address(object.data[index]),
+            _ => {}
+        }
+    }
+    for (_, section, value) in object.exports.iter_mut() {
+        if *section == Section::Code {
+            *value = address(*value);
+        }
+    }
+    for location in object.source_map.locations.iter_mut() {
+        if let Some((_, label)) = &mut location.label {
+            *label = address(*label);
+        }
+    }
+
+    object
+        .relocations
+        .retain(|relocation| match relocation.place {
+            Place::Code(index, _) => !removed[index],
+            Place::Data(_) => true,
+        });
+    for relocation in object.relocations.iter_mut() {
+        if let Place::Code(index, word) = relocation.place {
+            relocation.place = Place::Code(moved[index], word);
+        }
+    }
+    object.references.retain(|(index, _, _)| !removed[*index]);
+    for (index, _, _) in object.references.iter_mut() {
+        *index = moved[*index];
+    }
+    retain(&mut object.code, removed);
+    retain(&mut object.source_map.locations, removed);
+
+    for (index, target) in targets.into_iter().enumerate() {
+        if let Some(target) = target
+            && !removed[index]
+        {
+            set_target(object, moved[index], address(target as u16) as usize);
+        }
+    }
+    removed
+        .iter()
+        .zip(moved)
+        .map(|(removed, position)| (!removed).then_some(position))
+        .collect()
+}
+
+fn retain<T>(items: &mut Vec<T>, removed: &[bool]) {
+    let mut kept = removed.iter().map(|removed| !removed);
+    items.retain(|_| kept.next().unwrap_or(true));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::optimize;
+    use crate::interpreter::{PROGRAM_COUNTER_ADDRESS, STACK_BASE, State};
+    use crate::object::Object;
+    use crate::operation::Operation;
+    use crate::testing::{boot, check_listing, object as assemble, shrink_program};
+
+    /// How the program ended and every word below the stack, except the
+    /// program counter which differs once instructions are removed.
+    fn run(object: &Object) -> (State, Vec<u16>) {
+        let mut machine = boot(object.code.clone(), &object.data);
+        let state = machine.run();
+        let mut memory = machine.memory[..STACK_BASE].to_vec();
+        memory[PROGRAM_COUNTER_ADDRESS] = 0;
+        (state, memory)
+    }
+
+    /// Optimizes `program` and checks that it removes `removed` instructions
+    /// and ends in the same state as the original.
+    fn check(program: &str, pic: bool, removed: usize) -> Object {
+        let original = assemble(program, pic);
+        let mut optimized = assemble(program, pic);
+        let moved = optimize(&mut optimized);
+        assert_eq!(
+            moved.iter().filter(|position| position.is_none()).count(),
+            removed
+        );
+        assert_eq!(optimized.code.len(), original.code.len() - removed);
+        assert_eq!(run(&original), run(&optimized));
+        optimized
+    }
+
+    const LOOP: &str = "
+        IMM 5 R1
+        IMM 0 R2
+        DEF LOOP
+        MOV R2 R2
+        INC R2
+        DEC R1
+        JNZ LOOP R1
+        HLT 0
+    ";
+
+    #[test]
+    fn removes_moves_to_the_same_place() {
+        check(LOOP, false, 1);
+        check(LOOP, true, 1);
+    }
+
+    #[test]
+    fn removes_jumps_to_the_next_instruction() {
+        let program = "
+            IMM 3 R1
+            JMP NEXT
+            DEF NEXT
+            JZ .zero R1
+            DEF .zero
+            JNZ .nonzero R1
+            DEF .nonzero
+            INC R1
+            HLT 0
+        ";
+        check(program, false, 3);
+        check(program, true, 3);
+    }
+
+    #[test]
+    fn folds_immediate_additions() {
+        // IMM A R / ADD R R
+        check("IMM 7 R1\nADD R1 R1\nHLT 0", false, 1);
+        // adding zero
+        check("IMM 4 R2\nIMM 0 R1\nADD R1 R2\nHLT 0", false, 1);
+        // IMM B X / IMM A R / ADD R X
+        let optimized = check("IMM 4 R2\nIMM 3 R1\nADD R1 R2\nHLT 0", false, 1);
+        assert_eq!(optimized.code[1], vec![Operation::IMM.opcode(), 7, 2]);
+    }
+
+    #[test]
+    fn keeps_additions_reached_by_a_jump() {
+        let program = "
+            IMM 2 R2
+            IMM 3 R1
+            DEF AGAIN
+            ADD R1 R2
+            JL AGAIN R2 R3
+            HLT 0
+        ";
+        let optimized = check(&format!("IMM 20 R3\n{program}"), false, 0);
+        assert_eq!(optimized.code.len(), 6);
+    }
+
+    #[test]
+    fn turns_calls_before_returns_into_jumps() {
+        let program = "
+            CALL MAIN
+            HLT 0
+            DEF MAIN
+            IMM 5 R1
+            CALL DOUBLE
+            RET
+            DEF DOUBLE
+            ADD R1 R1
+            RET
+        ";
+        // the jump then goes to the next instruction and the addition folds
+        let optimized = check(program, false, 3);
+        assert_eq!(optimized.code[2], vec![Operation::IMM.opcode(), 10, 1]);
+        check(program, true, 3);
+    }
+
+    #[test]
+    fn threads_jump_chains() {
+        let program = "
+            IMM 2 R1
+            JMP FIRST
+            DEF END
+            HLT 0
+            DEF SECOND
+            JMP END
+            DEF FIRST
+            JNZ SECOND R1
+            JMP END
+        ";
+        let optimized = check(program, false, 0);
+        assert_eq!(optimized.code[4], vec![Operation::JNZ.opcode(), 2, 1]);
+        let optimized = check(program, true, 0);
+        assert_eq!(optimized.code[4], vec![Operation::JNZR.opcode(), 0xFFFE, 1]);
+    }
+
+    #[test]
+    fn moves_code_addresses() {
+        let program = "
+            IMM 1 R1
+            JMP NEXT
+            DEF NEXT
+            IMM TARGET R2
+            JMP TARGET
+            DEF TARGET
+            HLT 0
+            DEF ADDRESS
+            .word TARGET
+        ";
+        let mut object = assemble(program, false);
+        optimize(&mut object);
+        assert_eq!(object.code[1], vec![Operation::IMM.opcode(), 2, 2]);
+        assert_eq!(object.data, vec![2]);
+        assert_eq!(object.code[2][0], Operation::HLT.opcode());
+    }
+
+    #[test]
+    fn listing_follows_the_optimized_program() {
+        let program = "
+            IMM 1 R1
+            MOV R1 R1
+            JMP NEXT
+            DEF NEXT
+            CALL F
+            HLT 0
+            DEF F
+            IMM 2 R2
+            RET
+        ";
+        let assembly = shrink_program(program, false, true);
+        check_listing(&assembly);
+        let removed: Vec<&str> = assembly
+            .listing
+            .iter()
+            .filter(|entry| entry.address.is_none() && !entry.text.is_empty())
+            .map(|entry| entry.text.trim())
+            .collect();
+        assert_eq!(removed, ["MOV R1 R1", "JMP NEXT"]);
+        let f = assembly.symbols.iter().find(|symbol| symbol.name == "F");
+        assert_eq!(f.map(|symbol| symbol.value.clone()), Some(vec![3]));
+        let lines: Vec<usize> = assembly
+            .source_map
+            .locations
+            .iter()
+            .map(|l| l.line)
+            .collect();
+        assert_eq!(lines, [2, 6, 7, 9, 10]);
+    }
+}
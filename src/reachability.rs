@@ -0,0 +1,136 @@
+use crate::operation::Operation;
+
+/*
+REACHABILITY
+
+Execution starts at the first instruction, objects can also be entered at
+every exported label. From an instruction the machine can go on to the next
+one, unless it is JMP, RET or HLT, and to the target of a jump or call. RET
+continues after a CALL, which the CALL already reaches. There are no indirect
+jumps, so holding the address of code (IMM LABEL R1) does not make it
+reachable.
+
+The assembler warns about every run of instructions that can't be reached and
+every label that is never used. `--strip` removes the unreachable
+instructions from the program.
+*/
+
+/// Which instructions of `code`, placed at `base`, can run when execution
+/// starts at one of `roots`.
+pub fn reachable(
+    code: &[Vec<u16>],
+    base: u16,
+    roots: impl IntoIterator<Item = usize>,
+) -> Vec<bool> {
+    let mut reached = vec![false; code.len()];
+    let mut pending: Vec<usize> = roots.into_iter().collect();
+    while let Some(index) = pending.pop() {
+        if index >= code.len() || reached[index] {
+            continue;
+        }
+        reached[index] = true;
+        let row = &code[index];
+        let Some(op) = row.first().and_then(|word| Operation::try_from_u16(*word)) else {
+            pending.push(index + 1);
+            continue;
+        };
+        if !matches!(
+            op,
+            Operation::JMP | Operation::JR | Operation::RET | Operation::HLT
+        ) {
+            pending.push(index + 1);
+        }
+        if let Some(operand) = row.get(1) {
+            let address = (base + index as u16).wrapping_add(*operand);
+            if op.is_relative() {
+                pending.push(address.wrapping_sub(base) as usize);
+            } else if op.relative().is_some() {
+                pending.push(operand.wrapping_sub(base) as usize);
+            }
+        }
+    }
+    reached
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::Layout;
+    use crate::listing::SymbolKind;
+    use crate::testing::{assemble_program, check_listing, run, shrink_program};
+
+    const PROGRAM: &str = "
+        DEF ADDRESS
+        .word F
+        JMP START
+        IMM 1 R1
+        IMM 2 R1
+        DEF START
+        IMM 3 R2
+        CALL F
+        JMP END
+        DEF F
+        IMM 5 R3
+        RET
+        DEF UNUSED
+        IMM 7 R3
+        DEF END
+    ";
+
+    fn warnings(program: &str) -> Vec<String> {
+        let assembly =
+            assemble_program(program, Layout::default()).unwrap_or_else(|e| panic!("{e}"));
+        assembly
+            .warnings
+            .iter()
+            .map(|warning| warning.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn warns_about_unreachable_code() {
+        assert_eq!(
+            warnings(PROGRAM),
+            [
+                "test.x1:5: warning: 2 unreachable instructions",
+                "test.x1:15: warning: Unreachable instruction",
+                "test.x1:2: warning: Label ADDRESS is never used",
+                "test.x1:14: warning: Label UNUSED is never used",
+            ]
+        );
+        let reached = "
+            DEF LOOP
+            DEC R1
+            JNZ LOOP R1
+            CALL F
+            HLT 0
+            DEF F
+            RET
+        ";
+        assert!(warnings(reached).is_empty(), "{:?}", warnings(reached));
+    }
+
+    #[test]
+    fn strips_unreachable_code() {
+        let assembly = shrink_program(PROGRAM, true, false);
+        assert_eq!(assembly.bytecode.len(), 6);
+        check_listing(&assembly);
+        let label = |name: &str| {
+            let symbol = assembly
+                .symbols
+                .iter()
+                .find(|symbol| symbol.name == name)
+                .unwrap();
+            (symbol.kind, symbol.value.clone())
+        };
+        assert_eq!(label("START"), (SymbolKind::Label, vec![1]));
+        assert_eq!(label("F"), (SymbolKind::Label, vec![4]));
+        // a removed label moves to the next instruction, here the end
+        assert_eq!(label("UNUSED"), (SymbolKind::Label, vec![6]));
+        assert_eq!(assembly.data, [4]);
+        assert!(assembly.reachable.iter().all(|reached| *reached));
+
+        let stripped = run(assembly);
+        let original = run(assemble_program(PROGRAM, Layout::default()).unwrap());
+        assert_eq!(stripped.memory[1..4], original.memory[1..4]);
+    }
+}
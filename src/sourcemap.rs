@@ -0,0 +1,83 @@
+use std::fmt;
+use std::rc::Rc;
+
+/// Where an instruction came from. `label` is the nearest global label at or
+/// before the instruction, with its address.
+#[derive(Clone, Debug)]
+pub struct Location {
+    pub file: Rc<str>,
+    pub line: usize,
+    pub column: usize,
+    pub label: Option<(String, u16)>,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// Source location of every instruction, indexed by program counter.
+#[derive(Clone, Debug, Default)]
+pub struct SourceMap {
+    pub locations: Vec<Location>,
+}
+
+impl SourceMap {
+    pub fn get(&self, pc: u16) -> Option<&Location> {
+        self.locations.get(pc as usize)
+    }
+
+    /// Readable position of `pc`, like `program.x1:12:1 (FUNC1+1)`, or the
+    /// bare address if `pc` has no source.
+    pub fn describe(&self, pc: u16) -> String {
+        match self.get(pc) {
+            Some(location) => match &location.label {
+                Some((label, address)) if pc > *address => {
+                    format!("{location} ({label}+{})", pc - address)
+                }
+                Some((label, _)) => format!("{location} ({label})"),
+                None => location.to_string(),
+            },
+            None => format!("0x{pc:X}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::Layout;
+    use crate::testing::assemble_program;
+
+    #[test]
+    fn maps_instructions_to_their_source() {
+        let program = "IMM 1 R1
+DEF MAIN
+    INC R1
+  DEF .loop
+      DEC R1
+    JNZ .loop R1";
+        let map = assemble_program(program, Layout::default())
+            .unwrap()
+            .source_map;
+        let located: Vec<_> = map
+            .locations
+            .iter()
+            .map(|location| (location.line, location.column, location.label.clone()))
+            .collect();
+        let main = Some(("MAIN".to_string(), 1));
+        assert_eq!(
+            located,
+            [
+                (1, 1, None),
+                (3, 5, main.clone()),
+                (5, 7, main.clone()),
+                (6, 5, main)
+            ]
+        );
+        assert_eq!(map.describe(0), "test.x1:1:1");
+        assert_eq!(map.describe(1), "test.x1:3:5 (MAIN)");
+        assert_eq!(map.describe(3), "test.x1:6:5 (MAIN+2)");
+        assert_eq!(map.describe(4), "0x4");
+    }
+}
@@ -0,0 +1,101 @@
+use crate::compiler::{Assembly, Layout, assemble, read_file};
+use crate::interpreter::{Machine, PROGRAM_MEMORY_START, State};
+use crate::object::{Object, assemble_object};
+use crate::peephole;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/*
+TEST HELPERS
+
+Shared by the test modules: assembling programs given as text, running them
+and giving every test its own directory for the files it includes.
+*/
+
+/// Assembles `program`, read from `path` with the command line `defines`.
+pub fn assemble_file(
+    path: &Path,
+    program: &str,
+    defines: &[&str],
+    layout: Layout,
+) -> Result<Assembly, String> {
+    let defines: Vec<String> = defines.iter().map(|define| define.to_string()).collect();
+    let lines = read_file(program.to_string(), path, &defines).map_err(|e| e.to_string())?;
+    assemble(lines, layout).map_err(|e| e.to_string())
+}
+
+/// Assembles `program` as the file test.x1.
+pub fn assemble_program(program: &str, layout: Layout) -> Result<Assembly, String> {
+    assemble_file(Path::new("test.x1"), program, &[], layout)
+}
+
+/// Assembles `program` and shrinks it, see peephole::shrink.
+pub fn shrink_program(program: &str, strip: bool, optimize: bool) -> Assembly {
+    let path = Path::new("test.x1");
+    let lines = read_file(program.to_string(), path, &[]).unwrap_or_else(|e| panic!("{e}"));
+    let mut assembly = assemble(lines.clone(), Layout::default()).unwrap_or_else(|e| panic!("{e}"));
+    peephole::shrink(&mut assembly, lines, path, false, strip, optimize)
+        .unwrap_or_else(|e| panic!("{e}"));
+    assembly
+}
+
+/// Checks that the listing of `assembly` shows the words of its bytecode.
+pub fn check_listing(assembly: &Assembly) {
+    let listed: Vec<(u16, &[u16])> = assembly
+        .listing
+        .iter()
+        .filter(|entry| !entry.data && !entry.words.is_empty())
+        .filter_map(|entry| Some((entry.address?, entry.words.as_slice())))
+        .collect();
+    assert_eq!(listed.len(), assembly.bytecode.len());
+    for (address, words) in listed {
+        assert_eq!(assembly.bytecode[address as usize], words);
+    }
+    assert_eq!(assembly.source_map.locations.len(), assembly.bytecode.len());
+}
+
+/// Assembles `program` into an object, position-independent if `pic` is set.
+pub fn object(program: &str, pic: bool) -> Object {
+    let (object, _) = assemble_object(program.to_string(), Path::new("test.x1"), pic, &[])
+        .unwrap_or_else(|error| panic!("{error}"));
+    object
+}
+
+/// A machine with `code` and `data` loaded.
+pub fn boot(code: Vec<Vec<u16>>, data: &[u16]) -> Machine {
+    let mut machine = Machine::new(code);
+    machine.load(PROGRAM_MEMORY_START, data);
+    machine
+}
+
+/// Runs `assembly` to its end.
+pub fn run(assembly: Assembly) -> Machine {
+    let mut machine = boot(assembly.bytecode, &assembly.data);
+    assert_eq!(machine.run(), State::Finished);
+    machine
+}
+
+/// Assembles and runs `program` to its end.
+pub fn run_program(program: &str) -> Machine {
+    run(assemble_program(program, Layout::default()).unwrap_or_else(|e| panic!("{e}")))
+}
+
+/// R1 to R3 after running `assembly`, or the error assembling it.
+pub fn registers(assembly: Result<Assembly, String>) -> Result<[u16; 3], String> {
+    let machine = run(assembly?);
+    Ok([machine.memory[1], machine.memory[2], machine.memory[3]])
+}
+
+/// A directory of its own for the test `test`, holding `files` at their
+/// relative paths.
+pub fn directory(test: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("eightbit-{test}-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("Error creating the test directory.");
+    for (name, text) in files {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap_or(&dir))
+            .expect("Error creating the test directory.");
+        fs::write(path, text).expect("Error writing a test file.");
+    }
+    dir
+}